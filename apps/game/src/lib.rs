@@ -86,6 +86,10 @@ struct Args {
     /// Run a configured set of simulations and record prebaked data.
     #[structopt(long)]
     prebake: bool,
+    /// Run an LLM-driven experiment without a window. Each line of this file is sent to the LLM,
+    /// and the commands in its replies are applied to the simulation.
+    #[structopt(long)]
+    chat_script: Option<String>,
 
     /// Start at the tutorial intro screen
     #[structopt(long)]
@@ -177,6 +181,13 @@ fn run(mut settings: Settings) {
         challenges::prebake::prebake_all();
         return;
     }
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(path) = args.chat_script.take() {
+        if let Err(err) = sandbox::run_chat_script(&args.flags.sim_flags, path) {
+            error!("The chat script failed: {err:#}");
+        }
+        return;
+    }
 
     let mut setup = Setup {
         flags: args.flags,
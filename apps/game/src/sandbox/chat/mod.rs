@@ -0,0 +1,110 @@
+//! A chatbox for asking an LLM about the running simulation. Replies can include actions that
+//! control the simulation, like pausing or stepping forward.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+use serde::{Deserialize, Serialize};
+
+use abstio::MapName;
+
+pub use self::session::{run_chat_script, ChatCommand, GridlockWatch, SimSnapshot};
+pub use self::ui::Chatbox;
+use crate::app::App;
+use crate::sandbox::chat_i18n::Locale;
+
+mod persistence;
+mod session;
+mod transport;
+mod ui;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Role {
+    User,
+    Assistant,
+    System,
+    /// The reasoning behind the preceding assistant message, from models that expose it. This is
+    /// never sent back to the API or parsed for commands.
+    Thoughts,
+    /// What the simulation was doing when the following user message was sent. It's sent to the
+    /// API as part of that message.
+    SimState,
+    /// What happened when an action from the LLM was applied, like the time a step paused at.
+    /// The content starts with the action line, so the LLM can tell which result is which.
+    CommandResult,
+    /// The player reset the context here. Nothing before this is sent to the API anymore, but it
+    /// stays in the transcript. The content is empty.
+    ContextReset,
+    /// What an action from the LLM changed, one `ParamChange` per line. Sent to the API like a
+    /// `CommandResult`.
+    ParamDiff,
+}
+
+/// The map and scenario that were active when a conversation started. LLM advice is only
+/// meaningful for one experiment, so this is recorded alongside the history.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct ChatContext {
+    map: MapName,
+    scenario: String,
+}
+
+impl ChatContext {
+    fn current(app: &App) -> ChatContext {
+        ChatContext {
+            map: app.primary.map.get_name().clone(),
+            scenario: app.primary.sim.get_run_name().clone(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        Locale::English.scenario_on_map(&self.scenario, &self.map.describe())
+    }
+}
+
+/// Cleans up a message the user typed, so that what's stored in the transcript matches what's sent.
+/// Trailing whitespace on each line and blank lines at the start and end are removed, but newlines
+/// in the middle are kept.
+fn normalize_message(input: &str) -> String {
+    input
+        .lines()
+        .map(|line| line.trim_end())
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+fn last_message(messages: &[(Role, String)], role: Role) -> Option<&str> {
+    messages
+        .iter()
+        .rev()
+        .find(|(r, _)| *r == role)
+        .map(|(_, msg)| msg.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_last_message() {
+        let mut messages = vec![(Role::System, "Chatbox ready.".to_string())];
+        assert_eq!(last_message(&messages, Role::User), None);
+        assert_eq!(last_message(&messages, Role::Assistant), None);
+
+        messages.push((Role::User, "first".to_string()));
+        messages.push((Role::Assistant, "reply".to_string()));
+        messages.push((Role::Thoughts, "hmm".to_string()));
+        messages.push((Role::User, "second".to_string()));
+        assert_eq!(last_message(&messages, Role::User), Some("second"));
+        assert_eq!(last_message(&messages, Role::Assistant), Some("reply"));
+    }
+
+    #[test]
+    fn test_normalize_message() {
+        assert_eq!(
+            normalize_message("\n  \n  How do quotas affect   \t\n\ncongestion?  \r\n\n \n"),
+            "How do quotas affect\n\ncongestion?"
+        );
+        assert_eq!(normalize_message(" \n\t\n"), "");
+    }
+}
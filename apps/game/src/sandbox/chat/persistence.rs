@@ -0,0 +1,521 @@
+//! What the chatbox keeps between sessions: settings, prompt templates, drafts, archived
+//! messages, and the conversation itself.
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use abstutil::Timer;
+use geom::Duration;
+
+use crate::sandbox::chat::ui::{
+    ActionLines, ChatPosition, QueueFullPolicy, QueuedActionsPolicy, ResendKey, SendKey,
+};
+use crate::sandbox::chat::{ChatContext, Role};
+use crate::sandbox::chat_i18n::{Locale, Msg};
+
+/// How many messages of the transcript to show at once, by default
+pub const VISIBLE_MESSAGES: usize = 6;
+
+/// Also offered as a built-in template
+pub const DEFAULT_PROMPT: &str =
+    "I want to evaluate how different ride-hailing vehicle quotas (from \
+1,000 to 10,000) affect road traffic congestion in Hong Kong.";
+
+/// Template names come from the start of their text
+const MAX_TEMPLATE_NAME_LEN: usize = 40;
+
+/// A saved conversation this recent is probably still the current experiment, so it's continued
+/// unless the player says otherwise
+const RECENT_CONVERSATION: std::time::Duration = std::time::Duration::from_secs(12 * 3600);
+
+/// A conversation persisted as player data.
+#[derive(Serialize, Deserialize)]
+pub struct SavedConversation {
+    pub context: ChatContext,
+    pub messages: Vec<(Role, String)>,
+    /// How many older messages are in the `TranscriptArchive`
+    #[serde(default)]
+    pub archived: usize,
+    /// Fixed for the whole conversation, so an experiment can be rerun
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// As reported by the provider, for every request in this conversation
+    #[serde(default)]
+    pub tokens_used: usize,
+}
+
+impl SavedConversation {
+    pub fn path() -> String {
+        abstio::path_player("chat/conversation.json")
+    }
+
+    pub fn load() -> Option<SavedConversation> {
+        abstio::maybe_read_json::<SavedConversation>(
+            SavedConversation::path(),
+            &mut Timer::throwaway(),
+        )
+        .ok()
+    }
+
+    /// How long ago the conversation was last written, if the filesystem knows
+    pub fn age() -> Option<std::time::Duration> {
+        std::fs::metadata(SavedConversation::path())
+            .and_then(|meta| meta.modified())
+            .ok()?
+            .elapsed()
+            .ok()
+    }
+
+    /// Warns when the conversation was about a different experiment than `current`.
+    pub fn resume(mut self, current: &ChatContext, locale: Locale) -> SavedConversation {
+        if self.context != *current {
+            let about = |context: &ChatContext| {
+                locale.scenario_on_map(&context.scenario, &context.map.describe())
+            };
+            self.messages.push((
+                Role::System,
+                locale.different_experiment(&about(&self.context), &about(current)),
+            ));
+        }
+        self
+    }
+}
+
+/// A saved conversation the player hasn't yet chosen to continue or replace. Once anything new is
+/// saved, like a sent message, the choice stands as it is.
+pub struct ResumeChoice {
+    /// Only kept while it isn't loaded, so it can still be continued
+    pub saved: Option<SavedConversation>,
+    /// How many messages at the start of the transcript came from the saved conversation
+    pub loaded: usize,
+    pub age: Option<std::time::Duration>,
+    /// What a new conversation would be about
+    pub current: ChatContext,
+}
+
+pub fn is_greeting(role: &Role, msg: &str) -> bool {
+    *role == Role::System
+        && Locale::ALL
+            .iter()
+            .any(|locale| Msg::ChatboxReady.text(*locale) == msg)
+}
+
+/// Only a new conversation is greeted. A restored one was greeted when it started, so this just
+/// drops any extra greetings that piled up in it, keeping the first.
+pub fn greet(messages: &mut Vec<(Role, String)>, new_conversation: bool, locale: Locale) {
+    if new_conversation {
+        messages.push((Role::System, Msg::ChatboxReady.text(locale).to_string()));
+        return;
+    }
+    let mut greeted = false;
+    messages.retain(|(role, msg)| {
+        if !is_greeting(role, msg) {
+            return true;
+        }
+        !std::mem::replace(&mut greeted, true)
+    });
+}
+
+pub fn continue_by_default(age: Option<std::time::Duration>) -> bool {
+    age.map(|age| age < RECENT_CONVERSATION).unwrap_or(true)
+}
+
+pub fn describe_age(age: std::time::Duration, locale: Locale) -> String {
+    let minutes = age.as_secs() / 60;
+    if minutes < 60 {
+        locale.minutes_ago(minutes)
+    } else if minutes < 48 * 60 {
+        locale.hours_ago(minutes / 60)
+    } else {
+        locale.days_ago(minutes / (24 * 60))
+    }
+}
+
+/// The unsent contents of the input box, so they survive a crash or accidental close
+#[derive(Serialize, Deserialize)]
+pub struct SavedDraft {
+    pub text: String,
+}
+
+impl SavedDraft {
+    pub fn path() -> String {
+        abstio::path_player("chat/draft.json")
+    }
+
+    pub fn load() -> Option<String> {
+        abstio::maybe_read_json::<SavedDraft>(SavedDraft::path(), &mut Timer::throwaway())
+            .ok()
+            .map(|draft| draft.text)
+    }
+
+    pub fn save(text: String) {
+        abstio::write_json(SavedDraft::path(), &SavedDraft { text });
+    }
+
+    pub fn clear() {
+        abstio::delete_file(SavedDraft::path());
+    }
+}
+
+/// The oldest messages of a long conversation, kept on disk instead of in memory. Messages are
+/// ordered oldest first, and come before everything in `SavedConversation`.
+#[derive(Default, Serialize, Deserialize)]
+pub struct TranscriptArchive {
+    pub messages: Vec<(Role, String)>,
+}
+
+impl TranscriptArchive {
+    pub fn path() -> String {
+        abstio::path_player("chat/archive.json")
+    }
+
+    pub fn load() -> TranscriptArchive {
+        abstio::maybe_read_json::<TranscriptArchive>(
+            TranscriptArchive::path(),
+            &mut Timer::throwaway(),
+        )
+        .unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        abstio::write_json(TranscriptArchive::path(), self);
+    }
+}
+
+/// Chatbox preferences, persisted as player data
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChatSettings {
+    pub send_key: SendKey,
+    /// Full-strength colors and larger text, for low-vision users
+    pub high_contrast: bool,
+    /// The inclusive range of ride-hailing quotas the LLM may choose
+    pub ride_hail_quota_range: (usize, usize),
+    /// Past this, older messages are moved to the on-disk archive
+    pub max_messages_in_memory: usize,
+    /// How many recent messages are sent with each request, besides the system prompt
+    pub context_messages: usize,
+    /// Show the commands in replies, but never apply them
+    pub dry_run: bool,
+    /// Ask before sending a message longer than this many characters. `None` never asks.
+    pub confirm_send_above: Option<usize>,
+    /// How many messages can wait to be sent while a request is in flight. Each one becomes
+    /// another paid request.
+    pub max_queued_messages: usize,
+    pub queue_full_policy: QueueFullPolicy,
+    /// What to do when sending while actions from an earlier reply haven't run yet
+    pub queued_actions_on_send: QueuedActionsPolicy,
+    /// Let simulation events ask the LLM to react, without the player sending anything
+    pub auto_respond_to_events: bool,
+    /// Every this many sim minutes, send the LLM the current stats and ask whether anything needs
+    /// adjusting. 0 turns this off.
+    pub auto_report_minutes: usize,
+    /// How many automatic reports can go out in a row before the player sends something. Each is
+    /// a paid request.
+    pub max_auto_reports: usize,
+    /// Emacs-style editing shortcuts in the input box, like Ctrl+A and Ctrl+K
+    pub readline_keys: bool,
+    /// How many messages of the transcript to show at once. Larger screens have room for more.
+    pub visible_messages: usize,
+    pub resend_key: ResendKey,
+    /// When a reply has actions that can't be parsed, ask the LLM to try again, once per message
+    pub auto_correct_commands: bool,
+    /// Send a `SimSnapshot` along with each message
+    pub attach_sim_state: bool,
+    /// Send the `ScenarioParams` with each request
+    pub attach_scenario_params: bool,
+    /// A line at the bottom with the model, temperature, and tokens used so far
+    pub show_status_line: bool,
+    /// Show replies as they're written, instead of all at once
+    pub stream_replies: bool,
+    /// Pause the sim while the input box has focus, so the world doesn't change while composing
+    /// an instruction
+    pub pause_while_typing: bool,
+    /// The researcher's own instructions, like the study's goals, sent before the built-in ones.
+    /// There's no editor yet; change this in the settings file.
+    pub custom_prompt: String,
+    /// Comma-separated text that ends a reply as soon as the model writes it, like `ACTION: end`.
+    /// Providers cap how many they accept; OpenAI takes at most 4 and DeepSeek 16, and a request
+    /// with more is rejected. Like `custom_prompt`, this is only in the settings file.
+    pub stop_sequences: String,
+    /// Caps how many tokens a reply can use, to bound length and cost. `None` leaves it to the
+    /// provider.
+    pub max_tokens: Option<usize>,
+    /// Discourages the LLM from repeating the same words, the more often they've appeared. From
+    /// -2 to 2, and 0 leaves it to the provider. Not every provider honors this; reasoning models
+    /// like deepseek-reasoner ignore it.
+    pub frequency_penalty: f64,
+    /// Like `frequency_penalty`, but for any word that's appeared at all, nudging the LLM toward
+    /// new topics. The same range and caveats apply.
+    pub presence_penalty: f64,
+    /// Which corner of the screen the chatbox sits in
+    pub position: ChatPosition,
+    /// How recognized action lines appear in replies. They're parsed the same either way.
+    pub action_lines: ActionLines,
+}
+
+impl Default for ChatSettings {
+    fn default() -> ChatSettings {
+        ChatSettings {
+            // Matches MultilineTextBox, where Enter inserts a newline
+            send_key: SendKey::CtrlEnter,
+            high_contrast: false,
+            ride_hail_quota_range: (1_000, 10_000),
+            max_messages_in_memory: 200,
+            context_messages: 8,
+            dry_run: false,
+            confirm_send_above: None,
+            max_queued_messages: 3,
+            queue_full_policy: QueueFullPolicy::Reject,
+            queued_actions_on_send: QueuedActionsPolicy::Notice,
+            auto_respond_to_events: false,
+            auto_report_minutes: 0,
+            max_auto_reports: 10,
+            readline_keys: false,
+            visible_messages: VISIBLE_MESSAGES,
+            resend_key: ResendKey::CtrlR,
+            auto_correct_commands: false,
+            attach_sim_state: true,
+            attach_scenario_params: true,
+            show_status_line: true,
+            stream_replies: false,
+            pause_while_typing: false,
+            custom_prompt: String::new(),
+            stop_sequences: String::new(),
+            max_tokens: None,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            position: ChatPosition::BottomLeft,
+            action_lines: ActionLines::Show,
+        }
+    }
+}
+
+impl ChatSettings {
+    pub fn path() -> String {
+        abstio::path_player("chat/settings.json")
+    }
+
+    pub fn load() -> ChatSettings {
+        abstio::maybe_read_json::<ChatSettings>(ChatSettings::path(), &mut Timer::throwaway())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        abstio::write_json(ChatSettings::path(), self);
+    }
+}
+
+/// Prompt snippets the player saved for reuse across sessions
+#[derive(Default, Serialize, Deserialize)]
+pub struct PromptTemplates {
+    pub saved: Vec<PromptTemplate>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PromptTemplate {
+    pub name: String,
+    pub text: String,
+}
+
+impl PromptTemplates {
+    pub fn path() -> String {
+        abstio::path_player("chat/templates.json")
+    }
+
+    pub fn load() -> PromptTemplates {
+        abstio::maybe_read_json::<PromptTemplates>(PromptTemplates::path(), &mut Timer::throwaway())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        abstio::write_json(PromptTemplates::path(), self);
+    }
+
+    /// The built-in template comes first and can't be deleted, so saved templates start at index 1.
+    pub fn all(&self) -> Vec<PromptTemplate> {
+        let mut all = vec![PromptTemplate {
+            name: "Ride-hailing quota study".to_string(),
+            text: DEFAULT_PROMPT.to_string(),
+        }];
+        all.extend(self.saved.iter().cloned());
+        all
+    }
+
+    /// Returns false if this text is already saved.
+    pub fn add(&mut self, text: String) -> bool {
+        if self.all().iter().any(|t| t.text == text) {
+            return false;
+        }
+        self.saved.push(PromptTemplate {
+            name: template_name(&text),
+            text,
+        });
+        true
+    }
+}
+
+/// The conversation as Markdown, for pasting into a doc. Each message is a paragraph labeled with
+/// who wrote it. Messages still queued are included at the end, marked as not sent yet. A reply
+/// still being written isn't included, only a note that one is on its way.
+pub fn transcript_markdown(
+    context: &ChatContext,
+    messages: &[(Role, String)],
+    queued: &VecDeque<String>,
+    waiting: bool,
+) -> String {
+    let mut paragraphs = vec![format!("# LLM chat about {}", context.describe())];
+    for (role, msg) in messages {
+        if *role == Role::ContextReset {
+            paragraphs.push("_Context reset; the LLM didn't see anything above._".to_string());
+            continue;
+        }
+        let label = match role {
+            Role::User => "You",
+            Role::Assistant => "LLM",
+            Role::System => "System",
+            Role::Thoughts => "LLM's thoughts",
+            Role::SimState => "Sim state",
+            Role::CommandResult => "Action result",
+            Role::ParamDiff => "Changed",
+            Role::ContextReset => unreachable!(),
+        };
+        paragraphs.push(format!("**{label}:** {msg}"));
+    }
+    if waiting {
+        paragraphs.push("_Waiting for the LLM to reply._".to_string());
+    }
+    for msg in queued {
+        paragraphs.push(format!("**You (queued, not sent yet):** {msg}"));
+    }
+    paragraphs.join("\n\n") + "\n"
+}
+
+/// Names a template after the first line of its text, shortened if needed.
+pub fn template_name(text: &str) -> String {
+    let first_line = text.lines().next().unwrap_or("").trim();
+    if first_line.chars().count() <= MAX_TEMPLATE_NAME_LEN {
+        return first_line.to_string();
+    }
+    let mut name: String = first_line.chars().take(MAX_TEMPLATE_NAME_LEN - 1).collect();
+    name.push('…');
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use abstio::MapName;
+
+    #[test]
+    fn test_greeting() {
+        // A new conversation is greeted once
+        let mut messages = Vec::new();
+        greet(&mut messages, true, Locale::English);
+        assert_eq!(messages, vec![(Role::System, "Chatbox ready.".to_string())]);
+
+        // Restoring one that was greeted several times keeps only the first, in any language
+        let mut messages = vec![
+            (Role::System, "Chatbox ready.".to_string()),
+            (Role::User, "hi".to_string()),
+            (Role::System, "Chatbox ready.".to_string()),
+            (Role::System, "聊天框已就绪。".to_string()),
+            (Role::User, "Chatbox ready.".to_string()),
+        ];
+        greet(&mut messages, false, Locale::Chinese);
+        assert_eq!(
+            messages,
+            vec![
+                (Role::System, "Chatbox ready.".to_string()),
+                (Role::User, "hi".to_string()),
+                (Role::User, "Chatbox ready.".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resume_choice() {
+        use std::time::Duration;
+        let day = Duration::from_secs(24 * 3600);
+
+        assert!(continue_by_default(Some(Duration::from_secs(3 * 3600))));
+        assert!(!continue_by_default(Some(day * 3)));
+        // Without a modification time, nothing says the conversation is old
+        assert!(continue_by_default(None));
+
+        let english = Locale::English;
+        assert_eq!(
+            describe_age(Duration::from_secs(30), english),
+            "0 minutes ago"
+        );
+        assert_eq!(
+            describe_age(Duration::from_secs(60), english),
+            "1 minute ago"
+        );
+        assert_eq!(
+            describe_age(Duration::from_secs(5 * 3600), english),
+            "5 hours ago"
+        );
+        assert_eq!(describe_age(day * 3, english), "3 days ago");
+        assert_eq!(describe_age(day * 3, Locale::Chinese), "3 天前");
+
+        let saved = SavedConversation {
+            context: ChatContext {
+                map: MapName::seattle("montlake"),
+                scenario: "weekday".to_string(),
+            },
+            messages: vec![(Role::User, "hi".to_string())],
+            archived: 0,
+            seed: None,
+            tokens_used: 0,
+        };
+        let other = ChatContext {
+            map: MapName::seattle("montlake"),
+            scenario: "weekend".to_string(),
+        };
+        let resumed = saved.resume(&other, Locale::English);
+        assert_eq!(resumed.messages.len(), 2);
+        assert!(resumed.messages[1].1.starts_with("Warning: "));
+    }
+
+    #[test]
+    fn test_template_name() {
+        assert_eq!(template_name("Compare rush hours"), "Compare rush hours");
+        assert_eq!(template_name("First line\nSecond line"), "First line");
+        let name = template_name(DEFAULT_PROMPT);
+        assert_eq!(name.chars().count(), MAX_TEMPLATE_NAME_LEN);
+        assert!(name.starts_with("I want to evaluate"));
+        assert!(name.ends_with('…'));
+    }
+
+    #[test]
+    fn test_transcript_markdown() {
+        let context = ChatContext {
+            map: MapName::seattle("montlake"),
+            scenario: "weekday".to_string(),
+        };
+        let messages = vec![
+            (Role::System, "Chatbox ready.".to_string()),
+            (Role::SimState, "Sim time 7AM, paused.".to_string()),
+            (Role::User, "slow down".to_string()),
+            (Role::Assistant, "Slowing down.\nACTION: slower".to_string()),
+            (Role::User, "more?".to_string()),
+        ];
+        let queued = VecDeque::from(vec!["and then stop".to_string()]);
+        assert_eq!(
+            transcript_markdown(&context, &messages, &queued, true),
+            format!(
+                "# LLM chat about {}\n\n\
+                 **System:** Chatbox ready.\n\n\
+                 **Sim state:** Sim time 7AM, paused.\n\n\
+                 **You:** slow down\n\n\
+                 **LLM:** Slowing down.\nACTION: slower\n\n\
+                 **You:** more?\n\n\
+                 _Waiting for the LLM to reply._\n\n\
+                 **You (queued, not sent yet):** and then stop\n",
+                context.describe()
+            )
+        );
+    }
+}
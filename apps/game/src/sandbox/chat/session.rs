@@ -0,0 +1,1137 @@
+//! A conversation with the LLM, apart from the UI: the actions parsed out of replies, the
+//! simulation state sent along with messages, and running a whole conversation from a script.
+
+use std::collections::{BTreeSet, VecDeque};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use abstio::MapName;
+use abstutil::{prettyprint_usize, Timer};
+use geom::{Duration, Time};
+use map_model::IntersectionID;
+use sim::{Sim, SimFlags};
+
+use crate::app::App;
+use crate::sandbox::chat::persistence::ChatSettings;
+use crate::sandbox::chat::transport::{
+    parse_stop_sequences, penalty, LlmBackend, LlmConfig, LlmReply, ReplayBackend, RequestSettings,
+};
+use crate::sandbox::chat::{normalize_message, ChatContext, Role};
+use crate::sandbox::chat_i18n::{Locale, Msg};
+use crate::sandbox::SpeedSetting;
+
+/// How long an intersection has to be stuck before it's reported as possible gridlock
+const GRIDLOCK_DELAY: Duration = Duration::const_seconds(5.0 * 60.0);
+
+/// One value an action changed, like the ride-hailing quota
+#[derive(Debug, PartialEq)]
+pub struct ParamChange {
+    pub name: Msg,
+    pub before: String,
+    pub after: String,
+}
+
+impl ParamChange {
+    /// Like "quota: 3,000 → 5,000"
+    pub fn describe(&self, locale: Locale) -> String {
+        format!(
+            "{}: {} → {}",
+            self.name.text(locale),
+            self.before,
+            self.after
+        )
+    }
+}
+
+pub fn describe_speed(speed: Option<SpeedSetting>, locale: Locale) -> &'static str {
+    match speed {
+        None => Msg::Paused.text(locale),
+        Some(SpeedSetting::Realtime) => "1x",
+        Some(SpeedSetting::Fast) => "5x",
+        Some(SpeedSetting::Faster) => "30x",
+        Some(SpeedSetting::Fastest) => "3600x",
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChatCommand {
+    Pause,
+    Resume,
+    /// Switch to the next slower speed setting
+    SlowDown,
+    /// Switch to the next faster speed setting
+    SpeedUp,
+    /// How many ride-hailing vehicles the study should use
+    SetRideHailQuota(usize),
+    /// Run the simulation forward by this much, then pause
+    StepBy(Duration),
+}
+
+impl ChatCommand {
+    /// Describes what the command does, as a verb phrase
+    pub fn describe(&self, locale: Locale) -> String {
+        match self {
+            ChatCommand::Pause => Msg::PauseAction.text(locale).to_string(),
+            ChatCommand::Resume => Msg::ResumeAction.text(locale).to_string(),
+            ChatCommand::SlowDown => Msg::SlowDownAction.text(locale).to_string(),
+            ChatCommand::SpeedUp => Msg::SpeedUpAction.text(locale).to_string(),
+            ChatCommand::SetRideHailQuota(quota) => locale.set_quota_step(*quota),
+            ChatCommand::StepBy(dt) => locale.step_by_step(&dt.to_string()),
+        }
+    }
+}
+
+/// A command parsed from an LLM reply, along with the line of the reply that produced it
+pub type SourcedCommand = (ChatCommand, String);
+
+/// A command handed to the sandbox. Its results are reported against the reply line it came from,
+/// which two identical commands in the same frame would otherwise share.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AppliedCommand {
+    pub cmd: ChatCommand,
+    /// The position in `last_applied`, or `None` for the chatbox's own commands
+    pub source: Option<usize>,
+}
+
+/// Commands from LLM replies waiting to be applied. Each batch is applied within one frame, so a
+/// grouped sequence like pause, change something, resume never shows intermediate states.
+#[derive(Default)]
+pub struct CommandQueue {
+    batches: VecDeque<Vec<SourcedCommand>>,
+    /// The position in the whole conversation, including archived messages, of the newest reply
+    /// already parsed
+    newest_reply: Option<usize>,
+}
+
+impl CommandQueue {
+    /// Parses the commands in a reply, identified by its position in the whole conversation. A
+    /// reply is only ever parsed once, so the same commands can't be queued twice.
+    fn parse_reply(&mut self, reply_idx: usize, reply: &str) -> Vec<Vec<SourcedCommand>> {
+        if self
+            .newest_reply
+            .map(|newest| reply_idx <= newest)
+            .unwrap_or(false)
+        {
+            return Vec::new();
+        }
+        self.newest_reply = Some(reply_idx);
+        parse_commands(reply)
+    }
+
+    pub fn extend(&mut self, batches: Vec<Vec<SourcedCommand>>) {
+        self.batches.extend(batches);
+    }
+
+    fn take_batch(&mut self) -> Vec<SourcedCommand> {
+        self.batches.pop_front().unwrap_or_default()
+    }
+
+    /// The total number of commands, not batches
+    pub fn len(&self) -> usize {
+        self.batches.iter().map(|batch| batch.len()).sum()
+    }
+
+    pub fn clear(&mut self) {
+        self.batches.clear();
+    }
+
+    /// Combines every batch into one, so they're all applied in the next frame.
+    pub fn merge(&mut self) {
+        let all: Vec<SourcedCommand> = self.batches.drain(..).flatten().collect();
+        if !all.is_empty() {
+            self.batches.push_back(all);
+        }
+    }
+}
+
+/// Pauses the sim while the player types, for `pause_while_typing`, and resumes it afterwards.
+#[derive(Default)]
+pub struct AutoPause {
+    /// The sim was running when the player started typing, so it should resume when they stop
+    pub paused: bool,
+}
+
+impl AutoPause {
+    /// Returns the command to apply when the input box gains or loses focus, if any.
+    pub fn focus_changed(&mut self, focused: bool, sim_running: bool) -> Option<ChatCommand> {
+        if focused {
+            if !sim_running || self.paused {
+                return None;
+            }
+            self.paused = true;
+            Some(ChatCommand::Pause)
+        } else if self.paused {
+            self.paused = false;
+            Some(ChatCommand::Resume)
+        } else {
+            None
+        }
+    }
+
+    /// A pause or resume from the LLM wins, so it isn't undone when the player stops typing.
+    pub fn explicit_command(&mut self, cmd: ChatCommand) {
+        if matches!(cmd, ChatCommand::Pause | ChatCommand::Resume) {
+            self.paused = false;
+        }
+    }
+}
+
+/// What the simulation is doing, as the sandbox last reported it
+#[derive(Clone, Copy)]
+pub struct SimSnapshot {
+    pub time: Time,
+    /// `None` while paused
+    pub speed: Option<SpeedSetting>,
+    pub finished_trips: usize,
+    pub unfinished_trips: usize,
+}
+
+impl SimSnapshot {
+    pub fn current(app: &App, speed: Option<SpeedSetting>) -> SimSnapshot {
+        SimSnapshot::from_sim(&app.primary.sim, speed)
+    }
+
+    pub fn from_sim(sim: &Sim, speed: Option<SpeedSetting>) -> SimSnapshot {
+        let (finished_trips, unfinished_trips) = sim.num_trips();
+        SimSnapshot {
+            time: sim.time(),
+            speed,
+            finished_trips,
+            unfinished_trips,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let speed = match self.speed {
+            None => "paused",
+            Some(SpeedSetting::Realtime) => "running in real time",
+            Some(SpeedSetting::Fast) => "running at 5x",
+            Some(SpeedSetting::Faster) => "running at 30x",
+            Some(SpeedSetting::Fastest) => "running at 3600x",
+        };
+        format!(
+            "Sim time {}, {speed}. {} trips finished, {} not yet.",
+            self.time.ampm_tostring(),
+            prettyprint_usize(self.finished_trips),
+            prettyprint_usize(self.unfinished_trips)
+        )
+    }
+}
+
+/// Watches for intersections stuck long enough to suggest gridlock, so the chatbox can tell the
+/// LLM. Each intersection is only reported once, until it clears.
+#[derive(Default)]
+pub struct GridlockWatch {
+    reported: BTreeSet<IntersectionID>,
+    last_checked: Option<Time>,
+}
+
+impl GridlockWatch {
+    /// Checks at most once per sim minute. Returns an event describing the intersections that got
+    /// stuck since the last check, if any.
+    pub fn check(&mut self, app: &App) -> Option<String> {
+        let sim = &app.primary.sim;
+        let map = &app.primary.map;
+        let stuck = self.newly_stuck(sim.time(), || sim.delayed_intersections(GRIDLOCK_DELAY));
+        // The one stuck longest is most likely the cause
+        let (i, since) = stuck.first()?;
+        let mut event = format!(
+            "Gridlock suspected at {}, stuck since {}",
+            map.get_i(*i).name(None, map),
+            since.ampm_tostring()
+        );
+        if stuck.len() > 1 {
+            event.push_str(&format!(", and at {} more intersections", stuck.len() - 1));
+        }
+        Some(event)
+    }
+
+    fn newly_stuck(
+        &mut self,
+        now: Time,
+        delayed: impl FnOnce() -> Vec<(IntersectionID, Time)>,
+    ) -> Vec<(IntersectionID, Time)> {
+        if let Some(last) = self.last_checked {
+            if now < last {
+                // The clock going backwards, like after loading a savestate, starts over
+                self.reported.clear();
+            } else if now - last < Duration::minutes(1) {
+                return Vec::new();
+            }
+        }
+        self.last_checked = Some(now);
+        let delayed = delayed();
+        let stuck = delayed
+            .iter()
+            .filter(|(i, _)| !self.reported.contains(i))
+            .cloned()
+            .collect();
+        // Anything that cleared can be reported again
+        self.reported = delayed.into_iter().map(|(i, _)| i).collect();
+        stuck
+    }
+}
+
+/// Bump this when `ScenarioParams` changes shape, so prompts and researchers reading old
+/// transcripts can tell which fields to expect.
+pub const SCENARIO_PARAMS_VERSION: u32 = 1;
+
+/// The experiment's parameters, sent as a compact JSON block so the LLM's quota recommendations
+/// are grounded in the actual scenario. Version 1 looks like:
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "map": "us_seattle_montlake",
+///   "scenario": "weekday",
+///   "ride_hail_quota": 2500,
+///   "quota_range": [1000, 10000],
+///   "trips": 41000,
+///   "departures_by_hour": [12, 5, 0, ...]
+/// }
+/// ```
+///
+/// `ride_hail_quota` is `null` until a quota has been set. `quota_range` is inclusive.
+/// `departures_by_hour` always has 24 entries, counting scheduled departures in each hour from
+/// midnight; trips departing after the first day count toward the last hour.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ScenarioParams {
+    pub version: u32,
+    pub map: String,
+    pub scenario: String,
+    pub ride_hail_quota: Option<usize>,
+    pub quota_range: (usize, usize),
+    pub trips: usize,
+    pub departures_by_hour: Vec<usize>,
+}
+
+impl ScenarioParams {
+    pub fn from_sim(map: &MapName, sim: &Sim, quota_range: (usize, usize)) -> ScenarioParams {
+        let departures = sim
+            .all_trip_info()
+            .into_iter()
+            .map(|(_, info)| info.departure)
+            .collect::<Vec<_>>();
+        ScenarioParams {
+            version: SCENARIO_PARAMS_VERSION,
+            map: map.as_filename(),
+            scenario: sim.get_run_name().clone(),
+            ride_hail_quota: None,
+            quota_range,
+            trips: departures.len(),
+            departures_by_hour: departures_by_hour(departures),
+        }
+    }
+
+    /// Counting departures goes through every trip, so this is only redone when the scenario
+    /// changed.
+    pub fn is_stale(&self, sim: &Sim) -> bool {
+        let (finished, unfinished) = sim.num_trips();
+        self.scenario != *sim.get_run_name() || self.trips != finished + unfinished
+    }
+
+    pub fn to_block(&self) -> String {
+        format!(
+            "Scenario parameters (JSON, schema version {SCENARIO_PARAMS_VERSION}):\n{}",
+            serde_json::to_string(self).unwrap()
+        )
+    }
+}
+
+pub fn departures_by_hour(departures: Vec<Time>) -> Vec<usize> {
+    let mut hours = vec![0; 24];
+    for time in departures {
+        let hour = (time.inner_seconds() / 3600.0) as usize;
+        hours[hour.min(23)] += 1;
+    }
+    hours
+}
+
+/// The conversation and the commands from its replies, without any UI. The chatbox keeps one and
+/// sends its requests from a worker thread. Scripted experiments use `send` instead, which blocks
+/// until the reply arrives. Applying the commands is up to the caller either way.
+pub struct ChatSession {
+    pub context: ChatContext,
+    pub messages: Vec<(Role, String)>,
+    pub settings: RequestSettings,
+    /// Answers `send`. The chatbox's session only has one when replaying a recording.
+    pub backend: Option<Box<dyn LlmBackend>>,
+    /// Commands from replies, waiting to be applied
+    pub commands: CommandQueue,
+    pub sim_snapshot: Option<SimSnapshot>,
+    pub tokens_used: usize,
+}
+
+impl ChatSession {
+    /// Uses the same environment variables as the chatbox to pick and configure the backend.
+    pub fn new(map: MapName, scenario: String) -> Result<ChatSession> {
+        let backend: Box<dyn LlmBackend> = match ReplayBackend::from_env()? {
+            Some(replay) => Box::new(replay),
+            None => Box::new(LlmConfig::from_env()?),
+        };
+        let mut session = ChatSession::with_backend(ChatContext { map, scenario }, Some(backend));
+        let settings = ChatSettings::load();
+        session.settings.context_messages = settings.context_messages;
+        session.settings.custom_prompt = settings.custom_prompt;
+        session.settings.stop = parse_stop_sequences(&settings.stop_sequences);
+        session.settings.max_tokens = settings.max_tokens;
+        session.settings.frequency_penalty = penalty(settings.frequency_penalty);
+        session.settings.presence_penalty = penalty(settings.presence_penalty);
+        Ok(session)
+    }
+
+    pub fn with_backend(context: ChatContext, backend: Option<Box<dyn LlmBackend>>) -> ChatSession {
+        ChatSession {
+            context,
+            messages: Vec::new(),
+            settings: RequestSettings {
+                context_messages: ChatSettings::default().context_messages,
+                seed: None,
+                custom_prompt: String::new(),
+                scenario_params: None,
+                stop: Vec::new(),
+                max_tokens: None,
+                frequency_penalty: None,
+                presence_penalty: None,
+            },
+            backend,
+            commands: CommandQueue::default(),
+            sim_snapshot: None,
+            tokens_used: 0,
+        }
+    }
+
+    /// Attached to the next message sent, like the chatbox's "Attach sim state" setting
+    pub fn set_sim_snapshot(&mut self, snapshot: SimSnapshot) {
+        self.sim_snapshot = Some(snapshot);
+    }
+
+    /// Sent with every request from now on, like the chatbox's "Attach scenario" setting
+    pub fn set_scenario_params(&mut self, params: ScenarioParams) {
+        self.settings.scenario_params = Some(params.to_block());
+    }
+
+    /// Sends a message and waits for the reply. Returns the reply and the commands parsed from it,
+    /// in order. Grouped commands are flattened, since there are no frames to apply them within.
+    pub fn send(&mut self, msg: &str) -> Result<(String, Vec<ChatCommand>)> {
+        let history = self.push_user_message(msg);
+        let backend = self
+            .backend
+            .as_mut()
+            .ok_or_else(|| anyhow!("This session has no LLM backend to send with"))?;
+        let resp = backend.fetch(
+            self.context.clone(),
+            history,
+            None,
+            self.settings.clone(),
+            None,
+        )?;
+        self.tokens_used += resp.total_tokens.unwrap_or(0);
+        let reply = resp
+            .choices
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("The LLM returned no choices"))?;
+        let content = reply.content.clone();
+        let commands = self
+            .add_reply(0, reply)
+            .into_iter()
+            .flatten()
+            .map(|(cmd, _)| cmd)
+            .collect();
+        Ok((content, commands))
+    }
+
+    /// Adds a message from the player, after the sim state set since the last one. Returns the
+    /// history to send, which ends with the message.
+    pub fn push_user_message(&mut self, msg: &str) -> Vec<(Role, String)> {
+        if let Some(snapshot) = self.sim_snapshot.take() {
+            self.messages.push((Role::SimState, snapshot.describe()));
+        }
+        self.messages.push((Role::User, normalize_message(msg)));
+        self.messages.clone()
+    }
+
+    /// Adds a reply, and returns the commands in it. A reply is identified by its position in the
+    /// whole conversation, so `archived` counts the messages no longer in `messages`.
+    pub fn add_reply(&mut self, archived: usize, reply: LlmReply) -> Vec<Vec<SourcedCommand>> {
+        let batches = self
+            .commands
+            .parse_reply(archived + self.messages.len(), &reply.content);
+        self.messages.push((Role::Assistant, reply.content));
+        if let Some(reasoning) = reply.reasoning {
+            self.messages.push((Role::Thoughts, reasoning));
+        }
+        batches
+    }
+
+    pub fn tokens_used(&self) -> usize {
+        self.tokens_used
+    }
+}
+
+/// Runs an experiment without a window, for `--chat-script`. Each non-blank line of the file is
+/// sent as a message, and every reply and command is printed. Without a clock, only stepping
+/// forward changes the simulation; other commands are reported and skipped.
+pub fn run_chat_script(sim_flags: &SimFlags, path: String) -> Result<()> {
+    let mut timer = Timer::new("run chat script");
+    let (map, mut sim, _) = sim_flags.load_synchronously(&mut timer);
+    let mut session = ChatSession::new(map.get_name().clone(), sim.get_run_name().clone())?;
+    session.set_scenario_params(ScenarioParams::from_sim(
+        map.get_name(),
+        &sim,
+        ChatSettings::load().ride_hail_quota_range,
+    ));
+    for line in fs_err::read_to_string(path)?.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        session.set_sim_snapshot(SimSnapshot::from_sim(&sim, None));
+        println!("> {}", line.trim());
+        let (reply, commands) = session.send(line)?;
+        println!("{reply}");
+        for cmd in commands {
+            if let ChatCommand::StepBy(dt) = cmd {
+                sim.timed_step(&map, dt, &mut None, &mut Timer::throwaway());
+                println!(
+                    "[Stepped forward {dt}. It's now {}]",
+                    sim.time().ampm_tostring()
+                );
+            } else {
+                println!("[Can't {} without a window]", cmd.describe(Locale::English));
+            }
+        }
+    }
+    println!("[Used {} tokens]", prettyprint_usize(session.tokens_used()));
+    Ok(())
+}
+
+/// Finds commands in an LLM reply, split into batches that must be applied together. The
+/// structured protocol (lines starting with `ACTION:` or slash commands) takes priority, and every
+/// such line is used in order. Commands between `ACTION: begin` and `ACTION: end` form one batch;
+/// all others are applied individually. Without any structured lines, only a reply consisting
+/// entirely of a recognized phrase counts, so that prose merely mentioning "stop" or "faster"
+/// doesn't trigger anything.
+///
+/// Each command is paired with the line that produced it.
+pub fn parse_commands(reply: &str) -> Vec<Vec<SourcedCommand>> {
+    let mut batches = Vec::new();
+    let mut group: Option<Vec<SourcedCommand>> = None;
+    for raw_line in reply.lines() {
+        let source = raw_line.trim();
+        let line = source.to_lowercase();
+        let phrase = if let Some(rest) = line.strip_prefix("action:") {
+            rest
+        } else if let Some(rest) = line.strip_prefix('/') {
+            rest
+        } else {
+            continue;
+        };
+        match phrase.trim() {
+            "begin" => {
+                batches.extend(group.replace(Vec::new()));
+            }
+            "end" => {
+                batches.extend(group.take());
+            }
+            _ => {
+                if let Some(cmd) = command_from_phrase(phrase) {
+                    let cmd = (cmd, source.to_string());
+                    if let Some(ref mut group) = group {
+                        group.push(cmd);
+                    } else {
+                        batches.push(vec![cmd]);
+                    }
+                }
+            }
+        }
+    }
+    // A group missing its end marker still gets applied together
+    batches.extend(group);
+    batches.retain(|batch| !batch.is_empty());
+
+    if batches.is_empty() {
+        batches.extend(
+            command_from_phrase(&reply.to_lowercase())
+                .map(|cmd| vec![(cmd, reply.trim().to_string())]),
+        );
+    }
+    batches
+}
+
+/// A follow-up asking the LLM to justify the actions in one of its replies, or `None` if it had
+/// none. The actions are quoted as they were written, and the LLM is asked not to repeat them, so
+/// the explanation doesn't run them again.
+pub fn explain_prompt(reply: &str) -> Option<String> {
+    let sources: Vec<String> = parse_commands(reply)
+        .into_iter()
+        .flatten()
+        .map(|(_, source)| source)
+        .collect();
+    let issued = match sources.as_slice() {
+        [] => return None,
+        [source] => source.clone(),
+        // Bulleted, so no line starts like an action
+        _ => format!("these actions:\n- {}", sources.join("\n- ")),
+    };
+    Some(format!(
+        "Explain why you issued {issued}\n\nAnswer in prose, without writing any action lines."
+    ))
+}
+
+/// Explains each action line that names a known command, but with arguments that can't be parsed.
+/// Lines that don't look like actions at all are left alone.
+pub fn malformed_commands(reply: &str, locale: Locale) -> Vec<String> {
+    let mut problems = Vec::new();
+    for raw_line in reply.lines() {
+        let source = raw_line.trim();
+        let line = source.to_lowercase();
+        let phrase = match line
+            .strip_prefix("action:")
+            .or_else(|| line.strip_prefix('/'))
+        {
+            Some(phrase) => phrase.trim(),
+            None => continue,
+        };
+        if command_from_phrase(phrase).is_some() {
+            continue;
+        }
+        let expected = match phrase.split_whitespace().next() {
+            Some("step") => Msg::ExpectedDuration,
+            Some("set_quota") => Msg::ExpectedVehicles,
+            _ => continue,
+        };
+        problems.push(locale.malformed_action(source, expected));
+    }
+    problems
+}
+
+/// How to ask for each kind of action, in the grammar `command_from_phrase` understands
+pub struct ActionExample {
+    /// The action line, with placeholders for arguments
+    pub syntax: &'static str,
+    /// A complete action line that parses
+    example: &'static str,
+    when: &'static str,
+}
+
+pub const ACTION_EXAMPLES: [ActionExample; 6] = [
+    ActionExample {
+        syntax: "pause",
+        example: "pause",
+        when: "asked to stop the simulation",
+    },
+    ActionExample {
+        syntax: "resume",
+        example: "resume",
+        when: "asked to start the simulation again",
+    },
+    ActionExample {
+        syntax: "slow down",
+        example: "slow down",
+        when: "asked to run the simulation slower",
+    },
+    ActionExample {
+        syntax: "speed up",
+        example: "speed up",
+        when: "asked to run the simulation faster",
+    },
+    ActionExample {
+        syntax: "set_quota <vehicles>",
+        example: "set_quota 5000",
+        when: "asked to change how many ride-hailing vehicles there are",
+    },
+    ActionExample {
+        syntax: "step <duration>",
+        example: "step 5min",
+        when: "asked to run the simulation forward a fixed amount",
+    },
+];
+
+impl ActionExample {
+    /// A sentence for a prompt, asking the LLM to use this action
+    pub fn instruction(&self) -> String {
+        if self.syntax == self.example {
+            format!("Respond with ACTION: {} when {}.", self.syntax, self.when)
+        } else {
+            format!(
+                "Respond with ACTION: {} when {}, like ACTION: {}.",
+                self.syntax, self.when, self.example
+            )
+        }
+    }
+}
+
+pub fn command_from_phrase(phrase: &str) -> Option<ChatCommand> {
+    let phrase = phrase
+        .trim()
+        .trim_end_matches(|c: char| c == '.' || c == '!')
+        .trim_end_matches(" the simulation")
+        .trim_end_matches(" the sim");
+    match phrase {
+        "pause" | "stop" | "freeze" => Some(ChatCommand::Pause),
+        "resume" | "play" | "unpause" => Some(ChatCommand::Resume),
+        "slow down" | "slower" => Some(ChatCommand::SlowDown),
+        "speed up" | "faster" => Some(ChatCommand::SpeedUp),
+        _ => {
+            if let Some(dt) = phrase.strip_prefix("step ") {
+                return parse_duration(dt).map(ChatCommand::StepBy);
+            }
+            // Allow "5,000" or "5_000"
+            let quota = phrase.strip_prefix("set_quota ")?.replace([',', '_'], "");
+            quota.trim().parse().ok().map(ChatCommand::SetRideHailQuota)
+        }
+    }
+}
+
+/// The batch to apply this frame. Ones the player applied by hand come first, and only they run in
+/// dry run.
+pub fn next_batch(
+    manual: &mut CommandQueue,
+    pending: &mut CommandQueue,
+    dry_run: bool,
+) -> Vec<SourcedCommand> {
+    let batch = manual.take_batch();
+    if batch.is_empty() && !dry_run {
+        pending.take_batch()
+    } else {
+        batch
+    }
+}
+
+/// Describes what each batch of commands would have done.
+pub fn dry_run_messages(batches: &[Vec<SourcedCommand>], locale: Locale) -> Vec<String> {
+    batches
+        .iter()
+        .map(|batch| {
+            let steps: Vec<String> = batch.iter().map(|(cmd, _)| cmd.describe(locale)).collect();
+            locale.dry_run(&locale.steps(&steps))
+        })
+        .collect()
+}
+
+/// Parses durations like "5min", "30 seconds", or "1.5h". A unit is required.
+fn parse_duration(input: &str) -> Option<Duration> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(input.len());
+    let value: f64 = input[..split].parse().ok()?;
+    let unit = match input[split..].trim() {
+        "s" | "sec" | "secs" | "second" | "seconds" => 1.0,
+        "m" | "min" | "mins" | "minute" | "minutes" => 60.0,
+        "h" | "hr" | "hrs" | "hour" | "hours" => 3600.0,
+        _ => return None,
+    };
+    Some(Duration::seconds(value * unit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sandbox::chat::transport::{ReplayExchange, ReplyOrder};
+
+    #[test]
+    fn test_parse_commands() {
+        use ChatCommand::*;
+
+        for (reply, expected) in [
+            ("ACTION: pause", vec![vec![Pause]]),
+            ("Sure, pausing now.\nACTION: pause", vec![vec![Pause]]),
+            ("/play", vec![vec![Resume]]),
+            ("Resume", vec![vec![Resume]]),
+            ("stop", vec![vec![Pause]]),
+            ("Freeze!", vec![vec![Pause]]),
+            ("Stop the simulation.", vec![vec![Pause]]),
+            ("slow down", vec![vec![SlowDown]]),
+            ("Slow down the sim", vec![vec![SlowDown]]),
+            ("ACTION: slow down", vec![vec![SlowDown]]),
+            ("speed up", vec![vec![SpeedUp]]),
+            ("Faster.", vec![vec![SpeedUp]]),
+            ("action: speed up", vec![vec![SpeedUp]]),
+            // The structured protocol wins over the rest of the reply
+            (
+                "Traffic will slow down.\nACTION: speed up",
+                vec![vec![SpeedUp]],
+            ),
+            // Multiple commands are kept in order
+            (
+                "ACTION: pause\nLet me check.\nACTION: speed up\nACTION: resume",
+                vec![vec![Pause], vec![SpeedUp], vec![Resume]],
+            ),
+            // Groups
+            (
+                "ACTION: begin\nACTION: pause\nACTION: slow down\nACTION: resume\nACTION: end",
+                vec![vec![Pause, SlowDown, Resume]],
+            ),
+            (
+                "ACTION: speed up\nACTION: begin\nACTION: pause\nACTION: resume",
+                vec![vec![SpeedUp], vec![Pause, Resume]],
+            ),
+            ("ACTION: begin\nACTION: end", vec![]),
+            // Prose mentioning commands shouldn't trigger anything
+            ("Congestion doesn't stop at rush hour.", vec![]),
+            ("Buses could speed up if we add a lane.", vec![]),
+            ("Don't pause yet.", vec![]),
+            // Quotas
+            ("ACTION: set_quota 5000", vec![vec![SetRideHailQuota(5000)]]),
+            ("/set_quota 2,500", vec![vec![SetRideHailQuota(2500)]]),
+            ("ACTION: set_quota lots", vec![]),
+            ("ACTION: set_quota -5", vec![]),
+            ("set_quota 3000", vec![vec![SetRideHailQuota(3000)]]),
+            // Stepping
+            (
+                "ACTION: step 5min",
+                vec![vec![StepBy(Duration::minutes(5))]],
+            ),
+            (
+                "ACTION: step 30 seconds",
+                vec![vec![StepBy(Duration::seconds(30.0))]],
+            ),
+            (
+                "ACTION: step 1.5h",
+                vec![vec![StepBy(Duration::minutes(90))]],
+            ),
+            // Zero parses, so it can be rejected with feedback when applied
+            ("ACTION: step 0s", vec![vec![StepBy(Duration::ZERO)]]),
+            ("ACTION: step 5", vec![]),
+            ("ACTION: step soon", vec![]),
+        ] {
+            assert_eq!(
+                commands_only(parse_commands(reply)),
+                expected,
+                "parsing {:?}",
+                reply
+            );
+        }
+    }
+
+    fn commands_only(batches: Vec<Vec<SourcedCommand>>) -> Vec<Vec<ChatCommand>> {
+        batches
+            .into_iter()
+            .map(|batch| batch.into_iter().map(|(cmd, _)| cmd).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_action_examples() {
+        for example in &ACTION_EXAMPLES {
+            // Every example is something a reply could actually use
+            let batches = parse_commands(&format!("ACTION: {}", example.example));
+            assert_eq!(batches.len(), 1, "{}", example.example);
+            assert!(example.instruction().starts_with("Respond with ACTION: "));
+        }
+        assert_eq!(
+            ACTION_EXAMPLES[5].instruction(),
+            "Respond with ACTION: step <duration> when asked to run the simulation forward a \
+             fixed amount, like ACTION: step 5min."
+        );
+    }
+
+    #[test]
+    fn test_out_of_order_replies_apply_in_order() {
+        use ChatCommand::*;
+
+        let mut order = ReplyOrder::default();
+        let mut queue = CommandQueue::default();
+        let mut transcript = vec!["Pause it".to_string(), "Now speed it up".to_string()];
+        let first = order.issue();
+        let second = order.issue();
+
+        // Both requests are in flight, and the second one's reply comes back first
+        assert!(order.arrived(second, "ACTION: speed up").is_empty());
+        let replies = order.arrived(first, "ACTION: pause");
+        assert_eq!(replies, vec!["ACTION: pause", "ACTION: speed up"]);
+
+        // Like add_reply, each reply is parsed at the position it's added to the transcript. If
+        // the later reply had been added first, the earlier one would look already parsed.
+        for reply in replies {
+            let batches = queue.parse_reply(transcript.len(), reply);
+            queue.extend(batches);
+            transcript.push(reply.to_string());
+        }
+        assert_eq!(
+            commands_only(vec![queue.take_batch(), queue.take_batch()]),
+            vec![vec![Pause], vec![SpeedUp]]
+        );
+        assert!(queue.take_batch().is_empty());
+    }
+
+    #[test]
+    fn test_gridlock_watch() {
+        let mut watch = GridlockWatch::default();
+        let at = |minutes: usize| Time::START_OF_DAY + Duration::minutes(minutes);
+        let stuck = |ids: &[usize]| -> Vec<(IntersectionID, Time)> {
+            ids.iter().map(|i| (IntersectionID(*i), at(0))).collect()
+        };
+        assert_eq!(watch.newly_stuck(at(10), || stuck(&[1])), stuck(&[1]));
+        // Not checked again within a sim minute
+        assert!(watch
+            .newly_stuck(at(10), || panic!("checked too soon"))
+            .is_empty());
+        // Still stuck isn't news, but another intersection is
+        assert_eq!(watch.newly_stuck(at(11), || stuck(&[1, 2])), stuck(&[2]));
+        // Once it clears, it can be reported again
+        assert!(watch.newly_stuck(at(12), || stuck(&[2])).is_empty());
+        assert_eq!(watch.newly_stuck(at(13), || stuck(&[1, 2])), stuck(&[1]));
+
+        // The clock going backwards starts over
+        assert_eq!(watch.newly_stuck(at(5), || stuck(&[1, 2])), stuck(&[1, 2]));
+    }
+
+    #[test]
+    fn test_malformed_commands() {
+        assert_eq!(
+            malformed_commands("ACTION: step soon", Locale::English),
+            vec![
+                "Couldn't run \"ACTION: step soon\": expected a duration with a unit, like step \
+                  5min or step 30s."
+            ]
+        );
+        // A unit is required
+        assert_eq!(
+            malformed_commands("ACTION: step 5", Locale::English).len(),
+            1
+        );
+        assert_eq!(malformed_commands("/step", Locale::English).len(), 1);
+        assert_eq!(
+            malformed_commands("Sure.\nACTION: set_quota lots", Locale::English),
+            vec![
+                "Couldn't run \"ACTION: set_quota lots\": expected a whole number of vehicles, \
+                  like set_quota 5000."
+            ]
+        );
+        assert_eq!(
+            malformed_commands("ACTION: set_quota -5", Locale::English).len(),
+            1
+        );
+
+        // Valid actions, prose, and unknown commands aren't reported
+        assert!(malformed_commands(
+            "ACTION: step 5min\nACTION: set_quota 5,000",
+            Locale::English
+        )
+        .is_empty());
+        assert!(
+            malformed_commands("You could step through it slowly.", Locale::English).is_empty()
+        );
+        assert!(malformed_commands("ACTION: jump_to yesterday", Locale::English).is_empty());
+    }
+
+    #[test]
+    fn test_dry_run_messages() {
+        let batches = parse_commands(
+            "ACTION: set_quota 5000\nACTION: begin\nACTION: pause\nACTION: slow down\n\
+             ACTION: end",
+        );
+        assert_eq!(
+            dry_run_messages(&batches, Locale::English),
+            vec![
+                "[dry-run] would set the ride-hailing quota to 5,000 vehicles".to_string(),
+                "[dry-run] would pause, then slow down".to_string(),
+            ]
+        );
+        assert_eq!(
+            dry_run_messages(&batches, Locale::Chinese),
+            vec![
+                "[试运行] 将会将网约车配额设为 5,000 辆".to_string(),
+                "[试运行] 将会暂停，然后减速".to_string(),
+            ]
+        );
+        assert!(dry_run_messages(&parse_commands("No actions here"), Locale::English).is_empty());
+    }
+
+    #[test]
+    fn test_command_sources() {
+        use ChatCommand::*;
+
+        assert_eq!(
+            parse_commands("Sure.\n  ACTION: Pause  \nACTION: begin\n/step 5min\nACTION: end"),
+            vec![
+                vec![(Pause, "ACTION: Pause".to_string())],
+                vec![(StepBy(Duration::minutes(5)), "/step 5min".to_string())],
+            ]
+        );
+        assert_eq!(
+            parse_commands(" Slow down. "),
+            vec![vec![(SlowDown, "Slow down.".to_string())]]
+        );
+    }
+
+    #[test]
+    fn test_explain_prompt() {
+        assert_eq!(explain_prompt("Traffic looks fine."), None);
+        assert_eq!(
+            explain_prompt("Slowing things down.\nACTION: slower").unwrap(),
+            "Explain why you issued ACTION: slower\n\nAnswer in prose, without writing any \
+             action lines."
+        );
+        let prompt = explain_prompt("ACTION: pause\n/set_quota 5000").unwrap();
+        assert!(prompt.starts_with(
+            "Explain why you issued these actions:\n- ACTION: pause\n- /set_quota 5000\n"
+        ));
+        // The prompt itself mustn't look like it issues anything
+        assert!(parse_commands(&prompt).is_empty());
+    }
+
+    #[test]
+    fn test_grouped_commands_apply_in_one_frame() {
+        use ChatCommand::*;
+
+        let mut queue = CommandQueue::default();
+        queue.extend(parse_commands(
+            "ACTION: speed up\nACTION: begin\nACTION: pause\nACTION: slow down\n\
+             ACTION: resume\nACTION: end",
+        ));
+        assert_eq!(queue.len(), 4);
+
+        assert_eq!(commands_only(vec![queue.take_batch()]), vec![vec![SpeedUp]]);
+        assert_eq!(queue.len(), 3);
+        assert_eq!(
+            commands_only(vec![queue.take_batch()]),
+            vec![vec![Pause, SlowDown, Resume]]
+        );
+        assert_eq!(queue.len(), 0);
+        assert!(queue.take_batch().is_empty());
+    }
+
+    #[test]
+    fn test_merge_queued_actions() {
+        use ChatCommand::*;
+
+        let mut queue = CommandQueue::default();
+        queue.merge();
+        assert!(queue.take_batch().is_empty());
+
+        queue.extend(parse_commands(
+            "ACTION: speed up\nACTION: begin\nACTION: pause\nACTION: resume\nACTION: end",
+        ));
+        assert_eq!(
+            Locale::English.queued_actions(queue.len()),
+            "3 actions from an earlier reply haven't run yet and may be out of date."
+        );
+        // Running them before a new message applies everything at once, in order
+        queue.merge();
+        assert_eq!(queue.len(), 3);
+        assert_eq!(
+            commands_only(vec![queue.take_batch()]),
+            vec![vec![SpeedUp, Pause, Resume]]
+        );
+        assert!(queue.take_batch().is_empty());
+    }
+
+    #[test]
+    fn test_manual_commands() {
+        use ChatCommand::*;
+
+        let mut manual = CommandQueue::default();
+        let mut pending = CommandQueue::default();
+        pending.extend(parse_commands("ACTION: speed up"));
+        // Dry run holds back replies, but not what the player applied
+        assert!(next_batch(&mut manual, &mut pending, true).is_empty());
+        manual.extend(parse_commands("ACTION: pause"));
+        assert_eq!(
+            commands_only(vec![next_batch(&mut manual, &mut pending, true)]),
+            vec![vec![Pause]]
+        );
+        assert!(next_batch(&mut manual, &mut pending, true).is_empty());
+
+        // Otherwise, they go first
+        manual.extend(parse_commands("ACTION: resume"));
+        assert_eq!(
+            commands_only(vec![next_batch(&mut manual, &mut pending, false)]),
+            vec![vec![Resume]]
+        );
+        assert_eq!(
+            commands_only(vec![next_batch(&mut manual, &mut pending, false)]),
+            vec![vec![SpeedUp]]
+        );
+    }
+
+    #[test]
+    fn test_reply_parsed_once() {
+        use ChatCommand::*;
+
+        let mut queue = CommandQueue::default();
+        let batches = queue.parse_reply(5, "ACTION: pause");
+        queue.extend(batches);
+        // Parsing the same reply again, or an older one, finds nothing new
+        assert!(queue.parse_reply(5, "ACTION: pause").is_empty());
+        assert!(queue.parse_reply(3, "ACTION: resume").is_empty());
+        assert_eq!(queue.len(), 1);
+
+        assert_eq!(commands_only(vec![queue.take_batch()]), vec![vec![Pause]]);
+        assert!(queue.take_batch().is_empty());
+
+        assert_eq!(queue.parse_reply(7, "ACTION: resume").len(), 1);
+    }
+
+    #[test]
+    fn test_auto_pause() {
+        use ChatCommand::*;
+
+        // Pause while typing, then resume
+        let mut auto = AutoPause::default();
+        assert_eq!(auto.focus_changed(true, true), Some(Pause));
+        assert_eq!(auto.focus_changed(false, false), Some(Resume));
+
+        // If the player had already paused, leave it paused
+        assert_eq!(auto.focus_changed(true, false), None);
+        assert_eq!(auto.focus_changed(false, false), None);
+
+        // The LLM resuming or pausing while the player types isn't undone afterwards
+        assert_eq!(auto.focus_changed(true, true), Some(Pause));
+        auto.explicit_command(Resume);
+        assert_eq!(auto.focus_changed(false, true), None);
+        assert_eq!(auto.focus_changed(true, true), Some(Pause));
+        auto.explicit_command(Pause);
+        assert_eq!(auto.focus_changed(false, false), None);
+
+        // Other commands don't matter
+        assert_eq!(auto.focus_changed(true, true), Some(Pause));
+        auto.explicit_command(SlowDown);
+        assert_eq!(auto.focus_changed(false, false), Some(Resume));
+    }
+
+    #[test]
+    fn test_chat_session() {
+        let replay = ReplayBackend::new(vec![
+            ReplayExchange {
+                user: "crowded, slow down".to_string(),
+                assistant: "Slowing down.\nACTION: slower".to_string(),
+            },
+            ReplayExchange {
+                user: "jump ahead".to_string(),
+                assistant: "ACTION: begin\nACTION: pause\nACTION: step 10min\nACTION: end"
+                    .to_string(),
+            },
+        ]);
+        let mut session = ChatSession::with_backend(
+            ChatContext {
+                map: MapName::seattle("montlake"),
+                scenario: "weekday".to_string(),
+            },
+            Some(Box::new(replay)),
+        );
+
+        let (reply, commands) = session.send("  crowded, slow down ").unwrap();
+        assert_eq!(reply, "Slowing down.\nACTION: slower");
+        assert_eq!(commands, vec![ChatCommand::SlowDown]);
+
+        let (_, commands) = session.send("jump ahead").unwrap();
+        assert_eq!(
+            commands,
+            vec![
+                ChatCommand::Pause,
+                ChatCommand::StepBy(Duration::minutes(10))
+            ]
+        );
+
+        assert!(session.send("anything else?").is_err());
+
+        // Like the chatbox's, a session without a backend can't send by itself
+        let mut session = ChatSession::with_backend(session.context.clone(), None);
+        assert!(session.send("hello?").is_err());
+    }
+}
@@ -0,0 +1,2109 @@
+//! Getting replies from the LLM: building requests from the transcript, sending them to an
+//! OpenAI-compatible API or replaying a recording, and reading the responses.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::time::Instant;
+
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use abstutil::Timer;
+use geom::Duration;
+use widgetry::{Color, Text};
+
+use crate::sandbox::chat::{last_message, normalize_message, ChatContext, Role};
+use crate::sandbox::chat_i18n::{Locale, Msg};
+
+/// Health checks should be quick; a real request can wait longer
+const HEALTH_CHECK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// The range OpenAI-compatible APIs accept for `frequency_penalty` and `presence_penalty`
+pub const MAX_PENALTY: f64 = 2.0;
+
+/// Low, so the LLM sticks to the ACTION format
+pub const TEMPERATURE: f32 = 0.2;
+
+/// What's known about whether the LLM provider is reachable and accepts the API key
+#[derive(Debug, PartialEq)]
+pub enum ConnectionStatus {
+    Checking,
+    Ok,
+    /// The provider answered, but the check couldn't confirm everything works
+    Unverified(String),
+    Failed(String),
+}
+
+impl ConnectionStatus {
+    pub fn color(&self) -> Color {
+        match self {
+            ConnectionStatus::Ok => Color::GREEN,
+            ConnectionStatus::Checking | ConnectionStatus::Unverified(_) => Color::YELLOW,
+            ConnectionStatus::Failed(_) => Color::RED,
+        }
+    }
+
+    pub fn describe(&self, locale: Locale) -> String {
+        match self {
+            ConnectionStatus::Checking => Msg::CheckingConnection.text(locale).to_string(),
+            ConnectionStatus::Ok => Msg::Connected.text(locale).to_string(),
+            ConnectionStatus::Unverified(msg) | ConnectionStatus::Failed(msg) => msg.clone(),
+        }
+    }
+}
+
+/// Everything one request returned
+pub struct LlmResponse {
+    pub choices: Vec<LlmReply>,
+    /// Prompt and completion tokens together, if the provider reports them
+    pub total_tokens: Option<usize>,
+}
+
+/// One choice from the LLM
+#[derive(Clone, Debug, PartialEq)]
+pub struct LlmReply {
+    pub content: String,
+    /// Reasoning models like deepseek-reasoner return their thoughts separately
+    pub reasoning: Option<String>,
+}
+
+/// The settings that shape a request, as they were when it was sent
+#[derive(Clone, PartialEq)]
+pub struct RequestSettings {
+    pub context_messages: usize,
+    /// Asks the provider to sample deterministically. OpenAI honors this on a best-effort basis;
+    /// DeepSeek and many compatible gateways accept it but ignore it.
+    pub seed: Option<u64>,
+    pub custom_prompt: String,
+    /// The `ScenarioParams` block, if it's attached
+    pub scenario_params: Option<String>,
+    /// The provider stops the reply before any of these
+    pub stop: Vec<String>,
+    pub max_tokens: Option<usize>,
+    /// `None` when the setting is 0, so the provider's default applies
+    pub frequency_penalty: Option<f64>,
+    pub presence_penalty: Option<f64>,
+}
+
+impl RequestSettings {
+    /// Explains what differs from `now`, or `None` if nothing does.
+    pub fn describe_change(&self, now: &RequestSettings, locale: Locale) -> Option<String> {
+        let mut changes = Vec::new();
+        if self.context_messages != now.context_messages {
+            changes.push(locale.sent_with(
+                &locale.context_message_count(self.context_messages),
+                &now.context_messages.to_string(),
+            ));
+        }
+        if self.seed != now.seed {
+            changes.push(locale.sent_with(
+                &describe_seed(self.seed, locale),
+                &describe_seed(now.seed, locale),
+            ));
+        }
+        if self.custom_prompt != now.custom_prompt {
+            changes.push(Msg::DifferentCustomPrompt.text(locale).to_string());
+        }
+        if self.scenario_params != now.scenario_params {
+            changes.push(Msg::DifferentScenario.text(locale).to_string());
+        }
+        if self.stop != now.stop {
+            changes.push(Msg::DifferentStopSequences.text(locale).to_string());
+        }
+        if self.max_tokens != now.max_tokens {
+            changes.push(locale.sent_with(
+                &describe_max_tokens(self.max_tokens, locale),
+                &describe_max_tokens(now.max_tokens, locale),
+            ));
+        }
+        for (frequency, then, now) in [
+            (true, self.frequency_penalty, now.frequency_penalty),
+            (false, self.presence_penalty, now.presence_penalty),
+        ] {
+            if then != now {
+                changes.push(locale.sent_with(
+                    &locale.penalty(frequency, then.unwrap_or(0.0)),
+                    &now.unwrap_or(0.0).to_string(),
+                ));
+            }
+        }
+        if changes.is_empty() {
+            None
+        } else {
+            Some(changes.join("; "))
+        }
+    }
+}
+
+/// Splits the comma-separated setting, ignoring blank entries and repeats. Surrounding whitespace
+/// is trimmed, so a sequence can't start or end with a space or newline.
+pub fn parse_stop_sequences(setting: &str) -> Vec<String> {
+    let mut stop: Vec<String> = Vec::new();
+    for entry in setting.split(',').map(|entry| entry.trim()) {
+        if !entry.is_empty() && !stop.iter().any(|seq| seq == entry) {
+            stop.push(entry.to_string());
+        }
+    }
+    stop
+}
+
+/// Clamps a penalty from the settings file to what providers accept. 0 is the provider's default,
+/// so it isn't sent at all.
+pub fn penalty(setting: f64) -> Option<f64> {
+    let value = setting.clamp(-MAX_PENALTY, MAX_PENALTY);
+    (value != 0.0).then_some(value)
+}
+
+fn describe_max_tokens(max_tokens: Option<usize>, locale: Locale) -> String {
+    match max_tokens {
+        Some(max) => locale.reply_cap(max),
+        None => Msg::ProviderReplyLength.text(locale).to_string(),
+    }
+}
+
+fn describe_seed(seed: Option<u64>, locale: Locale) -> String {
+    match seed {
+        Some(seed) => locale.seed(seed),
+        None => Msg::RandomSampling.text(locale).to_string(),
+    }
+}
+
+/// What's needed to send the inflight request again
+pub struct InflightRequest {
+    /// From `ReplyOrder::issue`
+    pub seq: u64,
+    /// Ends with the message being sent
+    pub history: Vec<(Role, String)>,
+    pub image: Option<String>,
+    pub settings: RequestSettings,
+}
+
+/// Messages from the worker thread handling one LLM request
+pub enum WorkerMsg {
+    /// The request is still in flight, after this many seconds
+    Heartbeat(u64),
+    /// More of a streamed reply's content
+    Chunk(String),
+    /// The final result. Nothing else is sent after this.
+    Done(Result<LlmResponse>),
+}
+
+/// Numbers requests as they're sent, and hands back their results in that same order, however they
+/// arrive. Results for cancelled requests are dropped, and so is anything arriving twice.
+pub struct ReplyOrder<T> {
+    next_seq: u64,
+    /// Every earlier request has been handed back or cancelled
+    next_to_release: u64,
+    /// Results that arrived before an earlier request's
+    early: BTreeMap<u64, T>,
+    /// Requests whose results will never be handed back
+    cancelled: BTreeSet<u64>,
+}
+
+impl<T> Default for ReplyOrder<T> {
+    fn default() -> Self {
+        ReplyOrder {
+            next_seq: 0,
+            next_to_release: 0,
+            early: BTreeMap::new(),
+            cancelled: BTreeSet::new(),
+        }
+    }
+}
+
+impl<T> ReplyOrder<T> {
+    /// The sequence number for a request about to be sent
+    pub fn issue(&mut self) -> u64 {
+        self.next_seq += 1;
+        self.next_seq - 1
+    }
+
+    /// Like `arrived`, returns results that were only waiting on this request.
+    pub fn cancel(&mut self, seq: u64) -> Vec<T> {
+        if seq >= self.next_to_release {
+            self.early.remove(&seq);
+            self.cancelled.insert(seq);
+        }
+        self.release()
+    }
+
+    /// Returns every result that can be handled now, oldest request first. That's empty if an
+    /// earlier request is still in flight.
+    pub fn arrived(&mut self, seq: u64, result: T) -> Vec<T> {
+        if seq >= self.next_to_release && seq < self.next_seq && !self.cancelled.contains(&seq) {
+            self.early.entry(seq).or_insert(result);
+        }
+        self.release()
+    }
+
+    fn release(&mut self) -> Vec<T> {
+        let mut ready = Vec::new();
+        loop {
+            if self.cancelled.remove(&self.next_to_release) {
+                self.next_to_release += 1;
+            } else if let Some(result) = self.early.remove(&self.next_to_release) {
+                ready.push(result);
+                self.next_to_release += 1;
+            } else {
+                return ready;
+            }
+        }
+    }
+}
+
+/// Runs a blocking request on its own thread, sending a heartbeat every period until it finishes,
+/// then the result.
+pub fn run_with_heartbeats<F: FnOnce() -> Result<LlmResponse> + Send + 'static>(
+    tx: Sender<WorkerMsg>,
+    period: std::time::Duration,
+    request: F,
+) {
+    let (result_tx, result_rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = result_tx.send(request());
+    });
+
+    let started = Instant::now();
+    loop {
+        match result_rx.recv_timeout(period) {
+            Ok(res) => {
+                let _ = tx.send(WorkerMsg::Done(res));
+                return;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                // Stop if the chatbox has gone away
+                if tx
+                    .send(WorkerMsg::Heartbeat(started.elapsed().as_secs()))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                let _ = tx.send(WorkerMsg::Done(Err(anyhow!("the LLM request crashed"))));
+                return;
+            }
+        }
+    }
+}
+
+/// The index of the oldest message that fits in a context window of `window` messages. Reasoning
+/// is never sent and a simulation snapshot goes with its message, so neither counts. Nothing
+/// before a context reset fits.
+pub fn context_window_start(messages: &[(Role, String)], window: usize) -> usize {
+    let mut start = messages.len();
+    let mut remaining = window;
+    for (idx, (role, _)) in messages.iter().enumerate().rev() {
+        if *role == Role::ContextReset {
+            break;
+        }
+        if matches!(role, Role::Thoughts | Role::SimState) {
+            continue;
+        }
+        if remaining == 0 {
+            break;
+        }
+        remaining -= 1;
+        start = idx;
+    }
+    start
+}
+
+#[derive(Serialize)]
+struct DeepseekChatRequest {
+    pub model: String,
+    pub messages: Vec<DeepseekMessage>,
+    temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct DeepseekMessage {
+    role: String,
+    pub content: MessageContent,
+}
+
+/// Plain text, unless there's an image. Not every model accepts the list form.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize)]
+struct ImageUrl {
+    url: String,
+}
+
+impl MessageContent {
+    /// Embeds a PNG image in the message, for vision models.
+    fn with_image(text: String, png: Option<&[u8]>) -> MessageContent {
+        let png = match png {
+            Some(png) => png,
+            None => return MessageContent::Text(text),
+        };
+        MessageContent::Parts(vec![
+            ContentPart::Text { text },
+            ContentPart::ImageUrl {
+                image_url: ImageUrl {
+                    url: format!("data:image/png;base64,{}", STANDARD.encode(png)),
+                },
+            },
+        ])
+    }
+}
+
+#[derive(Deserialize)]
+struct DeepseekChatResponse {
+    pub choices: Vec<DeepseekChoice>,
+    /// Some compatible providers leave this out
+    #[serde(default)]
+    usage: Option<DeepseekUsage>,
+}
+
+#[derive(Deserialize)]
+struct DeepseekUsage {
+    pub total_tokens: usize,
+}
+
+/// One server-sent event of a streamed reply
+#[derive(Deserialize)]
+struct DeepseekStreamChunk {
+    pub choices: Vec<DeepseekStreamChoice>,
+    #[serde(default)]
+    usage: Option<DeepseekUsage>,
+}
+
+#[derive(Deserialize)]
+struct DeepseekStreamChoice {
+    delta: DeepseekDelta,
+}
+
+#[derive(Deserialize)]
+struct DeepseekDelta {
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default)]
+    reasoning_content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DeepseekChoice {
+    message: DeepseekMessageOut,
+}
+
+#[derive(Deserialize)]
+struct DeepseekMessageOut {
+    pub content: String,
+    /// Only reasoning models send this
+    reasoning_content: Option<String>,
+}
+
+/// Where and how to reach the LLM provider.
+///
+/// By default, this talks to DeepSeek's `deepseek-chat` model with a bearer token. `DEEPSEEK_MODEL`
+/// picks another model, like `deepseek-reasoner`. Other OpenAI-compatible endpoints work by
+/// changing `DEEPSEEK_BASE_URL`, and gateways that expect the key in a different header can set
+/// `LLM_AUTH_SCHEME=header` and `LLM_AUTH_HEADER` (defaulting to `api-key`).
+///
+/// Azure OpenAI needs both: the key goes in the `api-key` header, and the URL names a deployment
+/// and API version instead of a model. For example:
+///
+/// ```text
+/// DEEPSEEK_BASE_URL=https://my-resource.openai.azure.com/openai/deployments/my-deployment
+/// LLM_API_VERSION=2024-02-01
+/// LLM_AUTH_SCHEME=header
+/// ```
+pub struct LlmConfig {
+    api_key: String,
+    base_url: String,
+    /// Like `deepseek-chat` or `deepseek-reasoner`
+    pub model: String,
+    auth: AuthScheme,
+    /// Sent as the `api-version` query parameter, which Azure requires
+    api_version: Option<String>,
+    /// The model accepts images. Set by `LLM_VISION=1`.
+    pub vision: bool,
+}
+
+impl LlmConfig {
+    pub fn from_env() -> Result<LlmConfig> {
+        let api_key = std::env::var("DEEPSEEK_API_KEY")
+            .map_err(|_| anyhow::anyhow!("Missing DEEPSEEK_API_KEY env var"))?;
+        let base_url = std::env::var("DEEPSEEK_BASE_URL")
+            .unwrap_or_else(|_| "https://api.deepseek.com/v1".to_string());
+        let model = std::env::var("DEEPSEEK_MODEL").unwrap_or_else(|_| "deepseek-chat".to_string());
+        let auth = AuthScheme::parse(
+            std::env::var("LLM_AUTH_SCHEME").ok(),
+            std::env::var("LLM_AUTH_HEADER").ok(),
+        )?;
+        let api_version = std::env::var("LLM_API_VERSION").ok();
+        let vision = matches!(std::env::var("LLM_VISION").as_deref(), Ok("1") | Ok("true"));
+        Ok(LlmConfig {
+            api_key,
+            base_url,
+            model,
+            auth,
+            api_version,
+            vision,
+        })
+    }
+
+    /// `DEEPSEEK_BASE_URL` should stop before `/chat/completions`, but the full endpoint is
+    /// accepted too, since that's what provider docs often show.
+    fn url(&self, path: &str) -> String {
+        let base = self.base_url.trim_end_matches('/');
+        let base = base.strip_suffix("/chat/completions").unwrap_or(base);
+        let url = format!("{base}/{path}");
+        match self.api_version {
+            Some(ref version) => format!("{url}?api-version={version}"),
+            None => url,
+        }
+    }
+}
+
+/// Replays a recorded conversation instead of calling an LLM, for demos and tests. Chosen with
+/// `LLM_BACKEND=replay`, reading the file named by `LLM_REPLAY_FILE`. That's a JSON list of
+/// exchanges, in the order they were recorded:
+///
+/// ```json
+/// [
+///   {"user": "It's getting crowded, slow down", "assistant": "Slowing down.\nslower"},
+///   {"user": "How are the drivers doing?", "assistant": "Most are busy."}
+/// ]
+/// ```
+///
+/// A message gets the reply of the first unused exchange with the same `user` text, ignoring case
+/// and surrounding whitespace. Otherwise the next unused exchange answers, so a demo still works
+/// when the player words things differently. Each exchange answers once.
+pub struct ReplayBackend {
+    exchanges: Vec<ReplayExchange>,
+    used: Vec<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct ReplayExchange {
+    pub user: String,
+    pub assistant: String,
+}
+
+impl ReplayBackend {
+    pub fn new(exchanges: Vec<ReplayExchange>) -> ReplayBackend {
+        ReplayBackend {
+            used: vec![false; exchanges.len()],
+            exchanges,
+        }
+    }
+
+    /// `None` means requests go to the LLM over the network, as usual.
+    pub fn from_env() -> Result<Option<ReplayBackend>> {
+        match std::env::var("LLM_BACKEND").as_deref() {
+            Err(_) | Ok("http") => Ok(None),
+            Ok("replay") => {
+                let path = std::env::var("LLM_REPLAY_FILE").map_err(|_| {
+                    anyhow::anyhow!("LLM_BACKEND=replay needs LLM_REPLAY_FILE to be set")
+                })?;
+                let exchanges =
+                    abstio::maybe_read_json::<Vec<ReplayExchange>>(path, &mut Timer::throwaway())?;
+                Ok(Some(ReplayBackend::new(exchanges)))
+            }
+            Ok(other) => bail!("Unknown LLM_BACKEND {other}; use http or replay"),
+        }
+    }
+
+    fn reply(&mut self, user_msg: &str) -> Result<String> {
+        let wanted = user_msg.trim().to_lowercase();
+        let idx = (0..self.exchanges.len())
+            .filter(|idx| !self.used[*idx])
+            .find(|idx| self.exchanges[*idx].user.trim().to_lowercase() == wanted)
+            .or_else(|| self.used.iter().position(|used| !used))
+            .ok_or_else(|| anyhow::anyhow!("The recorded conversation has no replies left"))?;
+        self.used[idx] = true;
+        Ok(self.exchanges[idx].assistant.clone())
+    }
+}
+
+/// How the API key is sent
+#[derive(Debug, PartialEq)]
+pub enum AuthScheme {
+    /// `Authorization: Bearer <key>`, used by DeepSeek and OpenAI
+    Bearer,
+    /// The raw key in a custom header, like `api-key` for Azure or `x-api-key` for some proxies
+    Header(String),
+}
+
+impl AuthScheme {
+    /// Interprets the `LLM_AUTH_SCHEME` and `LLM_AUTH_HEADER` env vars. Naming a header implies
+    /// the header scheme.
+    pub fn parse(scheme: Option<String>, header: Option<String>) -> Result<AuthScheme> {
+        match (scheme.as_deref().map(str::to_lowercase).as_deref(), header) {
+            (None | Some("bearer"), None) => Ok(AuthScheme::Bearer),
+            (Some("bearer"), Some(header)) => {
+                bail!("LLM_AUTH_HEADER={header} only makes sense with LLM_AUTH_SCHEME=header")
+            }
+            (None | Some("header"), Some(header)) => Ok(AuthScheme::Header(header)),
+            (Some("header"), None) => Ok(AuthScheme::Header("api-key".to_string())),
+            (Some(other), _) => bail!("Unknown LLM_AUTH_SCHEME {other}; use bearer or header"),
+        }
+    }
+
+    fn apply(
+        &self,
+        req: reqwest::blocking::RequestBuilder,
+        api_key: &str,
+    ) -> reqwest::blocking::RequestBuilder {
+        match self {
+            AuthScheme::Bearer => req.bearer_auth(api_key),
+            AuthScheme::Header(name) => req.header(name.as_str(), api_key),
+        }
+    }
+}
+
+pub fn system_prompt(context: &ChatContext) -> String {
+    format!(
+        "You are controlling a traffic simulation of {}. You may include lines like \
+ACTION: pause, ACTION: resume, ACTION: slow down, or ACTION: speed up. To change how many \
+ride-hailing vehicles the study uses, write a line like ACTION: set_quota 5000; the quota is only \
+recorded for the study, since the simulation doesn't model a ride-hailing fleet yet. To run the \
+simulation forward a fixed amount and then pause, write a line like ACTION: step 5min. To apply \
+several actions at once, put them between ACTION: begin and ACTION: end. Keep replies short.",
+        context.describe()
+    )
+}
+
+/// Drops reasoning, since resending it just wastes tokens, and folds each simulation snapshot into
+/// the user message it was sent with.
+fn attach_sim_states(history: Vec<(Role, String)>) -> Vec<(Role, String)> {
+    let mut result = Vec::new();
+    let mut snapshot = None;
+    for (role, content) in history {
+        match role {
+            Role::Thoughts => {}
+            Role::SimState => {
+                snapshot = Some(content);
+            }
+            Role::User => match snapshot.take() {
+                Some(state) => result.push((
+                    Role::User,
+                    format!("{content}\n\n[Simulation state when sent: {state}]"),
+                )),
+                None => result.push((Role::User, content)),
+            },
+            _ => result.push((role, content)),
+        }
+    }
+    result
+}
+
+/// Takes the sim state attached to the message being sent out of the history, so it can go in its
+/// own system message. Older states stay with the messages they were sent with.
+fn take_live_context(mut history: Vec<(Role, String)>) -> (Vec<(Role, String)>, Option<String>) {
+    match history
+        .iter()
+        .rposition(|(role, _)| matches!(role, Role::SimState | Role::Assistant))
+    {
+        Some(idx) if history[idx].0 == Role::SimState => {
+            let (_, state) = history.remove(idx);
+            (history, Some(state))
+        }
+        _ => (history, None),
+    }
+}
+
+/// Only what came after the last context reset
+fn since_context_reset(mut history: Vec<(Role, String)>) -> Vec<(Role, String)> {
+    if let Some(idx) = history
+        .iter()
+        .rposition(|(role, _)| *role == Role::ContextReset)
+    {
+        history.drain(..=idx);
+    }
+    history
+}
+
+/// Takes the results of the last reply's actions out of the history, so they're always sent, even
+/// when the context window is too small to include them. Results of older replies stay where they
+/// are.
+fn take_command_results(mut history: Vec<(Role, String)>) -> (Vec<(Role, String)>, Vec<String>) {
+    let start = history
+        .iter()
+        .rposition(|(role, _)| *role == Role::Assistant)
+        .map_or(0, |idx| idx + 1);
+    let mut results = Vec::new();
+    let mut idx = start;
+    while idx < history.len() {
+        if matches!(history[idx].0, Role::CommandResult | Role::ParamDiff) {
+            results.push(history.remove(idx).1);
+        } else {
+            idx += 1;
+        }
+    }
+    (history, results)
+}
+
+/// Everything sent for one request. `history` ends with the user message being sent, which doesn't
+/// count against the context window. The system parts come first, each as its own message, so none
+/// of them overwrites another: the researcher's custom instructions, the built-in ones describing
+/// actions, the scenario's parameters, the results of the last reply's actions, then the live sim
+/// state.
+fn request_messages(
+    context: &ChatContext,
+    mut history: Vec<(Role, String)>,
+    image: Option<Vec<u8>>,
+    settings: &RequestSettings,
+) -> Vec<DeepseekMessage> {
+    let user_msg = match history.last() {
+        Some((Role::User, _)) => history.pop().map(|(_, msg)| msg),
+        _ => None,
+    };
+    let (history, live_context) = take_live_context(since_context_reset(history));
+    let (history, results) = take_command_results(history);
+    let mut system = Vec::new();
+    if !settings.custom_prompt.trim().is_empty() {
+        system.push(settings.custom_prompt.trim().to_string());
+    }
+    system.push(system_prompt(context));
+    if let Some(ref params) = settings.scenario_params {
+        system.push(params.clone());
+    }
+    if !results.is_empty() {
+        system.push(format!(
+            "Results of the actions in your last reply:\n{}",
+            results.join("\n")
+        ));
+    }
+    if let Some(state) = live_context {
+        system.push(format!(
+            "Live simulation state when the player sent their next message:\n---\n{state}\n---"
+        ));
+    }
+
+    let mut messages: Vec<DeepseekMessage> = system
+        .into_iter()
+        .map(|content| DeepseekMessage {
+            role: "system".to_string(),
+            content: MessageContent::Text(content),
+        })
+        .collect();
+    let history = attach_sim_states(history);
+    for (role, content) in history
+        .into_iter()
+        .rev()
+        .take(settings.context_messages)
+        .rev()
+    {
+        let r = match role {
+            Role::User => "user",
+            Role::Assistant => "assistant",
+            Role::System | Role::CommandResult | Role::ParamDiff => "system",
+            Role::Thoughts | Role::SimState | Role::ContextReset => unreachable!(),
+        };
+        messages.push(DeepseekMessage {
+            role: r.to_string(),
+            content: MessageContent::Text(content),
+        });
+    }
+    if let Some(user_msg) = user_msg {
+        messages.push(DeepseekMessage {
+            role: "user".to_string(),
+            content: MessageContent::with_image(normalize_message(&user_msg), image.as_deref()),
+        });
+    }
+    messages
+}
+
+fn fetch_deepseek_reply(
+    config: &LlmConfig,
+    context: ChatContext,
+    history: Vec<(Role, String)>,
+    image: Option<Vec<u8>>,
+    settings: RequestSettings,
+    on_chunk: Option<&dyn Fn(&str) -> bool>,
+) -> Result<LlmResponse> {
+    let url = config.url("chat/completions");
+    let req = DeepseekChatRequest {
+        model: config.model.clone(),
+        messages: request_messages(&context, history, image, &settings),
+        temperature: TEMPERATURE,
+        seed: settings.seed,
+        stream: on_chunk.is_some(),
+        stop: settings.stop.clone(),
+        max_tokens: settings.max_tokens,
+        frequency_penalty: settings.frequency_penalty,
+        presence_penalty: settings.presence_penalty,
+    };
+
+    let client = reqwest::blocking::Client::new();
+    let resp = config
+        .auth
+        .apply(client.post(url), &config.api_key)
+        .json(&req)
+        .send()?;
+    let status = resp.status();
+    if !status.is_success() {
+        let body = resp.text().unwrap_or_default();
+        // The response body often explains more, but it's too verbose for the transcript
+        warn!("LLM request failed with {}: {}", status, body);
+        if is_balance_exhausted(status.as_u16(), &body) {
+            bail!(BalanceExhausted);
+        }
+        bail!(HttpStatusError(status));
+    }
+    if let Some(on_chunk) = on_chunk {
+        return read_stream(std::io::BufReader::new(resp), on_chunk);
+    }
+    // Read everything before parsing, so a body split across chunks is handled in one piece
+    let bytes = resp
+        .bytes()
+        .map_err(|err| anyhow::anyhow!("The LLM's reply was cut off: {err}"))?;
+    let body = parse_chat_response(&bytes)?;
+    let total_tokens = body.usage.map(|usage| usage.total_tokens);
+    if body.choices.is_empty() {
+        return Ok(LlmResponse {
+            choices: vec![LlmReply {
+                content: "(empty reply)".to_string(),
+                reasoning: None,
+            }],
+            total_tokens,
+        });
+    }
+    let choices = body
+        .choices
+        .into_iter()
+        .map(|c| LlmReply {
+            content: c.message.content,
+            reasoning: c
+                .message
+                .reasoning_content
+                .filter(|reasoning| !reasoning.trim().is_empty()),
+        })
+        .collect();
+    Ok(LlmResponse {
+        choices,
+        total_tokens,
+    })
+}
+
+/// Parses a complete, non-streamed response body. A body that ends partway through is reported as
+/// cut off, not as a confusing syntax error.
+fn parse_chat_response(body: &[u8]) -> Result<DeepseekChatResponse> {
+    serde_json::from_slice(body).map_err(|err| {
+        if err.is_eof() {
+            anyhow::anyhow!(
+                "The LLM's reply was cut off after {} bytes. Try again.",
+                body.len()
+            )
+        } else {
+            anyhow::anyhow!("The LLM's reply wasn't valid JSON: {err}")
+        }
+    })
+}
+
+/// Reads a streamed reply, passing each piece of content to `on_chunk` as it arrives. Only the
+/// first choice is kept. Once `on_chunk` returns false, this stops and drops the reader, which
+/// closes the connection.
+fn read_stream<R: std::io::BufRead>(
+    reader: R,
+    on_chunk: &dyn Fn(&str) -> bool,
+) -> Result<LlmResponse> {
+    let mut content = String::new();
+    let mut reasoning = String::new();
+    let mut total_tokens = None;
+    for line in reader.lines() {
+        let line = line?;
+        // Blank lines separate events, and lines starting with : are keep-alive comments
+        let data = match line.strip_prefix("data:") {
+            Some(data) => data.trim(),
+            None => continue,
+        };
+        if data == "[DONE]" {
+            break;
+        }
+        let chunk: DeepseekStreamChunk = serde_json::from_str(data)?;
+        if let Some(usage) = chunk.usage {
+            total_tokens = Some(usage.total_tokens);
+        }
+        if let Some(choice) = chunk.choices.into_iter().next() {
+            if let Some(text) = choice.delta.reasoning_content {
+                reasoning.push_str(&text);
+            }
+            if let Some(text) = choice.delta.content.filter(|text| !text.is_empty()) {
+                content.push_str(&text);
+                if !on_chunk(&text) {
+                    bail!("The reply was cancelled");
+                }
+            }
+        }
+    }
+    if content.is_empty() {
+        content = "(empty reply)".to_string();
+    }
+    Ok(LlmResponse {
+        choices: vec![LlmReply {
+            content,
+            reasoning: Some(reasoning).filter(|reasoning| !reasoning.trim().is_empty()),
+        }],
+        total_tokens,
+    })
+}
+
+/// Keeps what was streamed of a cancelled reply, clearly marked. It's added to the transcript
+/// directly, so its commands are never parsed.
+pub fn finish_cancelled(
+    messages: &mut Vec<(Role, String)>,
+    partial: Option<String>,
+    locale: Locale,
+) {
+    match partial {
+        Some(partial) if !partial.trim().is_empty() => {
+            messages.push((Role::Assistant, locale.cancelled_reply(partial.trim_end())));
+        }
+        _ => {
+            messages.push((
+                Role::System,
+                Msg::CancelledBeforeReply.text(locale).to_string(),
+            ));
+        }
+    }
+}
+
+/// Answers chat requests. The chatbox and `ChatSession` both go through this, so a recording can
+/// stand in for the provider anywhere.
+pub trait LlmBackend {
+    /// `history` ends with the message being sent. Everything before it is trimmed to the
+    /// settings' context size. `on_chunk` is only called by backends that stream.
+    fn fetch(
+        &mut self,
+        context: ChatContext,
+        history: Vec<(Role, String)>,
+        image: Option<Vec<u8>>,
+        settings: RequestSettings,
+        on_chunk: Option<&dyn Fn(&str) -> bool>,
+    ) -> Result<LlmResponse>;
+}
+
+impl LlmBackend for LlmConfig {
+    fn fetch(
+        &mut self,
+        context: ChatContext,
+        history: Vec<(Role, String)>,
+        image: Option<Vec<u8>>,
+        settings: RequestSettings,
+        on_chunk: Option<&dyn Fn(&str) -> bool>,
+    ) -> Result<LlmResponse> {
+        fetch_deepseek_reply(self, context, history, image, settings, on_chunk)
+    }
+}
+
+impl LlmBackend for ReplayBackend {
+    fn fetch(
+        &mut self,
+        _: ChatContext,
+        history: Vec<(Role, String)>,
+        _: Option<Vec<u8>>,
+        _: RequestSettings,
+        _: Option<&dyn Fn(&str) -> bool>,
+    ) -> Result<LlmResponse> {
+        self.reply(last_message(&history, Role::User).unwrap_or(""))
+            .map(|content| LlmResponse {
+                choices: vec![LlmReply {
+                    content,
+                    reasoning: None,
+                }],
+                total_tokens: None,
+            })
+    }
+}
+
+/// Lists the provider's models, which checks the URL and API key without spending any tokens.
+pub fn check_connection(config: &LlmConfig, locale: Locale) -> ConnectionStatus {
+    let resp = reqwest::blocking::Client::builder()
+        .timeout(HEALTH_CHECK_TIMEOUT)
+        .build()
+        .and_then(|client| {
+            config
+                .auth
+                .apply(client.get(config.url("models")), &config.api_key)
+                .send()
+        });
+    match resp {
+        Ok(resp) if resp.status().is_success() => ConnectionStatus::Ok,
+        // Some gateways, like Azure deployments, don't offer a models list
+        Ok(resp) if matches!(resp.status().as_u16(), 404 | 405) => {
+            ConnectionStatus::Unverified(Msg::ConnectionUnverified.text(locale).to_string())
+        }
+        Ok(resp) => ConnectionStatus::Failed(describe_http_status(resp.status(), locale)),
+        Err(err) => ConnectionStatus::Failed(locale.unreachable(&err.to_string())),
+    }
+}
+
+/// Turns an HTTP error status from the LLM provider into something actionable.
+fn describe_http_status(status: reqwest::StatusCode, locale: Locale) -> String {
+    let msg = match status.as_u16() {
+        401 => Msg::InvalidApiKey,
+        403 => Msg::AccessDenied,
+        404 => Msg::WrongBaseUrl,
+        429 => Msg::RateLimited,
+        500..=599 => Msg::ProviderError,
+        _ => Msg::RequestFailed,
+    };
+    format!("{} (HTTP {status})", msg.text(locale))
+}
+
+/// A request the LLM provider answered with an error status. It's kept apart from other errors so
+/// the chatbox can describe it in the player's language.
+#[derive(Debug)]
+struct HttpStatusError(reqwest::StatusCode);
+
+impl std::fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", describe_http_status(self.0, Locale::English))
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// Like `{err:#}`, but in the player's language for the errors the chatbox recognizes
+pub fn describe_error(err: &anyhow::Error, locale: Locale) -> String {
+    if err.is::<BalanceExhausted>() {
+        Msg::BalanceExhausted.text(locale).to_string()
+    } else if let Some(HttpStatusError(status)) = err.downcast_ref::<HttpStatusError>() {
+        describe_http_status(*status, locale)
+    } else {
+        format!("{err:#}")
+    }
+}
+
+/// The provider refused a request because the account ran out of credit. Unlike other errors,
+/// sending again won't help until it's topped up.
+#[derive(Debug)]
+pub struct BalanceExhausted;
+
+impl std::fmt::Display for BalanceExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", Msg::BalanceExhausted.text(Locale::English))
+    }
+}
+
+impl std::error::Error for BalanceExhausted {}
+
+/// Recognizes out-of-credit errors from their status or body. DeepSeek answers 402 with
+/// "Insufficient Balance", and OpenAI-compatible providers use the `insufficient_quota` code,
+/// sometimes with a 429 that otherwise means rate limiting.
+fn is_balance_exhausted(status: u16, body: &str) -> bool {
+    if status == 402 {
+        return true;
+    }
+    let body = body.to_lowercase();
+    [
+        "insufficient balance",
+        "insufficient_balance",
+        "insufficient_quota",
+        "exceeded your current quota",
+    ]
+    .into_iter()
+    .any(|marker| body.contains(marker))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::mpsc::Receiver;
+
+    use abstio::MapName;
+    use abstutil::prettyprint_usize;
+    use geom::Time;
+
+    use crate::sandbox::chat::persistence::transcript_markdown;
+    use crate::sandbox::chat::session::{
+        departures_by_hour, describe_speed, ChatSession, ParamChange, ScenarioParams, SimSnapshot,
+        SCENARIO_PARAMS_VERSION,
+    };
+    use crate::sandbox::SpeedSetting;
+
+    #[test]
+    fn test_reply_order() {
+        let mut order = ReplyOrder::default();
+        let first = order.issue();
+        let second = order.issue();
+        let third = order.issue();
+        // Replies that beat an earlier one wait for it
+        assert!(order.arrived(third, "third").is_empty());
+        assert!(order.arrived(second, "second").is_empty());
+        assert_eq!(
+            order.arrived(first, "first"),
+            vec!["first", "second", "third"]
+        );
+        // Late duplicates and made-up numbers are dropped
+        assert!(order.arrived(second, "second again").is_empty());
+        assert!(order.arrived(99, "never sent").is_empty());
+
+        // A cancelled request doesn't hold up the ones after it, and its reply is dropped
+        let cancelled = order.issue();
+        let next = order.issue();
+        assert!(order.arrived(next, "next").is_empty());
+        assert_eq!(order.cancel(cancelled), vec!["next"]);
+        assert!(order.arrived(cancelled, "too late").is_empty());
+
+        // Sent one at a time, nothing is held back
+        let last = order.issue();
+        assert_eq!(order.arrived(last, "last"), vec!["last"]);
+    }
+
+    #[test]
+    fn test_request_settings_change() {
+        let sent = RequestSettings {
+            context_messages: 8,
+            seed: None,
+            custom_prompt: String::new(),
+            scenario_params: None,
+            stop: Vec::new(),
+            max_tokens: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+        };
+        assert_eq!(sent.describe_change(&sent, Locale::English), None);
+
+        let now = RequestSettings {
+            context_messages: 20,
+            seed: None,
+            custom_prompt: String::new(),
+            scenario_params: None,
+            stop: Vec::new(),
+            max_tokens: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+        };
+        assert_eq!(
+            sent.describe_change(&now, Locale::English),
+            Some("sent with 8 context messages, not the current 20".to_string())
+        );
+
+        let now = RequestSettings {
+            context_messages: 20,
+            seed: Some(42),
+            custom_prompt: String::new(),
+            scenario_params: None,
+            stop: Vec::new(),
+            max_tokens: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+        };
+        assert_eq!(
+            sent.describe_change(&now, Locale::English),
+            Some(
+                "sent with 8 context messages, not the current 20; sent with random sampling, \
+                 not the current seed 42"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_stop_sequences() {
+        assert_eq!(
+            parse_stop_sequences(" ACTION: end ,,\n, ``` , ACTION: end"),
+            vec!["ACTION: end".to_string(), "```".to_string()]
+        );
+        assert!(parse_stop_sequences(" , ").is_empty());
+
+        let request = |stop| {
+            serde_json::to_value(DeepseekChatRequest {
+                model: "deepseek-chat".to_string(),
+                messages: Vec::new(),
+                temperature: TEMPERATURE,
+                seed: None,
+                stream: false,
+                stop,
+                max_tokens: None,
+                frequency_penalty: None,
+                presence_penalty: None,
+            })
+            .unwrap()
+        };
+        // Only sent when there's something to stop at
+        assert!(request(Vec::new()).get("stop").is_none());
+        assert_eq!(
+            request(vec!["```".to_string()])["stop"],
+            serde_json::json!(["```"])
+        );
+    }
+
+    #[test]
+    fn test_max_tokens() {
+        let request = |max_tokens| {
+            serde_json::to_value(DeepseekChatRequest {
+                model: "deepseek-chat".to_string(),
+                messages: Vec::new(),
+                temperature: TEMPERATURE,
+                seed: None,
+                stream: false,
+                stop: Vec::new(),
+                max_tokens,
+                frequency_penalty: None,
+                presence_penalty: None,
+            })
+            .unwrap()
+        };
+        // Left to the provider unless it's set
+        assert!(request(None).get("max_tokens").is_none());
+        assert_eq!(request(Some(512))["max_tokens"], serde_json::json!(512));
+    }
+
+    #[test]
+    fn test_penalties() {
+        let request = |frequency, presence| {
+            serde_json::to_value(DeepseekChatRequest {
+                model: "deepseek-chat".to_string(),
+                messages: Vec::new(),
+                temperature: TEMPERATURE,
+                seed: None,
+                stream: false,
+                stop: Vec::new(),
+                max_tokens: None,
+                frequency_penalty: penalty(frequency),
+                presence_penalty: penalty(presence),
+            })
+            .unwrap()
+        };
+        // At the default, neither is sent
+        let defaults = request(0.0, 0.0);
+        assert!(defaults.get("frequency_penalty").is_none());
+        assert!(defaults.get("presence_penalty").is_none());
+
+        let set = request(0.5, -1.0);
+        assert_eq!(set["frequency_penalty"], serde_json::json!(0.5));
+        assert_eq!(set["presence_penalty"], serde_json::json!(-1.0));
+        assert!(request(0.0, 0.5).get("frequency_penalty").is_none());
+
+        // Out of range values from the settings file are clamped
+        let clamped = request(7.0, -3.0);
+        assert_eq!(clamped["frequency_penalty"], serde_json::json!(2.0));
+        assert_eq!(clamped["presence_penalty"], serde_json::json!(-2.0));
+    }
+
+    #[test]
+    fn test_image_content() {
+        assert_eq!(
+            serde_json::to_value(MessageContent::with_image("hi".to_string(), None)).unwrap(),
+            serde_json::json!("hi")
+        );
+        assert_eq!(
+            serde_json::to_value(MessageContent::with_image(
+                "What's congested?".to_string(),
+                Some(&b"png"[..])
+            ))
+            .unwrap(),
+            serde_json::json!([
+                {"type": "text", "text": "What's congested?"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,cG5n"}}
+            ])
+        );
+    }
+
+    #[test]
+    fn test_context_window_start() {
+        let messages = vec![
+            (Role::User, "a".to_string()),
+            (Role::Assistant, "b".to_string()),
+            (Role::Thoughts, "c".to_string()),
+            (Role::User, "d".to_string()),
+        ];
+        assert_eq!(context_window_start(&messages, 0), 4);
+        assert_eq!(context_window_start(&messages, 1), 3);
+        // Thoughts don't take a slot
+        assert_eq!(context_window_start(&messages, 2), 1);
+        assert_eq!(context_window_start(&messages, 3), 0);
+        assert_eq!(context_window_start(&messages, 10), 0);
+    }
+
+    #[test]
+    fn test_context_reset() {
+        let context = ChatContext {
+            map: MapName::seattle("montlake"),
+            scenario: "weekday".to_string(),
+        };
+        let settings = RequestSettings {
+            context_messages: 8,
+            seed: None,
+            custom_prompt: String::new(),
+            scenario_params: None,
+            stop: Vec::new(),
+            max_tokens: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+        };
+        let messages = vec![
+            (Role::User, "a".to_string()),
+            (Role::Assistant, "b".to_string()),
+            (Role::ContextReset, String::new()),
+            (Role::User, "c".to_string()),
+            (Role::Assistant, "d".to_string()),
+        ];
+        // The window stops at the reset, however large it is
+        assert_eq!(context_window_start(&messages, 1), 4);
+        assert_eq!(context_window_start(&messages, 10), 3);
+
+        let mut history = messages.clone();
+        history.push((Role::User, "e".to_string()));
+        let sent: Vec<String> = request_messages(&context, history, None, &settings)
+            .into_iter()
+            .skip(1)
+            .map(|msg| match msg.content {
+                MessageContent::Text(text) => text,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(sent, vec!["c", "d", "e"]);
+
+        // It's still in the transcript
+        let markdown = transcript_markdown(&context, &messages, &VecDeque::new(), false);
+        assert!(markdown.contains("**LLM:** b\n\n_Context reset"));
+    }
+
+    #[test]
+    fn test_heartbeats_stop_after_result() {
+        let (tx, rx) = mpsc::channel();
+        run_with_heartbeats(tx, std::time::Duration::from_millis(10), || {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            Ok(LlmResponse {
+                choices: vec![LlmReply {
+                    content: "done".to_string(),
+                    reasoning: None,
+                }],
+                total_tokens: None,
+            })
+        });
+        let msgs: Vec<WorkerMsg> = rx.iter().collect();
+        let (last, heartbeats) = msgs.split_last().unwrap();
+        assert!(!heartbeats.is_empty());
+        assert!(heartbeats
+            .iter()
+            .all(|msg| matches!(msg, WorkerMsg::Heartbeat(_))));
+        assert!(matches!(last, WorkerMsg::Done(Ok(resp)) if resp.choices[0].content == "done"));
+
+        // A crashed request still reports a result
+        let (tx, rx) = mpsc::channel();
+        run_with_heartbeats(tx, std::time::Duration::from_millis(10), || panic!("oops"));
+        assert!(matches!(rx.iter().last(), Some(WorkerMsg::Done(Err(_)))));
+    }
+
+    /// Serves one canned HTTP response on a local port, returning the base URL to use.
+    fn mock_server(status: &'static str, body: &'static str) -> String {
+        mock_server_with_headers(status, body).0
+    }
+
+    /// Also hands back the request line and headers the server received.
+    fn mock_server_with_headers(
+        status: &'static str,
+        body: &'static str,
+    ) -> (String, mpsc::Receiver<Vec<String>>) {
+        use std::io::Write;
+
+        mock_server_writing(move |stream| {
+            let head = response_head(status, &format!("Content-Length: {}", body.len()));
+            write!(stream, "{head}{body}").unwrap();
+        })
+    }
+
+    /// The status line and headers of a response, up to the blank line before the body.
+    fn response_head(status: &str, header: &str) -> String {
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Type: application/json\r\n{header}\r\n\
+             Connection: close\r\n\r\n"
+        )
+    }
+
+    /// Like `mock_server_with_headers`, but `respond` writes the whole response itself.
+    fn mock_server_writing<F: FnOnce(&mut std::net::TcpStream) + Send + 'static>(
+        respond: F,
+    ) -> (String, mpsc::Receiver<Vec<String>>) {
+        use std::io::{BufRead, BufReader, Read};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            // Consume the whole request before responding
+            let mut content_length = 0;
+            let mut lines = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some((key, value)) = line.split_once(':') {
+                    if key.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+                lines.push(line.trim().to_string());
+            }
+            let _ = tx.send(lines);
+            let mut request_body = vec![0; content_length];
+            reader.read_exact(&mut request_body).unwrap();
+
+            respond(reader.get_mut());
+        });
+        (format!("http://{addr}/v1"), rx)
+    }
+
+    /// Just returns the content of each choice.
+    fn fetch_from_mock(status: &'static str, body: &'static str) -> Result<Vec<String>> {
+        fetch_replies_from_mock(status, body)
+            .map(|replies| replies.into_iter().map(|r| r.content).collect())
+    }
+
+    fn fetch_replies_from_mock(status: &'static str, body: &'static str) -> Result<Vec<LlmReply>> {
+        fetch_response_from_mock(status, body).map(|resp| resp.choices)
+    }
+
+    fn fetch_response_from_mock(status: &'static str, body: &'static str) -> Result<LlmResponse> {
+        fetch_response_from(mock_server(status, body))
+    }
+
+    fn fetch_response_from(base_url: String) -> Result<LlmResponse> {
+        let config = LlmConfig {
+            api_key: "test".to_string(),
+            base_url,
+            model: "deepseek-chat".to_string(),
+            auth: AuthScheme::Bearer,
+            api_version: None,
+            vision: false,
+        };
+        let context = ChatContext {
+            map: MapName::seattle("montlake"),
+            scenario: "weekday".to_string(),
+        };
+        fetch_deepseek_reply(
+            &config,
+            context,
+            vec![(Role::User, "hello".to_string())],
+            None,
+            RequestSettings {
+                context_messages: 8,
+                seed: None,
+                custom_prompt: String::new(),
+                scenario_params: None,
+                stop: Vec::new(),
+                max_tokens: None,
+                frequency_penalty: None,
+                presence_penalty: None,
+            },
+            None,
+        )
+    }
+
+    #[test]
+    fn test_fetch_reply() {
+        let reply = fetch_from_mock(
+            "200 OK",
+            r#"{"choices": [{"message": {"role": "assistant", "content": "ACTION: pause"}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(reply, vec!["ACTION: pause"]);
+    }
+
+    #[test]
+    fn test_fetch_multiple_choices() {
+        let reply = fetch_from_mock(
+            "200 OK",
+            r#"{"choices": [
+                {"message": {"content": "ACTION: pause"}},
+                {"message": {"content": "ACTION: speed up"}}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(reply, vec!["ACTION: pause", "ACTION: speed up"]);
+    }
+
+    #[test]
+    fn test_fetch_reasoning() {
+        let reply = fetch_replies_from_mock(
+            "200 OK",
+            r#"{"choices": [
+                {"message": {"content": "ACTION: pause", "reasoning_content": "Traffic is bad."}},
+                {"message": {"content": "ACTION: resume", "reasoning_content": "  "}}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            reply,
+            vec![
+                LlmReply {
+                    content: "ACTION: pause".to_string(),
+                    reasoning: Some("Traffic is bad.".to_string()),
+                },
+                // Blank reasoning is dropped
+                LlmReply {
+                    content: "ACTION: resume".to_string(),
+                    reasoning: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fetch_empty_choices() {
+        let reply = fetch_from_mock("200 OK", r#"{"choices": []}"#).unwrap();
+        assert_eq!(reply, vec!["(empty reply)"]);
+    }
+
+    #[test]
+    fn test_fetch_rate_limited() {
+        let err = fetch_from_mock(
+            "429 Too Many Requests",
+            r#"{"error": {"message": "slow down"}}"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().starts_with("Rate limited"), "{}", err);
+    }
+
+    #[test]
+    fn test_fetch_balance_exhausted() {
+        let err = fetch_from_mock(
+            "402 Payment Required",
+            r#"{"error": {"message": "Insufficient Balance", "type": "unknown_error",
+                "param": null, "code": "invalid_request_error"}}"#,
+        )
+        .unwrap_err();
+        assert!(err.is::<BalanceExhausted>(), "{}", err);
+        assert!(err.to_string().contains("top_up"), "{}", err);
+
+        // A quota error looks like rate limiting, except for the body
+        let err = fetch_from_mock(
+            "429 Too Many Requests",
+            r#"{"error": {"message": "You exceeded your current quota, please check your plan and
+                billing details.", "type": "insufficient_quota", "code": "insufficient_quota"}}"#,
+        )
+        .unwrap_err();
+        assert!(err.is::<BalanceExhausted>(), "{}", err);
+
+        // Anything unrecognized falls back to describing the status
+        let err =
+            fetch_from_mock("403 Forbidden", r#"{"error": {"message": "nope"}}"#).unwrap_err();
+        assert!(!err.is::<BalanceExhausted>());
+        assert!(err.to_string().starts_with("Access denied"), "{}", err);
+        // The chatbox shows recognized errors in the player's language
+        assert_eq!(
+            describe_error(&err, Locale::Chinese),
+            "访问被拒绝。API 密钥可能缺少权限或额度 (HTTP 403 Forbidden)"
+        );
+    }
+
+    #[test]
+    fn test_fetch_usage() {
+        let resp = fetch_response_from_mock(
+            "200 OK",
+            r#"{"choices": [{"message": {"role": "assistant", "content": "hi"}}],
+                "usage": {"prompt_tokens": 3000, "completion_tokens": 412, "total_tokens": 3412}}"#,
+        )
+        .unwrap();
+        assert_eq!(resp.total_tokens, Some(3412));
+        assert_eq!(
+            Locale::English.status_line("deepseek-chat", TEMPERATURE, None, 3412),
+            "deepseek-chat · temp 0.2 · 3,412 tok"
+        );
+        assert_eq!(
+            Locale::English.status_line("deepseek-chat", TEMPERATURE, Some(1024), 3412),
+            "deepseek-chat · temp 0.2 · max 1,024 per reply · 3,412 tok"
+        );
+
+        let resp = fetch_response_from_mock(
+            "200 OK",
+            r#"{"choices": [{"message": {"role": "assistant", "content": "hi"}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(resp.total_tokens, None);
+    }
+
+    #[test]
+    fn test_cancel_stream() {
+        let body = "data: {\"choices\": [{\"delta\": {\"content\": \"Slowing\"}}]}\n\n\
+                    : keep-alive\n\n\
+                    data: {\"choices\": [{\"delta\": {\"content\": \" down.\\nACTION: \"}}]}\n\n\
+                    data: {\"choices\": [{\"delta\": {\"content\": \"slower\"}}]}\n\n\
+                    data: [DONE]\n\n";
+
+        // Read the whole stream
+        let chunks = std::cell::RefCell::new(Vec::new());
+        let resp = read_stream(body.as_bytes(), &|text| {
+            chunks.borrow_mut().push(text.to_string());
+            true
+        })
+        .unwrap();
+        assert_eq!(resp.choices[0].content, "Slowing down.\nACTION: slower");
+        assert_eq!(chunks.borrow().len(), 3);
+
+        // Cancel after two chunks, in the middle of a command
+        let partial = std::cell::RefCell::new(String::new());
+        let res = read_stream(body.as_bytes(), &|text| {
+            partial.borrow_mut().push_str(text);
+            !partial.borrow().contains("ACTION")
+        });
+        assert!(res.is_err());
+
+        let mut messages = vec![(Role::User, "slow down".to_string())];
+        finish_cancelled(&mut messages, Some(partial.into_inner()), Locale::English);
+        assert_eq!(
+            messages,
+            vec![
+                (Role::User, "slow down".to_string()),
+                (
+                    Role::Assistant,
+                    "Slowing down.\nACTION: [cancelled]".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fetch_malformed_json() {
+        assert!(fetch_from_mock("200 OK", r#"{"choices": [{"#).is_err());
+        let err = fetch_from_mock("200 OK", r#"{"choices": [{"#).unwrap_err();
+        assert!(format!("{err}").contains("cut off after 14 bytes"));
+        let err = fetch_from_mock("200 OK", "<html>Bad gateway</html>").unwrap_err();
+        assert!(format!("{err}").contains("wasn't valid JSON"));
+    }
+
+    #[test]
+    fn test_fetch_chunked_reply() {
+        use std::io::Write;
+
+        // A non-streamed reply, split mid-JSON across chunks that arrive separately
+        let (base_url, _) = mock_server_writing(|stream| {
+            let head = response_head("200 OK", "Transfer-Encoding: chunked");
+            write!(stream, "{head}").unwrap();
+            for piece in [
+                r#"{"choices": [{"mess"#,
+                r#"age": {"content": "Paus"#,
+                r#"ed."}}]}"#,
+            ] {
+                write!(stream, "{:x}\r\n{piece}\r\n", piece.len()).unwrap();
+                stream.flush().unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(20));
+            }
+            write!(stream, "0\r\n\r\n").unwrap();
+        });
+        let resp = fetch_response_from(base_url).unwrap();
+        assert_eq!(resp.choices[0].content, "Paused.");
+
+        // The connection closes before all the promised bytes arrive
+        let (base_url, _) = mock_server_writing(|stream| {
+            let head = response_head("200 OK", "Content-Length: 100");
+            write!(stream, "{head}{{\"choices\": [").unwrap();
+        });
+        let err = fetch_response_from(base_url).unwrap_err();
+        assert!(format!("{err}").contains("cut off"));
+    }
+
+    #[test]
+    fn test_fetch_gzip_reply() {
+        use std::io::Write;
+
+        // {"choices": [{"message": {"content": "Paused."}}]}, gzipped
+        const BODY: [u8; 73] = [
+            31, 139, 8, 0, 0, 0, 0, 0, 2, 255, 1, 50, 0, 205, 255, 123, 34, 99, 104, 111, 105, 99,
+            101, 115, 34, 58, 32, 91, 123, 34, 109, 101, 115, 115, 97, 103, 101, 34, 58, 32, 123,
+            34, 99, 111, 110, 116, 101, 110, 116, 34, 58, 32, 34, 80, 97, 117, 115, 101, 100, 46,
+            34, 125, 125, 93, 125, 68, 138, 62, 65, 50, 0, 0, 0,
+        ];
+        let (base_url, rx) = mock_server_writing(|stream| {
+            let header = format!("Content-Encoding: gzip\r\nContent-Length: {}", BODY.len());
+            let head = response_head("200 OK", &header);
+            write!(stream, "{head}").unwrap();
+            stream.write_all(&BODY).unwrap();
+        });
+        let resp = fetch_response_from(base_url).unwrap();
+        assert_eq!(resp.choices[0].content, "Paused.");
+        // Servers only compress when the request says gzip is accepted
+        let headers = rx.recv().unwrap();
+        assert!(headers
+            .iter()
+            .any(|line| line.to_lowercase() == "accept-encoding: gzip"));
+    }
+
+    #[test]
+    fn test_check_connection() {
+        for (status, expected) in [
+            ("200 OK", "Ok"),
+            ("401 Unauthorized", "Failed"),
+            ("404 Not Found", "Unverified"),
+            ("503 Service Unavailable", "Failed"),
+        ] {
+            let config = LlmConfig {
+                api_key: "test".to_string(),
+                base_url: mock_server(status, r#"{"data": []}"#),
+                model: "deepseek-chat".to_string(),
+                auth: AuthScheme::Bearer,
+                api_version: None,
+                vision: false,
+            };
+            let result = check_connection(&config, Locale::English);
+            assert!(
+                format!("{result:?}").starts_with(expected),
+                "{status}: {result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_attach_sim_states() {
+        let history = vec![
+            (Role::SimState, "Sim time 7AM, paused.".to_string()),
+            (Role::User, "hi".to_string()),
+            (Role::Assistant, "hello".to_string()),
+            (Role::Thoughts, "hmm".to_string()),
+            (Role::User, "and now?".to_string()),
+        ];
+        assert_eq!(
+            attach_sim_states(history),
+            vec![
+                (
+                    Role::User,
+                    "hi\n\n[Simulation state when sent: Sim time 7AM, paused.]".to_string()
+                ),
+                (Role::Assistant, "hello".to_string()),
+                (Role::User, "and now?".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_system_parts() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        /// Answers every request with "ok", keeping what was sent as (role, content) pairs
+        struct CapturingBackend(Rc<RefCell<Vec<Vec<(String, String)>>>>);
+
+        impl LlmBackend for CapturingBackend {
+            fn fetch(
+                &mut self,
+                context: ChatContext,
+                history: Vec<(Role, String)>,
+                image: Option<Vec<u8>>,
+                settings: RequestSettings,
+                _: Option<&dyn Fn(&str) -> bool>,
+            ) -> Result<LlmResponse> {
+                let sent = request_messages(&context, history, image, &settings)
+                    .into_iter()
+                    .map(|msg| match msg.content {
+                        MessageContent::Text(text) => (msg.role, text),
+                        _ => unreachable!(),
+                    })
+                    .collect();
+                self.0.borrow_mut().push(sent);
+                Ok(LlmResponse {
+                    choices: vec![LlmReply {
+                        content: "ok".to_string(),
+                        reasoning: None,
+                    }],
+                    total_tokens: None,
+                })
+            }
+        }
+
+        let context = ChatContext {
+            map: MapName::seattle("montlake"),
+            scenario: "weekday".to_string(),
+        };
+        let requests = Rc::new(RefCell::new(Vec::new()));
+        let mut session = ChatSession::with_backend(
+            context.clone(),
+            Some(Box::new(CapturingBackend(requests.clone()))),
+        );
+        session.settings.custom_prompt = "  Focus on bus delays.\n".to_string();
+        let snapshot = |hour: usize| SimSnapshot {
+            time: Time::START_OF_DAY + Duration::hours(hour),
+            speed: None,
+            finished_trips: 0,
+            unfinished_trips: 0,
+        };
+        // Sent the way the chatbox and scripts send, so the history passed along already ends with
+        // the message being sent
+        session.set_sim_snapshot(snapshot(7));
+        session.send("hi").unwrap();
+        session.set_sim_snapshot(snapshot(8));
+        session.send("and now?").unwrap();
+
+        let requests = requests.borrow();
+        let sent: Vec<(&str, &str)> = requests[1]
+            .iter()
+            .map(|(role, content)| (role.as_str(), content.as_str()))
+            .collect();
+        // The custom prompt, the built-in instructions, then the live state, all separate
+        assert_eq!(sent[0], ("system", "Focus on bus delays."));
+        assert_eq!(sent[1], ("system", system_prompt(&context).as_str()));
+        assert_eq!(sent[2].0, "system");
+        assert!(sent[2]
+            .1
+            .contains(&format!("---\n{}\n---", snapshot(8).describe())));
+        // Older states stay with their message, and the new message is sent exactly once, last
+        assert_eq!(
+            &sent[3..],
+            &[
+                (
+                    "user",
+                    format!(
+                        "hi\n\n[Simulation state when sent: {}]",
+                        snapshot(7).describe()
+                    )
+                    .as_str()
+                ),
+                ("assistant", "ok"),
+                ("user", "and now?"),
+            ]
+        );
+        assert_eq!(
+            sent.iter()
+                .filter(|(_, content)| *content == "and now?")
+                .count(),
+            1
+        );
+
+        // The first request had the same three system parts, and its message only once too
+        assert_eq!(
+            requests[0]
+                .iter()
+                .map(|(role, _)| role.as_str())
+                .collect::<Vec<_>>(),
+            vec!["system", "system", "system", "user"]
+        );
+        assert_eq!(requests[0][3].1, "hi");
+
+        // Without a custom prompt or sim state, only the built-in instructions are sent
+        let settings = RequestSettings {
+            custom_prompt: String::new(),
+            ..session.settings.clone()
+        };
+        let history = vec![(Role::User, "hi".to_string())];
+        let messages = request_messages(&context, history, None, &settings);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].role, "system");
+    }
+
+    #[test]
+    fn test_param_diffs() {
+        let change = ParamChange {
+            name: Msg::QuotaParam,
+            before: prettyprint_usize(3000),
+            after: prettyprint_usize(5000),
+        };
+        assert_eq!(change.describe(Locale::English), "quota: 3,000 → 5,000");
+        assert_eq!(
+            format!(
+                "speed: {} → {}",
+                describe_speed(None, Locale::English),
+                describe_speed(Some(SpeedSetting::Fast), Locale::English)
+            ),
+            "speed: paused → 5x"
+        );
+
+        // Diffs go back to the LLM along with the other results of its last reply
+        let history = vec![
+            (Role::Assistant, "ACTION: set_quota 5000".to_string()),
+            (
+                Role::CommandResult,
+                "ACTION: set_quota 5000 → Ride-hailing quota recorded as 5,000 vehicles."
+                    .to_string(),
+            ),
+            (Role::ParamDiff, change.describe(Locale::English)),
+        ];
+        let (history, results) = take_command_results(history);
+        assert_eq!(history.len(), 1);
+        assert_eq!(results[1], "quota: 3,000 → 5,000");
+    }
+
+    #[test]
+    fn test_command_results() {
+        let context = ChatContext {
+            map: MapName::seattle("montlake"),
+            scenario: "weekday".to_string(),
+        };
+        let settings = RequestSettings {
+            context_messages: 8,
+            seed: None,
+            custom_prompt: String::new(),
+            scenario_params: None,
+            stop: Vec::new(),
+            max_tokens: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+        };
+        let history = vec![
+            (Role::User, "step ahead".to_string()),
+            (Role::Assistant, "ACTION: step 5min".to_string()),
+            (
+                Role::CommandResult,
+                "ACTION: step 5min → Stepped forward 5m and paused at 7:05 AM.".to_string(),
+            ),
+            (Role::User, "again".to_string()),
+            (Role::Assistant, "ACTION: set_quota 99999999".to_string()),
+            (
+                Role::CommandResult,
+                "ACTION: set_quota 99999999 → Ignored ride-hailing quota.".to_string(),
+            ),
+            (Role::SimState, "Sim time 7:05AM, paused.".to_string()),
+            (Role::User, "why?".to_string()),
+        ];
+        let contents = |settings: &RequestSettings| -> Vec<(String, String)> {
+            request_messages(&context, history.clone(), None, settings)
+                .into_iter()
+                .map(|msg| match msg.content {
+                    MessageContent::Text(text) => (msg.role, text),
+                    _ => unreachable!(),
+                })
+                .collect()
+        };
+
+        // The latest result gets its own system message, before the live state
+        let messages = contents(&settings);
+        assert_eq!(
+            messages[1],
+            (
+                "system".to_string(),
+                "Results of the actions in your last reply:\n\
+                 ACTION: set_quota 99999999 → Ignored ride-hailing quota."
+                    .to_string()
+            )
+        );
+        assert!(messages[2].1.contains("Sim time 7:05AM"));
+        // An older result stays in the history, in order
+        assert_eq!(
+            messages[5],
+            (
+                "system".to_string(),
+                "ACTION: step 5min → Stepped forward 5m and paused at 7:05 AM.".to_string()
+            )
+        );
+
+        // Even without room for any history, the latest result is sent
+        let messages = contents(&RequestSettings {
+            context_messages: 0,
+            ..settings
+        });
+        assert_eq!(messages.len(), 4);
+        assert!(messages[1].1.contains("Ignored ride-hailing quota."));
+    }
+
+    #[test]
+    fn test_scenario_params() {
+        let departures = vec![
+            Time::START_OF_DAY,
+            Time::START_OF_DAY + Duration::minutes(59),
+            Time::START_OF_DAY + Duration::hours(7),
+            Time::START_OF_DAY + Duration::hours(30),
+        ];
+        let params = ScenarioParams {
+            version: SCENARIO_PARAMS_VERSION,
+            map: MapName::seattle("montlake").as_filename(),
+            scenario: "weekday".to_string(),
+            ride_hail_quota: Some(2_500),
+            quota_range: (1_000, 10_000),
+            trips: departures.len(),
+            departures_by_hour: departures_by_hour(departures),
+        };
+        assert_eq!(
+            params.to_block(),
+            "Scenario parameters (JSON, schema version 1):\n\
+             {\"version\":1,\"map\":\"us_seattle_montlake\",\"scenario\":\"weekday\",\
+             \"ride_hail_quota\":2500,\"quota_range\":[1000,10000],\"trips\":4,\
+             \"departures_by_hour\":[2,0,0,0,0,0,0,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,1]}"
+        );
+
+        // Sent after the built-in instructions and before the live state
+        let context = ChatContext {
+            map: MapName::seattle("montlake"),
+            scenario: "weekday".to_string(),
+        };
+        let settings = RequestSettings {
+            context_messages: 8,
+            seed: None,
+            custom_prompt: String::new(),
+            scenario_params: Some(params.to_block()),
+            stop: Vec::new(),
+            max_tokens: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+        };
+        let history = vec![
+            (Role::SimState, "Sim time 7AM, paused.".to_string()),
+            (Role::User, "hi".to_string()),
+        ];
+        let messages = request_messages(&context, history, None, &settings);
+        let contents: Vec<String> = messages
+            .iter()
+            .map(|msg| match msg.content {
+                MessageContent::Text(ref text) => text.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(contents[0], system_prompt(&context));
+        assert_eq!(contents[1], params.to_block());
+        assert!(contents[2].contains("Sim time 7AM, paused."));
+    }
+
+    #[test]
+    fn test_replay_backend() {
+        let exchange = |user: &str, assistant: &str| ReplayExchange {
+            user: user.to_string(),
+            assistant: assistant.to_string(),
+        };
+        let mut replay = ReplayBackend::new(vec![
+            exchange("hello", "Hi!"),
+            exchange("Slow down", "Slowing down.\nslower"),
+            exchange("hello", "Hello again."),
+        ]);
+        // Matching the content skips ahead
+        assert_eq!(
+            replay.reply("  slow DOWN ").unwrap(),
+            "Slowing down.\nslower"
+        );
+        assert_eq!(replay.reply("hello").unwrap(), "Hi!");
+        // Otherwise the next unused reply answers
+        assert_eq!(replay.reply("something else").unwrap(), "Hello again.");
+        assert!(replay.reply("hello").is_err());
+    }
+
+    #[test]
+    fn test_base_url_with_path() {
+        for base_url in [
+            "https://api.deepseek.com/v1",
+            "https://api.deepseek.com/v1/",
+            "https://api.deepseek.com/v1/chat/completions",
+            "https://api.deepseek.com/v1/chat/completions/",
+        ] {
+            let config = LlmConfig {
+                api_key: "test".to_string(),
+                base_url: base_url.to_string(),
+                model: "deepseek-chat".to_string(),
+                auth: AuthScheme::Bearer,
+                api_version: None,
+                vision: false,
+            };
+            assert_eq!(
+                config.url("chat/completions"),
+                "https://api.deepseek.com/v1/chat/completions",
+                "{base_url}"
+            );
+            assert_eq!(
+                config.url("models"),
+                "https://api.deepseek.com/v1/models",
+                "{base_url}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_auth_schemes() {
+        let parse = |scheme: Option<&str>, header: Option<&str>| {
+            AuthScheme::parse(scheme.map(String::from), header.map(String::from))
+        };
+        assert_eq!(parse(None, None).unwrap(), AuthScheme::Bearer);
+        assert_eq!(parse(Some("Bearer"), None).unwrap(), AuthScheme::Bearer);
+        assert_eq!(
+            parse(Some("header"), None).unwrap(),
+            AuthScheme::Header("api-key".to_string())
+        );
+        assert_eq!(
+            parse(None, Some("x-api-key")).unwrap(),
+            AuthScheme::Header("x-api-key".to_string())
+        );
+        assert!(parse(Some("bearer"), Some("x-api-key")).is_err());
+        assert!(parse(Some("basic"), None).is_err());
+
+        for (auth, api_version, expected_header, expected_request_line) in [
+            (
+                AuthScheme::Bearer,
+                None,
+                "authorization: Bearer secret",
+                "POST /v1/chat/completions HTTP/1.1",
+            ),
+            (
+                AuthScheme::Header("api-key".to_string()),
+                Some("2024-02-01".to_string()),
+                "api-key: secret",
+                "POST /v1/chat/completions?api-version=2024-02-01 HTTP/1.1",
+            ),
+            (
+                AuthScheme::Header("x-api-key".to_string()),
+                None,
+                "x-api-key: secret",
+                "POST /v1/chat/completions HTTP/1.1",
+            ),
+        ] {
+            let (base_url, rx) = mock_server_with_headers(
+                "200 OK",
+                r#"{"choices": [{"message": {"role": "assistant", "content": "ok"}}]}"#,
+            );
+            let config = LlmConfig {
+                api_key: "secret".to_string(),
+                base_url,
+                model: "deepseek-chat".to_string(),
+                auth,
+                api_version,
+                vision: false,
+            };
+            let context = ChatContext {
+                map: MapName::seattle("montlake"),
+                scenario: "weekday".to_string(),
+            };
+            fetch_deepseek_reply(
+                &config,
+                context,
+                vec![(Role::User, "hello".to_string())],
+                None,
+                RequestSettings {
+                    context_messages: 8,
+                    seed: None,
+                    custom_prompt: String::new(),
+                    scenario_params: None,
+                    stop: Vec::new(),
+                    max_tokens: None,
+                    frequency_penalty: None,
+                    presence_penalty: None,
+                },
+                None,
+            )
+            .unwrap();
+
+            let lines = rx.recv().unwrap();
+            assert_eq!(lines[0], expected_request_line);
+            assert!(
+                lines
+                    .iter()
+                    .any(|l| l.eq_ignore_ascii_case(expected_header)),
+                "{expected_header} missing from {lines:?}"
+            );
+            // Only one way of authenticating should be used
+            let auth_headers = lines.iter().filter(|l| l.contains("secret")).count();
+            assert_eq!(auth_headers, 1);
+        }
+    }
+}
@@ -0,0 +1,4165 @@
+//! The chatbox panel itself: layout, input handling, and drawing the transcript.
+
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::time::Instant;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use abstutil::prettyprint_usize;
+use geom::{Circle, Distance, Duration, Pt2D, Time};
+use widgetry::{
+    lctrl, Choice, EventCtx, GeomBatch, GfxCtx, HorizontalAlignment, Key, Line, MultiKey,
+    MultilineTextBox, Outcome, Panel, RoundedF64, ScreenDims, Spinner, Text, TextSpan, Toggle,
+    UpdateType, VerticalAlignment, Widget,
+};
+
+use crate::app::App;
+use crate::sandbox::chat::persistence::{
+    continue_by_default, describe_age, greet, is_greeting, template_name, transcript_markdown,
+    ChatSettings, PromptTemplates, ResumeChoice, SavedConversation, SavedDraft, TranscriptArchive,
+    DEFAULT_PROMPT,
+};
+use crate::sandbox::chat::session::{
+    command_from_phrase, describe_speed, dry_run_messages, explain_prompt, malformed_commands,
+    next_batch, parse_commands, AppliedCommand, AutoPause, ChatCommand, ChatSession, CommandQueue,
+    ParamChange, ScenarioParams, SimSnapshot, SourcedCommand, ACTION_EXAMPLES,
+};
+use crate::sandbox::chat::transport::{
+    check_connection, context_window_start, describe_error, finish_cancelled, parse_stop_sequences,
+    penalty, run_with_heartbeats, system_prompt, BalanceExhausted, ConnectionStatus,
+    InflightRequest, LlmBackend, LlmConfig, LlmReply, LlmResponse, ReplayBackend, ReplyOrder,
+    RequestSettings, WorkerMsg, MAX_PENALTY, TEMPERATURE,
+};
+use crate::sandbox::chat::{last_message, normalize_message, ChatContext, Role};
+use crate::sandbox::chat_i18n::{Locale, Msg};
+use crate::sandbox::SpeedSetting;
+
+/// How many archived messages to bring back at a time
+const LOAD_EARLIER_BATCH: usize = 50;
+
+const HIGH_CONTRAST_FONT_SIZE: usize = 26;
+
+/// The player is only asked about a saved conversation once per run of the game. After that, the
+/// saved file already reflects what they chose.
+static ASKED_ABOUT_SAVED: AtomicBool = AtomicBool::new(false);
+
+/// How long an unsaved draft can wait before it's written to disk
+const DRAFT_SAVE_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// The LLM reacts to at most one simulation event this often, so events can't drive a runaway loop
+/// of paid requests
+const AUTO_RESPOND_COOLDOWN: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How often the worker reports that a request is still in flight
+const HEARTBEAT_PERIOD: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// The most recent messages that can be sent with each request
+const MAX_CONTEXT_MESSAGES: usize = 50;
+
+/// The highest cap on reply length the settings offer. DeepSeek's chat model allows 8K.
+const MAX_REPLY_TOKENS: usize = 8192;
+
+/// Upper bounds for the automatic report settings
+const MAX_AUTO_REPORT_MINUTES: usize = 240;
+
+const MAX_AUTO_REPORTS: usize = 50;
+
+/// Stepping runs synchronously, so don't let the LLM freeze the UI for too long
+const MAX_STEP: Duration = Duration::const_seconds(6.0 * 3600.0);
+
+const PANEL_PADDING: f64 = 8.0;
+
+const INPUT_MARGIN: usize = 6;
+
+/// Room to leave for the Send button, which is sized by its label
+const SEND_BUTTON_WIDTH: f64 = 70.0;
+
+const MIN_INPUT_WIDTH: f64 = 120.0;
+
+/// Between the controls in the header
+const HEADER_SPACING: f64 = 10.0;
+
+/// Room to leave beside a reply for the button that picks it, like `SEND_BUTTON_WIDTH`
+const OPTION_BUTTON_WIDTH: f64 = 90.0;
+
+const MIN_INPUT_HEIGHT: f64 = 30.0;
+
+/// The input box grows with its text, from this many lines up to the height `InputLayout` allows
+const MIN_INPUT_LINES: usize = 3;
+
+/// Sizes the input box to fit inside the panel's content area. On small windows, the input shrinks
+/// rather than overflowing, and the Send button moves below it.
+///
+/// Like everything in widgetry, this works in logical pixels. `get_window_dims` already divides
+/// out the scale factor, so the pixel constants above stay in proportion to text on any monitor.
+/// When the window size or scale factor changes, widgetry reports a window resize, and the
+/// chatbox rebuilds its panel to recompute this.
+pub struct InputLayout {
+    input_dims: ScreenDims,
+    stacked: bool,
+}
+
+/// The width inside the panel's padding, which text and the input can fill
+fn content_width(window: ScreenDims, width_pct: usize) -> f64 {
+    (width_pct as f64 / 100.0) * window.width - 2.0 * PANEL_PADDING
+}
+
+/// Splits items of these widths into rows no wider than `available`, keeping their order, and
+/// returns how many go in each row. An item too wide for any row gets one to itself.
+fn pack_rows(widths: &[f64], spacing: f64, available: f64) -> Vec<usize> {
+    let mut rows = Vec::new();
+    let mut len = 0;
+    let mut used = 0.0;
+    for width in widths {
+        if len > 0 && used + spacing + width > available {
+            rows.push(len);
+            len = 0;
+        }
+        used = if len == 0 {
+            *width
+        } else {
+            used + spacing + width
+        };
+        len += 1;
+    }
+    if len > 0 {
+        rows.push(len);
+    }
+    rows
+}
+
+impl InputLayout {
+    pub fn new(window: ScreenDims, width_pct: usize, height_pct: usize) -> InputLayout {
+        let content_w = content_width(window, width_pct);
+        let content_h = (height_pct as f64 / 100.0) * window.height - 2.0 * PANEL_PADDING;
+
+        let beside_send = content_w - INPUT_MARGIN as f64 - SEND_BUTTON_WIDTH;
+        let stacked = beside_send < MIN_INPUT_WIDTH;
+        let width = if stacked {
+            content_w
+        } else {
+            (content_w * 0.65).max(220.0).min(beside_send)
+        };
+        // The panel scrolls vertically, but the input alone should never be taller than what's
+        // visible
+        let height = (content_h * 0.30)
+            .max(90.0)
+            .min(content_h)
+            .max(MIN_INPUT_HEIGHT);
+
+        InputLayout {
+            input_dims: ScreenDims::new(width.max(1.0), height),
+            stacked,
+        }
+    }
+}
+
+/// What to do with a new message when the queue of unsent messages is full
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum QueueFullPolicy {
+    /// Refuse the new message
+    Reject,
+    /// Forget the oldest unsent message to make room
+    DropOldest,
+}
+
+/// What to do when a new message is sent while actions from an earlier reply are still queued.
+/// They were meant for the conversation as it was, so they might not fit anymore.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum QueuedActionsPolicy {
+    /// Send without mentioning them
+    Ignore,
+    /// Send, with a note in the transcript that they're still queued
+    Notice,
+    /// Hold the message until the player runs or discards them, or sends anyway
+    Ask,
+}
+
+/// Where the chatbox is pinned, to keep it clear of whichever sim controls are in the way
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum ChatPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl ChatPosition {
+    pub const ALL: [ChatPosition; 4] = [
+        ChatPosition::TopLeft,
+        ChatPosition::TopRight,
+        ChatPosition::BottomLeft,
+        ChatPosition::BottomRight,
+    ];
+
+    fn label(self) -> Msg {
+        match self {
+            ChatPosition::TopLeft => Msg::TopLeft,
+            ChatPosition::TopRight => Msg::TopRight,
+            ChatPosition::BottomLeft => Msg::BottomLeft,
+            ChatPosition::BottomRight => Msg::BottomRight,
+        }
+    }
+
+    /// The panel's top-left corner, as fractions of the window. Left and right keep the same
+    /// margin from the edge. The bottom presets sit where the chatbox always used to, unless the
+    /// panel is too tall for that.
+    fn corner(self, width_pct: usize, height_pct: usize) -> (f64, f64) {
+        let (width, height) = (width_pct as f64 / 100.0, height_pct as f64 / 100.0);
+        let x = match self {
+            ChatPosition::TopLeft | ChatPosition::BottomLeft => 0.02,
+            ChatPosition::TopRight | ChatPosition::BottomRight => 0.98 - width,
+        };
+        let y = match self {
+            ChatPosition::TopLeft | ChatPosition::TopRight => 0.02,
+            ChatPosition::BottomLeft | ChatPosition::BottomRight => (1.0 - height).min(0.65),
+        };
+        (x, y)
+    }
+}
+
+/// How the transcript shows the lines of a reply that the chatbox recognizes as actions, like
+/// `ACTION: pause`. Only the display changes; the stored reply keeps them.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum ActionLines {
+    /// As the LLM wrote them
+    Show,
+    /// As a dimmed `[action: pause]`
+    Chip,
+    Hide,
+}
+
+impl ActionLines {
+    pub const ALL: [ActionLines; 3] = [ActionLines::Show, ActionLines::Chip, ActionLines::Hide];
+
+    fn label(self) -> Msg {
+        match self {
+            ActionLines::Show => Msg::ShowActionLines,
+            ActionLines::Chip => Msg::ActionChips,
+            ActionLines::Hide => Msg::HideActionLines,
+        }
+    }
+}
+
+/// Which key combination sends the message in the input box
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SendKey {
+    /// Enter sends, and Shift+Enter inserts a newline
+    Enter,
+    /// Ctrl+Enter sends, and Enter inserts a newline
+    CtrlEnter,
+}
+
+/// The shortcut for sending the last message again
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ResendKey {
+    CtrlR,
+    F5,
+    Off,
+}
+
+impl ResendKey {
+    fn hotkey(self) -> Option<MultiKey> {
+        match self {
+            ResendKey::CtrlR => Some(lctrl(Key::R)),
+            ResendKey::F5 => Some(Key::F5.into()),
+            ResendKey::Off => None,
+        }
+    }
+}
+
+/// What clicking the Send button does right now. The label comes from this alone, worked out from
+/// state that's already been updated for the frame, so it can't disagree with what a click does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SendButton {
+    Send,
+    /// A reply is pending, so a new message waits its turn behind this many others
+    Queue(usize),
+    /// A reply is pending and there's nothing to queue, so clicking stops the request
+    Cancel,
+    /// Out of credit with the provider
+    Disabled,
+}
+
+impl SendButton {
+    pub fn new(
+        out_of_credits: bool,
+        waiting: bool,
+        choosing: bool,
+        queued: usize,
+        has_input: bool,
+    ) -> SendButton {
+        if out_of_credits {
+            SendButton::Disabled
+        } else if !waiting && !choosing {
+            SendButton::Send
+        } else if waiting && queued == 0 && !has_input {
+            SendButton::Cancel
+        } else {
+            SendButton::Queue(queued)
+        }
+    }
+
+    fn label(self, locale: Locale) -> String {
+        match self {
+            SendButton::Send | SendButton::Disabled => Msg::Send.text(locale).to_string(),
+            SendButton::Queue(0) => Msg::Queue.text(locale).to_string(),
+            SendButton::Queue(n) => format!("{}: {n}", Msg::Queued.text(locale)),
+            SendButton::Cancel => Msg::Cancel.text(locale).to_string(),
+        }
+    }
+}
+
+/// Keyboard shortcuts for the chatbox's controls. They all use Ctrl, so they work while typing, and
+/// none of them are the input box's editing keys or the keys for sending, resending, and scrolling.
+const SHORTCUTS: [(&str, Key); 8] = [
+    ("smaller", Key::Minus),
+    ("larger", Key::Equals),
+    ("high contrast", Key::H),
+    ("templates", Key::T),
+    ("attach map view", Key::I),
+    ("toggle seed", Key::G),
+    ("copy as markdown", Key::M),
+    ("focus input", Key::L),
+];
+
+/// The shortcut for a control, named like its widget
+fn shortcut(action: &str) -> MultiKey {
+    let (_, key) = SHORTCUTS
+        .iter()
+        .find(|(name, _)| *name == action)
+        .expect("every control with a shortcut is in SHORTCUTS");
+    lctrl(*key)
+}
+
+#[derive(Debug, PartialEq)]
+enum EnterAction {
+    Send,
+    Newline,
+}
+
+impl SendKey {
+    fn on_enter(self, ctrl: bool, shift: bool) -> EnterAction {
+        let send = match self {
+            SendKey::Enter => !shift,
+            SendKey::CtrlEnter => ctrl,
+        };
+        if send {
+            EnterAction::Send
+        } else {
+            EnterAction::Newline
+        }
+    }
+
+    fn hotkey(self) -> MultiKey {
+        match self {
+            SendKey::Enter => Key::Enter.into(),
+            SendKey::CtrlEnter => lctrl(Key::Enter),
+        }
+    }
+
+    fn hint(self) -> Msg {
+        match self {
+            SendKey::Enter => Msg::HintEnter,
+            SendKey::CtrlEnter => Msg::HintCtrlEnter,
+        }
+    }
+
+    /// Shown in the empty input box, to teach the key where it's used
+    fn placeholder(self) -> Msg {
+        match self {
+            SendKey::Enter => Msg::PlaceholderEnter,
+            SendKey::CtrlEnter => Msg::PlaceholderCtrlEnter,
+        }
+    }
+}
+
+pub struct Chatbox {
+    panel: Panel,
+    pub settings: ChatSettings,
+    templates: PromptTemplates,
+    show_templates: bool,
+    /// The conversation itself, and the actions queued from its replies
+    pub session: ChatSession,
+    /// How many messages before the session's are in the on-disk archive
+    archived: usize,
+    pub seed: Option<u64>,
+    locale: Locale,
+    input_prefill: String,
+    /// A capture of the map view, to send with the next message
+    attachment: Option<String>,
+    /// A long message is waiting for the player to confirm sending it
+    confirming_send: bool,
+    /// A message is waiting for the player to decide what happens to the queued actions
+    confirming_queued_actions: bool,
+    /// When the input first changed without being written to the draft file
+    unsaved_draft_since: Option<Instant>,
+    pending_rx: Option<Receiver<WorkerMsg>>,
+    /// What's arrived so far of a streamed reply. It's only drawn, never in `messages`, until the
+    /// reply finishes or is cancelled.
+    partial_reply: Option<String>,
+    inflight: Option<InflightRequest>,
+    reply_order: ReplyOrder<(Result<LlmResponse>, Option<String>)>,
+    /// Messages sent while a request was in flight, oldest first
+    queued_messages: VecDeque<String>,
+    /// When a simulation event or automatic report last triggered a request
+    last_auto_response: Option<Instant>,
+    auto_report: AutoReport,
+    /// The LLM was already asked to fix malformed actions since the player last sent something
+    auto_corrected: bool,
+    /// The player clicked into the input box and hasn't clicked away yet
+    editing: bool,
+    /// Give the input box focus the next time it's built
+    focus_input: bool,
+    /// Text to find in the transcript. Archived messages aren't searched.
+    search: String,
+    search_case_sensitive: bool,
+    /// Show messages in read-only text boxes, so part of one can be selected and copied. Each
+    /// message is its own box, so a selection can't span two of them.
+    select_text: bool,
+    /// The index into `messages` of the match last jumped to
+    search_match: Option<usize>,
+    resume_choice: Option<ResumeChoice>,
+    connection: ConnectionStatus,
+    /// The provider said the account is out of credit. Nothing is sent until the player says it's
+    /// topped up by checking the connection again.
+    out_of_credits: bool,
+    health_rx: Option<Receiver<ConnectionStatus>>,
+    pub sim_snapshot: Option<SimSnapshot>,
+    pub scenario_params: Option<ScenarioParams>,
+    /// How long the inflight request has been waiting, according to the last heartbeat
+    waiting_secs: u64,
+    /// When the LLM returns several choices, they wait here until the user picks one
+    alternatives: Vec<LlmReply>,
+    /// A reply the player pinned, to compare against a regenerated one
+    pinned_reply: Option<String>,
+    /// The inflight request is regenerating a reply for comparison, not continuing the
+    /// conversation
+    comparing: bool,
+    /// A regenerated reply, shown beside `pinned_reply`. It's never added to the conversation or
+    /// run.
+    comparison: Option<String>,
+    /// Indices into `messages` of `Role::Thoughts` and `Role::SimState` the player has expanded
+    expanded_thoughts: BTreeSet<usize>,
+    /// Commands the player applied from a reply with its Apply button. They go before the
+    /// session's commands, and dry run doesn't hold them back.
+    manual_commands: CommandQueue,
+    auto_pause: AutoPause,
+    /// Commands the chatbox issues itself, like `auto_pause`. They don't come from a reply, so
+    /// dry run doesn't hold them back.
+    internal_commands: Vec<ChatCommand>,
+    /// The most recent batch handed to the sandbox, to explain why it happened
+    last_applied: Vec<SourcedCommand>,
+    /// How many queued commands the panel currently shows
+    shown_queue_len: usize,
+    /// What the Send button showed when the panel was last built
+    shown_send_button: SendButton,
+    /// Whether the input had something to send when it last changed, so the Send button doesn't
+    /// have to read the whole input every frame
+    input_has_text: bool,
+    ride_hail_quota: Option<usize>,
+    /// How many of the most recent messages are scrolled out of view
+    scroll_back: usize,
+    width_pct: usize,
+    height_pct: usize,
+    /// How tall the input box was last built, to notice when its text needs more or less room
+    input_height: f64,
+    on_submit: Option<Box<dyn FnMut(&str)>>,
+    on_command: Option<Box<dyn FnMut(&ChatCommand)>>,
+}
+
+impl Chatbox {
+    pub fn new(ctx: &mut EventCtx, app: &App) -> Chatbox {
+        let locale = load_locale(ctx, app);
+        let current = ChatContext::current(app);
+        let mut saved = SavedConversation::load();
+        let mut resume_choice = None;
+        if saved.is_some() && !ASKED_ABOUT_SAVED.swap(true, Ordering::Relaxed) {
+            let age = SavedConversation::age();
+            let unloaded = if continue_by_default(age) {
+                None
+            } else {
+                saved.take()
+            };
+            resume_choice = Some(ResumeChoice {
+                saved: unloaded,
+                loaded: 0,
+                age,
+                current: current.clone(),
+            });
+        }
+        let (context, mut messages, archived, seed, tokens_used) = match saved {
+            Some(saved) => {
+                let saved = saved.resume(&current, locale);
+                (
+                    saved.context,
+                    saved.messages,
+                    saved.archived,
+                    saved.seed,
+                    saved.tokens_used,
+                )
+            }
+            None => (current, Vec::new(), 0, None, 0),
+        };
+        if let Some(ref mut choice) = resume_choice {
+            choice.loaded = messages.len();
+        }
+        let new_conversation = messages.is_empty() && archived == 0;
+        let replay: Option<Box<dyn LlmBackend>> = match ReplayBackend::from_env() {
+            Ok(replay) => replay.map(|replay| Box::new(replay) as Box<dyn LlmBackend>),
+            Err(err) => {
+                messages.push((Role::System, locale.replay_failed(&format!("{err:#}"))));
+                None
+            }
+        };
+        if replay.is_some() {
+            messages.push((
+                Role::System,
+                Msg::ReplayingRecording.text(locale).to_string(),
+            ));
+        }
+        greet(&mut messages, new_conversation, locale);
+        // Only a recording answers right away. Otherwise, the chatbox sends its own requests from
+        // a worker thread.
+        let mut session = ChatSession::with_backend(context, replay);
+        session.messages = messages;
+        session.tokens_used = tokens_used;
+
+        let mut cb = Chatbox {
+            panel: Panel::empty(ctx),
+            settings: ChatSettings::load(),
+            templates: PromptTemplates::load(),
+            show_templates: false,
+            session,
+            archived,
+            seed,
+            locale,
+            input_prefill: SavedDraft::load().unwrap_or_else(|| DEFAULT_PROMPT.to_string()),
+            attachment: None,
+            confirming_send: false,
+            confirming_queued_actions: false,
+            unsaved_draft_since: None,
+            pending_rx: None,
+            partial_reply: None,
+            inflight: None,
+            reply_order: ReplyOrder::default(),
+            queued_messages: VecDeque::new(),
+            last_auto_response: None,
+            auto_report: AutoReport::default(),
+            auto_corrected: false,
+            editing: false,
+            focus_input: false,
+            search: String::new(),
+            search_case_sensitive: false,
+            select_text: false,
+            search_match: None,
+            resume_choice,
+            connection: ConnectionStatus::Checking,
+            out_of_credits: false,
+            health_rx: None,
+            sim_snapshot: None,
+            scenario_params: None,
+            waiting_secs: 0,
+            alternatives: Vec::new(),
+            pinned_reply: None,
+            comparing: false,
+            comparison: None,
+            expanded_thoughts: BTreeSet::new(),
+            manual_commands: CommandQueue::default(),
+            auto_pause: AutoPause::default(),
+            internal_commands: Vec::new(),
+            last_applied: Vec::new(),
+            shown_queue_len: 0,
+            shown_send_button: SendButton::Send,
+            input_has_text: false,
+            ride_hail_quota: None,
+            scroll_back: 0,
+            width_pct: 35,
+            height_pct: 35,
+            input_height: 0.0,
+            on_submit: None,
+            on_command: None,
+        };
+        cb.start_health_check();
+        cb.rebuild_panel(ctx);
+        cb
+    }
+
+    /// Handles any reply or connection check that finished since the last call, without waiting.
+    /// `event` calls this, but it doesn't depend on any input, so anything driving the chatbox
+    /// can call it directly.
+    pub fn poll_pending(&mut self, ctx: &mut EventCtx) {
+        let mut result = None;
+        let mut heartbeat = false;
+        let mut chunk = false;
+        if let Some(rx) = &self.pending_rx {
+            while let Ok(msg) = rx.try_recv() {
+                match msg {
+                    WorkerMsg::Heartbeat(secs) => {
+                        self.waiting_secs = secs;
+                        heartbeat = true;
+                    }
+                    WorkerMsg::Chunk(text) => {
+                        self.partial_reply
+                            .get_or_insert_with(String::new)
+                            .push_str(&text);
+                        chunk = true;
+                    }
+                    WorkerMsg::Done(res) => {
+                        result = Some(res);
+                        break;
+                    }
+                }
+            }
+        }
+        if let Some(res) = result {
+            self.pending_rx = None;
+            self.partial_reply = None;
+            self.waiting_secs = 0;
+            if let Some(req) = self.inflight.take() {
+                // Say so when the settings changed while waiting, instead of quietly using old ones
+                let stale = req
+                    .settings
+                    .describe_change(&self.request_settings(), self.locale);
+                for (res, stale) in self.reply_order.arrived(req.seq, (res, stale)) {
+                    self.add_result(res, stale);
+                }
+            }
+            self.save();
+            self.scroll_back = 0;
+            self.rebuild_panel(ctx);
+            self.send_next_queued(ctx);
+        } else {
+            // Don't rebuild the whole panel, which would disturb the input box
+            if heartbeat {
+                let status = self.waiting_status(ctx);
+                self.replace_widget(ctx, "waiting status", status);
+            }
+            if chunk {
+                let partial = self.partial_reply_widget(ctx);
+                self.replace_widget(ctx, "partial reply", partial);
+            }
+        }
+
+        if let Some(status) = self.health_rx.as_ref().and_then(|rx| rx.try_recv().ok()) {
+            self.health_rx = None;
+            self.connection = status;
+            // Don't rebuild the whole panel, which would disturb the input box
+            let dot = self.connection_dot(ctx);
+            self.replace_widget(ctx, "connection status", dot);
+        }
+    }
+
+    /// Records a finished request's reply or error. `stale` describes how the settings changed
+    /// since it was sent.
+    fn add_result(&mut self, res: Result<LlmResponse>, stale: Option<String>) {
+        // A real request says as much about the connection as a health check
+        self.connection = match res {
+            Ok(_) => ConnectionStatus::Ok,
+            Err(ref err) => ConnectionStatus::Failed(describe_error(err, self.locale)),
+        };
+        self.out_of_credits = matches!(res, Err(ref err) if err.is::<BalanceExhausted>());
+        self.health_rx = None;
+        if let Ok(LlmResponse {
+            total_tokens: Some(tokens),
+            ..
+        }) = res
+        {
+            self.session.tokens_used += tokens;
+        }
+        match res.map(|resp| resp.choices) {
+            Ok(choices) if std::mem::take(&mut self.comparing) => {
+                self.comparison = choices.into_iter().next().map(|reply| reply.content);
+            }
+            Ok(mut choices) => {
+                if choices.len() == 1 {
+                    self.add_reply(choices.pop().unwrap());
+                } else {
+                    self.alternatives = choices;
+                }
+            }
+            Err(err) => {
+                self.comparing = false;
+                self.session.messages.push((
+                    Role::System,
+                    format!(
+                        "{}: {}",
+                        self.tr(Msg::LlmError),
+                        describe_error(&err, self.locale)
+                    ),
+                ));
+            }
+        }
+        if let Some(change) = stale {
+            self.session
+                .messages
+                .push((Role::System, self.locale.reply_was(&change)));
+        }
+    }
+
+    pub fn event(&mut self, ctx: &mut EventCtx) {
+        // The input box has fixed dims, so the panel can't just relayout
+        if ctx.input.is_window_resized() {
+            self.rebuild_panel(ctx);
+        }
+
+        self.poll_pending(ctx);
+        self.maybe_auto_report(ctx);
+
+        // Keep local copy of input in sync. The panel might be between rebuilds, so the input box
+        // and everything else in it are looked up with maybe_find.
+        let max_input_height = InputLayout::new(
+            ctx.canvas.get_window_dims(),
+            self.width_pct,
+            self.height_pct,
+        )
+        .input_dims
+        .height;
+        if let Some((text, height)) = self
+            .panel
+            .maybe_find_mut::<MultilineTextBox>("chat_input")
+            .and_then(|input| {
+                input.take_dirty().then(|| {
+                    (
+                        input.get_text(),
+                        fit_input_height(ctx, input, max_input_height),
+                    )
+                })
+            })
+        {
+            self.input_has_text = !normalize_message(&text).is_empty();
+            self.input_prefill = text;
+            self.unsaved_draft_since.get_or_insert_with(Instant::now);
+            if height != self.input_height {
+                // Rebuilding keeps the caret and focus
+                self.rebuild_panel(ctx);
+            } else {
+                // Don't rebuild the whole panel, which would disturb the input box
+                let size = self.input_size(ctx);
+                self.replace_widget(ctx, "input size", size);
+            }
+        }
+        // Write at most once per delay while typing, not on every keystroke
+        if let Some(since) = self.unsaved_draft_since {
+            if since.elapsed() >= DRAFT_SAVE_DELAY {
+                self.save_draft();
+            } else {
+                ctx.request_update(UpdateType::Game);
+            }
+        }
+
+        // The sandbox consumes commands between our events
+        if self.shown_queue_len != self.pending_command_count() {
+            self.rebuild_panel(ctx);
+        }
+        // Everything the button depends on is up to date by now. The input's text was only read
+        // if it changed, and the rest is cheap to check.
+        if self.shown_send_button != self.send_button() {
+            self.rebuild_panel(ctx);
+        }
+
+        // Handle these before the input box sees them, so it keeps focus
+        if let Some(delta) = transcript_scroll(ctx, self.visible_messages()) {
+            let max = self
+                .session
+                .messages
+                .len()
+                .saturating_sub(self.visible_messages()) as isize;
+            let scroll_back = (self.scroll_back as isize + delta).clamp(0, max) as usize;
+            if scroll_back != self.scroll_back {
+                self.scroll_back = scroll_back;
+                self.rebuild_panel(ctx);
+            }
+            return;
+        }
+
+        // Clicking into the input isn't the only way to start typing
+        if ctx.input.pressed(shortcut("focus input")) {
+            if !self.editing {
+                self.focus_input = true;
+                self.editing = true;
+                self.focus_changed(ctx, true);
+                self.rebuild_panel(ctx);
+            }
+            return;
+        }
+
+        // Newlines are the input box's default, so intercept Enter first when it should send
+        if self
+            .panel
+            .maybe_find::<MultilineTextBox>("chat_input")
+            .map(|input| input.has_focus())
+            .unwrap_or(false)
+            && self.settings.send_key.on_enter(
+                ctx.is_key_down(Key::LeftControl),
+                ctx.is_key_down(Key::LeftShift),
+            ) == EnterAction::Send
+            && (ctx.input.pressed(Key::Enter) || ctx.input.pressed(lctrl(Key::Enter)))
+        {
+            self.send(ctx);
+            return;
+        }
+
+        match self.panel.event(ctx) {
+            Outcome::Clicked(x) if x == "send" => {
+                if self.send_button() == SendButton::Cancel {
+                    self.cancel_request();
+                    self.rebuild_panel(ctx);
+                    self.send_next_queued(ctx);
+                } else {
+                    self.send(ctx);
+                }
+            }
+            Outcome::Changed(x) if x == "transcript search" => {
+                let search = match self
+                    .panel
+                    .maybe_find::<MultilineTextBox>("transcript search")
+                {
+                    Some(search) => search.get_text(),
+                    None => return,
+                };
+                self.search = search;
+                self.search_match = None;
+                self.jump_to_match(true);
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Changed(x) if x == "select text" => {
+                self.select_text = self.panel.is_checked("select text");
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Changed(x) if x == "match case" => {
+                self.search_case_sensitive = self.panel.is_checked("match case");
+                self.search_match = None;
+                self.jump_to_match(true);
+                self.rebuild_panel(ctx);
+            }
+            // A single-line box reports Enter as a click
+            Outcome::Clicked(x) if x == "transcript search" => {
+                self.jump_to_match(!ctx.is_key_down(Key::LeftShift));
+                self.rebuild_panel(ctx);
+            }
+            Outcome::FocusGained(x) if x == "chat_input" => {
+                self.editing = true;
+                self.focus_changed(ctx, true);
+            }
+            Outcome::FocusLost(x) if x == "chat_input" => {
+                self.editing = false;
+                self.focus_changed(ctx, false);
+            }
+            Outcome::Clicked(x) if x == "resend last message" => {
+                self.resend_last(ctx);
+            }
+            Outcome::Clicked(x) if x == "send anyway" => {
+                self.confirming_send = false;
+                let input = self.current_input();
+                self.check_queued_actions(ctx, input);
+            }
+            Outcome::Clicked(x) if x == "cancel send" => {
+                self.confirming_send = false;
+                self.confirming_queued_actions = false;
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Clicked(x) if x == "run queued actions" => {
+                self.confirming_queued_actions = false;
+                self.session.commands.merge();
+                let input = self.current_input();
+                self.dispatch(ctx, input);
+            }
+            Outcome::Clicked(x) if x == "discard queued actions" => {
+                self.confirming_queued_actions = false;
+                self.session.commands.clear();
+                let input = self.current_input();
+                self.dispatch(ctx, input);
+            }
+            Outcome::Clicked(x) if x == "send with queued actions" => {
+                self.confirming_queued_actions = false;
+                let input = self.current_input();
+                self.dispatch(ctx, input);
+            }
+            Outcome::Clicked(x) if x == "cancel request" => {
+                self.cancel_request();
+                self.rebuild_panel(ctx);
+                self.send_next_queued(ctx);
+            }
+            Outcome::Clicked(x) if x == "restart request" => {
+                // The old worker notices nobody is listening and stops
+                if let Some(req) = self.inflight.take() {
+                    for (res, stale) in self.reply_order.cancel(req.seq) {
+                        self.add_result(res, stale);
+                    }
+                    self.start_request(req.history, req.image);
+                }
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Clicked(x) if x == "discard queued messages" => {
+                self.queued_messages.clear();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Changed(x) if x == "high contrast" => {
+                self.settings.high_contrast = self.panel.is_checked("high contrast");
+                self.settings.save();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Changed(x) if x == "dry run" => {
+                self.settings.dry_run = self.panel.is_checked("dry run");
+                self.settings.save();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Changed(x) if x == "pause while typing" => {
+                self.settings.pause_while_typing = self.panel.is_checked("pause while typing");
+                self.settings.save();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Changed(x) if x == "attach sim state" => {
+                self.settings.attach_sim_state = self.panel.is_checked("attach sim state");
+                self.settings.save();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Changed(x) if x == "attach scenario" => {
+                self.settings.attach_scenario_params = self.panel.is_checked("attach scenario");
+                self.settings.save();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Changed(x) if x == "max reply tokens" => {
+                let max = self.panel.spinner("max reply tokens");
+                self.settings.max_tokens = (max > 0).then_some(max);
+                self.settings.save();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Changed(x) if x == "react to events" => {
+                self.settings.auto_respond_to_events = self.panel.is_checked("react to events");
+                self.settings.save();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Changed(x) if x == "auto report minutes" => {
+                self.settings.auto_report_minutes = self.panel.spinner("auto report minutes");
+                self.settings.save();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Changed(x) if x == "max auto reports" => {
+                self.settings.max_auto_reports = self.panel.spinner("max auto reports");
+                self.settings.save();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Changed(x) if x == "frequency penalty" => {
+                self.settings.frequency_penalty =
+                    self.panel.spinner::<RoundedF64>("frequency penalty").0;
+                self.settings.save();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Changed(x) if x == "presence penalty" => {
+                self.settings.presence_penalty =
+                    self.panel.spinner::<RoundedF64>("presence penalty").0;
+                self.settings.save();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Changed(x) if x == "action lines" => {
+                self.sync_input();
+                self.settings.action_lines = self.panel.dropdown_value("action lines");
+                self.settings.save();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Changed(x) if x == "chat position" => {
+                self.sync_input();
+                self.settings.position = self.panel.dropdown_value("chat position");
+                self.settings.save();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Changed(x) if x == "context messages" => {
+                self.settings.context_messages = self.panel.spinner("context messages");
+                self.settings.save();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Changed(x) if x == "send key" => {
+                self.settings.send_key = if self.panel.is_checked("send key") {
+                    SendKey::Enter
+                } else {
+                    SendKey::CtrlEnter
+                };
+                self.settings.save();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Clicked(x) if x.starts_with("choose option ") => {
+                // The panel might be stale, so the index might not exist anymore
+                if let Ok(idx) = x["choose option ".len()..].parse::<usize>() {
+                    if idx < self.alternatives.len() {
+                        let reply = std::mem::take(&mut self.alternatives).remove(idx);
+                        self.add_reply(reply);
+                        self.save();
+                        self.send_next_queued(ctx);
+                    }
+                }
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Clicked(x) if x == "pin reply" => {
+                self.pinned_reply = self.last_assistant_reply().map(|reply| reply.to_string());
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Clicked(x) if x == "unpin reply" => {
+                self.pinned_reply = None;
+                self.comparison = None;
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Clicked(x) if x == "regenerate to compare" => {
+                self.regenerate_for_comparison();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Clicked(x) if x == "connection status" => {
+                // The balance can't be checked, so trust that the player topped it up
+                let topped_up = std::mem::take(&mut self.out_of_credits);
+                self.start_health_check();
+                self.rebuild_panel(ctx);
+                if topped_up {
+                    self.send_next_queued(ctx);
+                }
+            }
+            Outcome::Clicked(x)
+                if x.starts_with("toggle thoughts ") || x.starts_with("toggle sim state ") =>
+            {
+                if let Some(Ok(idx)) = x.rsplit(' ').next().map(|idx| idx.parse::<usize>()) {
+                    if !self.expanded_thoughts.remove(&idx) {
+                        self.expanded_thoughts.insert(idx);
+                    }
+                }
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Clicked(x) if x.starts_with("apply actions ") => {
+                if let Some((_, msg)) = x["apply actions ".len()..]
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|idx| self.session.messages.get(idx))
+                {
+                    let batches = parse_commands(msg);
+                    self.apply_manually(ctx, batches);
+                }
+            }
+            Outcome::Clicked(x) if x.starts_with("explain actions ") => {
+                if let Some(prompt) = x["explain actions ".len()..]
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|idx| self.session.messages.get(idx))
+                    .and_then(|(_, msg)| explain_prompt(msg))
+                {
+                    if self.pending_rx.is_none()
+                        && self.alternatives.is_empty()
+                        && !self.out_of_credits
+                    {
+                        self.submit(ctx, prompt);
+                    }
+                }
+            }
+            Outcome::Clicked(x) if x == "continue conversation" => {
+                if let Some(choice) = self.resume_choice.take() {
+                    if let Some(saved) = choice.saved {
+                        self.load_saved(saved.resume(&choice.current, self.locale));
+                    }
+                }
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Clicked(x) if x == "new conversation" => {
+                if let Some(choice) = self.resume_choice.take() {
+                    if choice.saved.is_none() {
+                        self.start_fresh(choice.current, choice.loaded);
+                    }
+                }
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Clicked(x) if x == "load earlier messages" => {
+                self.load_earlier_messages();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Clicked(x) if x == "attach map view" => {
+                if self.attachment.take().is_none() {
+                    // The capture happens after this frame is drawn
+                    let path = abstio::path_player("chat/map_view.png");
+                    ctx.request_update(UpdateType::ScreenCapture { path: path.clone() });
+                    self.attachment = Some(path);
+                }
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Clicked(x) if x == "toggle seed" => {
+                // Small enough to read off the header and note down
+                self.seed = match self.seed {
+                    Some(_) => None,
+                    None => Some(rand::random::<u32>() as u64),
+                };
+                self.save();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Clicked(x) if x == "reset context" => {
+                self.session
+                    .messages
+                    .push((Role::ContextReset, String::new()));
+                self.save();
+                self.scroll_back = 0;
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Clicked(x) if x == "copy as markdown" => {
+                let mut messages = TranscriptArchive::load().messages;
+                messages.extend(self.session.messages.iter().cloned());
+                widgetry::tools::set_clipboard(transcript_markdown(
+                    &self.session.context,
+                    &messages,
+                    &self.queued_messages,
+                    self.pending_rx.is_some(),
+                ));
+            }
+            Outcome::Clicked(x) if x == "templates" => {
+                self.show_templates = !self.show_templates;
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Clicked(x) if x.starts_with("insert template ") => {
+                if let Some(template) = x["insert template ".len()..]
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|idx| self.templates.all().into_iter().nth(idx))
+                {
+                    self.insert_into_input(ctx, &template.text);
+                }
+            }
+            Outcome::Clicked(x) if x.starts_with("insert example action ") => {
+                if let Some(example) = x["insert example action ".len()..]
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|idx| ACTION_EXAMPLES.get(idx))
+                {
+                    self.insert_into_input(ctx, &example.instruction());
+                }
+            }
+            Outcome::Clicked(x) if x == "save template" => {
+                let text = normalize_message(&self.input_prefill);
+                if !text.is_empty() && self.templates.add(text) {
+                    self.templates.save();
+                    self.rebuild_panel(ctx);
+                }
+            }
+            Outcome::Clicked(x) if x.starts_with("delete template ") => {
+                // Skip the built-in template. A second click before the panel rebuilds might name
+                // one that's already gone.
+                if let Some(idx) = x["delete template ".len()..]
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|idx| idx.checked_sub(1))
+                    .filter(|idx| *idx < self.templates.saved.len())
+                {
+                    self.templates.saved.remove(idx);
+                    self.templates.save();
+                }
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Clicked(x) if x == "clear queue" => {
+                self.manual_commands.clear();
+                self.session.commands.clear();
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Clicked(x) if x == "smaller" => {
+                self.sync_input();
+                self.width_pct = self.width_pct.saturating_sub(5).max(15);
+                self.height_pct = self.height_pct.saturating_sub(5).max(15);
+                self.rebuild_panel(ctx);
+            }
+            Outcome::Clicked(x) if x == "larger" => {
+                self.sync_input();
+                self.width_pct = (self.width_pct + 5).min(50);
+                self.height_pct = (self.height_pct + 5).min(60);
+                self.rebuild_panel(ctx);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn draw(&self, g: &mut GfxCtx) {
+        self.panel.draw(g);
+    }
+
+    pub fn recreate_panel(&mut self, ctx: &mut EventCtx) {
+        self.rebuild_panel(ctx);
+    }
+
+    /// Call before the chatbox goes away, including when the game exits. Stops waiting for any
+    /// reply, then writes the draft and conversation right away instead of after the usual delay.
+    /// The writes are synchronous, so everything is on disk when this returns.
+    pub fn on_close(&mut self) {
+        if let Some(input) = self.panel.maybe_find::<MultilineTextBox>("chat_input") {
+            let text = input.get_text();
+            if text != self.input_prefill {
+                self.input_prefill = text;
+                self.unsaved_draft_since.get_or_insert_with(Instant::now);
+            }
+        }
+        self.save_draft();
+        if self.pending_rx.is_some() {
+            // Keeps what was streamed so far, and saves
+            self.cancel_request();
+        } else {
+            self.write_conversation();
+        }
+    }
+
+    /// Writes the input box's contents to disk, if they've changed since the last time.
+    pub fn save_draft(&mut self) {
+        if self.unsaved_draft_since.take().is_some() {
+            SavedDraft::save(self.input_prefill.clone());
+        }
+    }
+
+    /// Returns the next batch of commands, which should all be applied in the same frame. In dry
+    /// run mode, this only returns the chatbox's own commands, never ones from a reply.
+    pub fn take_commands(&mut self) -> Vec<AppliedCommand> {
+        let mut commands: Vec<AppliedCommand> = std::mem::take(&mut self.internal_commands)
+            .into_iter()
+            .map(|cmd| AppliedCommand { cmd, source: None })
+            .collect();
+        let batch = next_batch(
+            &mut self.manual_commands,
+            &mut self.session.commands,
+            self.settings.dry_run,
+        );
+        if batch.is_empty() {
+            return commands;
+        }
+        for (idx, (cmd, _)) in batch.iter().enumerate() {
+            self.auto_pause.explicit_command(*cmd);
+            commands.push(AppliedCommand {
+                cmd: *cmd,
+                source: Some(idx),
+            });
+        }
+        self.last_applied = batch;
+        commands
+    }
+
+    pub fn focus_changed(&mut self, ctx: &mut EventCtx, focused: bool) {
+        let sim_running = self
+            .sim_snapshot
+            .map(|snapshot| snapshot.speed.is_some())
+            .unwrap_or(false);
+        // Still resume after the setting is turned off mid-pause
+        if !self.settings.pause_while_typing && !self.auto_pause.paused {
+            return;
+        }
+        if let Some(cmd) = self.auto_pause.focus_changed(focused, sim_running) {
+            if cmd == ChatCommand::Pause {
+                self.session
+                    .messages
+                    .push((Role::System, self.tr(Msg::PausedWhileTyping).to_string()));
+                self.rebuild_panel(ctx);
+            }
+            self.internal_commands.push(cmd);
+        }
+    }
+
+    /// How many commands from LLM replies are still waiting to be applied.
+    pub fn pending_command_count(&self) -> usize {
+        self.manual_commands.len() + self.session.commands.len()
+    }
+
+    /// Records a quota requested by the LLM, if it's within the configured range, and reports the
+    /// result in the transcript. Nothing in the sim uses the quota yet, and the report says so.
+    pub fn set_ride_hail_quota(&mut self, ctx: &mut EventCtx, cmd: AppliedCommand, quota: usize) {
+        let (min, max) = self.settings.ride_hail_quota_range;
+        if quota < min || quota > max {
+            self.add_command_result(ctx, cmd, self.locale.quota_out_of_range(quota, min, max));
+            return;
+        }
+        let before = self.ride_hail_quota.replace(quota);
+        self.add_command_result(ctx, cmd, self.locale.quota_set(quota));
+        if before != Some(quota) {
+            self.add_param_diff(
+                ctx,
+                cmd,
+                vec![ParamChange {
+                    name: Msg::QuotaParam,
+                    before: before
+                        .map(prettyprint_usize)
+                        .unwrap_or_else(|| self.tr(Msg::Unset).to_string()),
+                    after: prettyprint_usize(quota),
+                }],
+            );
+        }
+    }
+
+    /// Checks that a step requested by the LLM is sensible. If not, explains why in the transcript
+    /// and returns false.
+    pub fn validate_step(&mut self, ctx: &mut EventCtx, cmd: AppliedCommand, dt: Duration) -> bool {
+        if dt <= Duration::ZERO {
+            self.add_command_result(ctx, cmd, self.tr(Msg::StepByNothing).to_string());
+            false
+        } else if dt > MAX_STEP {
+            self.add_command_result(
+                ctx,
+                cmd,
+                self.locale
+                    .step_too_long(&dt.to_string(), &MAX_STEP.to_string()),
+            );
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Called every frame, so the next message can say what the simulation is doing.
+    pub fn set_sim_snapshot(&mut self, snapshot: SimSnapshot) {
+        self.sim_snapshot = Some(snapshot);
+    }
+
+    /// Also called every frame, but only recomputes the scenario's parameters when they changed.
+    pub fn refresh_scenario_params(&mut self, app: &App) {
+        let stale = match self.scenario_params {
+            Some(ref params) => params.is_stale(&app.primary.sim),
+            None => true,
+        };
+        if stale {
+            self.scenario_params = Some(ScenarioParams::from_sim(
+                app.primary.map.get_name(),
+                &app.primary.sim,
+                self.settings.ride_hail_quota_range,
+            ));
+        }
+    }
+
+    /// Records how the simulation's speed changed while applying an action from the LLM. Changes
+    /// made by the chatbox itself, like pausing while typing, aren't recorded.
+    pub fn report_speed_change(
+        &mut self,
+        ctx: &mut EventCtx,
+        cmd: AppliedCommand,
+        before: Option<SpeedSetting>,
+        after: Option<SpeedSetting>,
+    ) {
+        if before != after {
+            self.add_param_diff(
+                ctx,
+                cmd,
+                vec![ParamChange {
+                    name: Msg::SpeedParam,
+                    before: describe_speed(before, self.locale).to_string(),
+                    after: describe_speed(after, self.locale).to_string(),
+                }],
+            );
+        }
+    }
+
+    /// Explains that a step requested by the LLM can't run, because this mode has no time panel to
+    /// run the simulation with.
+    pub fn reject_step(&mut self, ctx: &mut EventCtx, cmd: AppliedCommand, dt: Duration) {
+        self.add_command_result(
+            ctx,
+            cmd,
+            self.locale.step_without_time_panel(&dt.to_string()),
+        );
+    }
+
+    /// Reports the new simulation time after a step requested by the LLM.
+    pub fn report_step(
+        &mut self,
+        ctx: &mut EventCtx,
+        cmd: AppliedCommand,
+        dt: Duration,
+        now: Time,
+    ) {
+        self.add_command_result(
+            ctx,
+            cmd,
+            self.locale.stepped(&dt.to_string(), &now.ampm_tostring()),
+        );
+    }
+
+    /// Sends the current stats when an automatic report is due, unless something else is in
+    /// flight, the cooldown shared with simulation events hasn't passed, or the player hasn't sent
+    /// anything for `max_auto_reports` reports. A report that can't go out is skipped, not delayed.
+    fn maybe_auto_report(&mut self, ctx: &mut EventCtx) {
+        let snapshot = match self.sim_snapshot {
+            Some(snapshot) => snapshot,
+            None => return,
+        };
+        let interval = (self.settings.auto_report_minutes > 0)
+            .then(|| Duration::minutes(self.settings.auto_report_minutes));
+        if !self.auto_report.due(interval, snapshot.time) {
+            return;
+        }
+        let max = self.settings.max_auto_reports.clamp(1, MAX_AUTO_REPORTS);
+        if self.pending_rx.is_some()
+            || !self.alternatives.is_empty()
+            || self.out_of_credits
+            || self.auto_report.sent >= max
+            || !can_auto_respond(true, self.last_auto_response, Instant::now())
+        {
+            return;
+        }
+        self.auto_report.sent += 1;
+        self.last_auto_response = Some(Instant::now());
+        let notice = self.locale.auto_report(
+            self.auto_report.sent,
+            max,
+            &snapshot.time.ampm_tostring(),
+            self.auto_report.sent == max,
+        );
+        self.session.messages.push((Role::System, notice));
+        self.save();
+        self.scroll_back = 0;
+        self.start_request(
+            self.history_with_prompt(format!(
+                "Automatic report, not from the player. Here are the current stats: {} \
+                 Do any settings need adjusting? If not, just say so.",
+                snapshot.describe()
+            )),
+            None,
+        );
+        self.rebuild_panel(ctx);
+    }
+
+    /// Adds something that happened in the simulation, like suspected gridlock, to the transcript.
+    /// If `auto_respond` is set, the LLM is asked to react, but only if the player enabled that,
+    /// nothing else is in flight, and it hasn't happened too recently.
+    pub fn push_system_event(&mut self, ctx: &mut EventCtx, text: String, auto_respond: bool) {
+        self.session.messages.push((Role::System, text.clone()));
+        self.save();
+        self.scroll_back = 0;
+        if auto_respond
+            && self.pending_rx.is_none()
+            && self.alternatives.is_empty()
+            && can_auto_respond(
+                self.settings.auto_respond_to_events,
+                self.last_auto_response,
+                Instant::now(),
+            )
+        {
+            self.last_auto_response = Some(Instant::now());
+            self.start_request(
+                self.history_with_prompt(format!(
+                    "The simulation reported: {text}. React to this only if it needs action."
+                )),
+                None,
+            );
+        }
+        self.rebuild_panel(ctx);
+    }
+
+    fn add_system_message(&mut self, ctx: &mut EventCtx, msg: String) {
+        self.session.messages.push((Role::System, msg));
+        self.save();
+        self.scroll_back = 0;
+        self.rebuild_panel(ctx);
+    }
+
+    /// Only changes made by commands from replies are recorded, not ones from `internal_commands`.
+    fn add_param_diff(
+        &mut self,
+        ctx: &mut EventCtx,
+        cmd: AppliedCommand,
+        changes: Vec<ParamChange>,
+    ) {
+        if changes.is_empty() || cmd.source.is_none() {
+            return;
+        }
+        let lines: Vec<String> = changes
+            .iter()
+            .map(|change| change.describe(self.locale))
+            .collect();
+        self.session
+            .messages
+            .push((Role::ParamDiff, lines.join("\n")));
+        self.save();
+        self.scroll_back = 0;
+        self.rebuild_panel(ctx);
+    }
+
+    /// Records the outcome of a command from the batch just applied, so the next request tells
+    /// the LLM how its actions went.
+    fn add_command_result(&mut self, ctx: &mut EventCtx, cmd: AppliedCommand, msg: String) {
+        match cmd.source.and_then(|idx| self.last_applied.get(idx)) {
+            Some((_, source)) => {
+                self.session
+                    .messages
+                    .push((Role::CommandResult, format!("{source} → {msg}")));
+                self.save();
+                self.scroll_back = 0;
+                self.rebuild_panel(ctx);
+            }
+            None => self.add_system_message(ctx, msg),
+        }
+    }
+
+    fn rebuild_panel(&mut self, ctx: &mut EventCtx) {
+        let mut col = Vec::new();
+        let header = vec![
+            Line(self.tr(Msg::Title)).small_heading().into_widget(ctx),
+            self.connection_dot(ctx),
+            ctx.style()
+                .btn_plain
+                .text("-")
+                .hotkey(shortcut("smaller"))
+                .build_widget(ctx, "smaller"),
+            ctx.style()
+                .btn_plain
+                .text("+")
+                .hotkey(shortcut("larger"))
+                .build_widget(ctx, "larger"),
+            self.checkbox(
+                ctx,
+                "high contrast",
+                Msg::HighContrast,
+                shortcut("high contrast"),
+                self.settings.high_contrast,
+            ),
+            ctx.style()
+                .btn_plain
+                .text(if self.show_templates {
+                    self.tr(Msg::HideTemplates)
+                } else {
+                    self.tr(Msg::Templates)
+                })
+                .hotkey(shortcut("templates"))
+                .build_widget(ctx, "templates"),
+            ctx.style()
+                .btn_plain
+                .text(if self.attachment.is_some() {
+                    self.tr(Msg::RemoveMapView)
+                } else {
+                    self.tr(Msg::AttachMapView)
+                })
+                .hotkey(shortcut("attach map view"))
+                .build_widget(ctx, "attach map view"),
+            ctx.style()
+                .btn_plain
+                .text(match self.seed {
+                    Some(seed) => format!("{}: {seed}", self.tr(Msg::Seed)),
+                    None => self.tr(Msg::FixSeed).to_string(),
+                })
+                .hotkey(shortcut("toggle seed"))
+                .build_widget(ctx, "toggle seed"),
+            ctx.style()
+                .btn_plain
+                .text(self.tr(Msg::CopyMarkdown))
+                .hotkey(shortcut("copy as markdown"))
+                .tooltip(Text::tooltip(
+                    ctx,
+                    shortcut("copy as markdown"),
+                    self.tr(Msg::CopyMarkdownTooltip),
+                ))
+                .build_widget(ctx, "copy as markdown"),
+            ctx.style()
+                .btn_plain
+                .text(self.tr(Msg::ResetContext))
+                .tooltip(self.tr(Msg::ResetContextTooltip))
+                .disabled(matches!(
+                    self.session.messages.last(),
+                    None | Some((Role::ContextReset, _))
+                ))
+                .build_widget(ctx, "reset context"),
+        ];
+        // Wrapped into as many rows as the panel's width needs
+        let widths: Vec<f64> = header.iter().map(|w| w.get_width_for_forcing()).collect();
+        let mut header = header.into_iter();
+        for len in pack_rows(
+            &widths,
+            HEADER_SPACING,
+            content_width(ctx.canvas.get_window_dims(), self.width_pct),
+        ) {
+            col.push(
+                Widget::evenly_spaced_row(
+                    HEADER_SPACING as usize,
+                    header.by_ref().take(len).collect(),
+                )
+                .centered_vert(),
+            );
+        }
+        if let Some(ref choice) = self.resume_choice {
+            col.push(self.resume_choice_row(ctx, choice));
+        }
+        col.push(self.search_row(ctx));
+
+        let (start, end) = visible_range(
+            self.session.messages.len(),
+            self.scroll_back,
+            self.visible_messages(),
+        );
+        if start == 0 && self.archived > 0 {
+            col.push(
+                ctx.style()
+                    .btn_plain
+                    .text(self.locale.load_earlier(self.archived))
+                    .build_widget(ctx, "load earlier messages")
+                    .margin_above(4),
+            );
+        }
+        if start > 0 {
+            col.push(
+                self.secondary_line(ctx, Line(self.locale.earlier_messages(start)))
+                    .into_widget(ctx)
+                    .margin_above(4),
+            );
+        }
+        // The next message the user sends takes one slot
+        let window_start = context_window_start(
+            &self.session.messages,
+            self.settings.context_messages.saturating_sub(1),
+        );
+        for (idx, (role, msg)) in self
+            .session
+            .messages
+            .iter()
+            .enumerate()
+            .take(end)
+            .skip(start)
+        {
+            // Right after a reset, its own line already marks where the context starts
+            if idx == window_start
+                && idx > 0
+                && self.session.messages[idx - 1].0 != Role::ContextReset
+            {
+                col.push(
+                    self.secondary_line(ctx, Line(self.tr(Msg::ContextWindowDivider)))
+                        .into_widget(ctx)
+                        .margin_above(4),
+                );
+            }
+            let prefix = match role {
+                Role::User => self.tr(Msg::YouPrefix),
+                Role::Assistant => self.tr(Msg::LlmPrefix),
+                Role::System | Role::CommandResult => "",
+                Role::Thoughts => {
+                    col.push(self.thoughts(ctx, idx, msg));
+                    continue;
+                }
+                Role::SimState => {
+                    col.push(self.sim_state(ctx, idx, msg));
+                    continue;
+                }
+                Role::ParamDiff => {
+                    col.push(
+                        Text::from_multiline(
+                            msg.lines()
+                                .map(|line| {
+                                    self.secondary_line(ctx, Line(format!("Δ {line}")))
+                                        .small_monospaced()
+                                })
+                                .collect(),
+                        )
+                        .into_widget(ctx)
+                        .margin_left(10)
+                        .margin_above(4),
+                    );
+                    continue;
+                }
+                Role::ContextReset => {
+                    col.push(
+                        self.secondary_line(ctx, Line(self.tr(Msg::ContextResetDivider)))
+                            .into_widget(ctx)
+                            .margin_above(4),
+                    );
+                    continue;
+                }
+            };
+            let matches = find_in(msg, &self.search, self.search_case_sensitive);
+            // Search highlighting still needs the usual rendering. Replies are shown raw, without
+            // tables or hidden action lines, so what's selected is what the LLM wrote.
+            if self.select_text && matches.is_empty() {
+                let mut message = MultilineTextBox::new(
+                    format!("transcript message {idx}"),
+                    format!("{prefix}{msg}"),
+                    ScreenDims::new(self.wrap_width(ctx, 0.0), 0.0),
+                    false,
+                )
+                .read_only(true)
+                .max_height(ctx, f64::INFINITY);
+                message.set_high_contrast(self.settings.high_contrast);
+                col.push(message.into_widget().margin_above(4));
+                if *role == Role::Assistant {
+                    col.push(self.action_buttons(ctx, idx, msg));
+                }
+                continue;
+            }
+            if *role == Role::Assistant && matches.is_empty() {
+                let lines = shown_reply(msg, self.settings.action_lines);
+                let shown = lines
+                    .iter()
+                    .map(|(line, _)| line.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                let blocks = reply_blocks(&shown);
+                if blocks.iter().any(|b| matches!(b, ReplyBlock::Table(_))) {
+                    col.push(self.reply_with_tables(ctx, blocks));
+                    col.push(self.action_buttons(ctx, idx, msg));
+                    continue;
+                }
+                if self.settings.action_lines != ActionLines::Show {
+                    let mut txt = Text::new();
+                    for (i, (line, chip)) in lines.into_iter().enumerate() {
+                        let line = if i == 0 {
+                            format!("{prefix}{line}")
+                        } else {
+                            line
+                        };
+                        txt.add_line(if chip {
+                            self.secondary_line(ctx, Line(line))
+                        } else {
+                            self.body_line(ctx, Line(line))
+                        });
+                    }
+                    col.push(
+                        txt.wrap_to_pixels(ctx, self.wrap_width(ctx, 0.0))
+                            .into_widget(ctx)
+                            .margin_above(4),
+                    );
+                    col.push(self.action_buttons(ctx, idx, msg));
+                    continue;
+                }
+            }
+            let txt = if matches.is_empty() {
+                Text::from(self.body_line(ctx, Line(format!("{prefix}{msg}"))))
+            } else {
+                let marker = if self.search_match == Some(idx) {
+                    "▶ "
+                } else {
+                    ""
+                };
+                let mut txt = Text::from(self.body_line(ctx, Line(format!("{marker}{prefix}"))));
+                let mut last = 0;
+                for (start, end) in matches {
+                    txt.append(self.body_line(ctx, Line(&msg[last..start])));
+                    txt.append(
+                        self.body_line(ctx, Line(&msg[start..end]))
+                            .fg(ctx.style().text_hotkey_color),
+                    );
+                    last = end;
+                }
+                txt.append(self.body_line(ctx, Line(&msg[last..])));
+                txt
+            };
+            col.push(
+                txt.wrap_to_pixels(ctx, self.wrap_width(ctx, 0.0))
+                    .into_widget(ctx)
+                    .margin_above(4),
+            );
+            if *role == Role::Assistant {
+                col.push(self.action_buttons(ctx, idx, msg));
+            }
+        }
+        if self.pending_rx.is_some() && self.scroll_back == 0 {
+            col.push(self.partial_reply_widget(ctx));
+        }
+        if self.scroll_back > 0 {
+            col.push(
+                self.secondary_line(ctx, Line(self.locale.newer_messages(self.scroll_back)))
+                    .into_widget(ctx)
+                    .margin_above(4),
+            );
+        }
+
+        if !self.alternatives.is_empty() {
+            col.push(
+                self.secondary_line(ctx, Line(self.tr(Msg::PickAlternative)))
+                    .into_widget(ctx)
+                    .margin_above(4),
+            );
+            for (idx, alternative) in self.alternatives.iter().enumerate() {
+                col.push(
+                    Widget::row(vec![
+                        ctx.style()
+                            .btn_outline
+                            .text(self.locale.option(idx + 1))
+                            .build_widget(ctx, format!("choose option {}", idx)),
+                        Text::from(self.body_line(ctx, Line(&alternative.content)))
+                            .wrap_to_pixels(ctx, self.wrap_width(ctx, OPTION_BUTTON_WIDTH))
+                            .into_widget(ctx),
+                    ])
+                    .margin_above(4),
+                );
+            }
+        }
+
+        if let Some(widget) = self.comparison_section(ctx) {
+            col.push(widget);
+        }
+
+        if !self.last_applied.is_empty() {
+            let sources: Vec<&str> = self
+                .last_applied
+                .iter()
+                .map(|(_, source)| source.as_str())
+                .collect();
+            col.push(
+                self.secondary_line(
+                    ctx,
+                    Line(self.locale.last_action_reason(&sources.join(" / "))),
+                )
+                .into_widget(ctx)
+                .margin_above(4),
+            );
+        }
+
+        self.shown_queue_len = self.pending_command_count();
+        if self.shown_queue_len > 0 {
+            col.push(
+                Widget::row(vec![
+                    self.secondary_line(
+                        ctx,
+                        Line(self.locale.actions_queued(self.shown_queue_len)),
+                    )
+                    .into_widget(ctx),
+                    ctx.style()
+                        .btn_plain
+                        .text(self.tr(Msg::Clear))
+                        .build_widget(ctx, "clear queue"),
+                ])
+                .centered_vert()
+                .margin_above(4),
+            );
+        }
+
+        if let Some(quota) = self.ride_hail_quota {
+            col.push(
+                self.secondary_line(ctx, Line(self.locale.ride_hail_quota(quota)))
+                    .into_widget(ctx)
+                    .margin_above(4),
+            );
+        }
+
+        if self.show_templates {
+            col.push(self.templates_section(ctx));
+        }
+
+        if self.pending_rx.is_some() {
+            col.push(self.waiting_status(ctx));
+        }
+        if let Some(change) = self.inflight.as_ref().and_then(|req| {
+            req.settings
+                .describe_change(&self.request_settings(), self.locale)
+        }) {
+            col.push(
+                Widget::row(vec![
+                    self.secondary_line(ctx, Line(self.locale.request_was(&change)))
+                        .into_widget(ctx)
+                        .centered_vert(),
+                    ctx.style()
+                        .btn_outline
+                        .text(self.tr(Msg::RestartRequest))
+                        .build_widget(ctx, "restart request")
+                        .margin_left(6),
+                ])
+                .margin_above(4),
+            );
+        }
+        if !self.queued_messages.is_empty() {
+            col.push(
+                Widget::row(vec![
+                    self.secondary_line(
+                        ctx,
+                        Line(self.locale.messages_waiting(
+                            self.queued_messages.len(),
+                            self.settings.max_queued_messages,
+                        )),
+                    )
+                    .into_widget(ctx)
+                    .centered_vert(),
+                    ctx.style()
+                        .btn_plain
+                        .text(self.tr(Msg::Discard))
+                        .build_widget(ctx, "discard queued messages")
+                        .margin_left(6),
+                ])
+                .margin_above(4),
+            );
+        }
+
+        if self.attachment.is_some() {
+            col.push(
+                self.secondary_line(ctx, Line(self.tr(Msg::MapViewAttached)))
+                    .into_widget(ctx)
+                    .margin_above(4),
+            );
+        }
+
+        if self.confirming_send {
+            let len = self.current_input().chars().count();
+            col.push(
+                Widget::row(vec![
+                    self.body_line(
+                        ctx,
+                        Line(self.locale.confirm_send(len, estimate_tokens(len))),
+                    )
+                    .into_widget(ctx)
+                    .centered_vert(),
+                    ctx.style()
+                        .btn_solid_primary
+                        .text(self.tr(Msg::SendAnyway))
+                        .build_widget(ctx, "send anyway")
+                        .margin_left(6),
+                    ctx.style()
+                        .btn_plain
+                        .text(self.tr(Msg::Cancel))
+                        .build_widget(ctx, "cancel send")
+                        .margin_left(4),
+                ])
+                .margin_above(4),
+            );
+        }
+
+        if self.confirming_queued_actions {
+            col.push(
+                Widget::col(vec![
+                    self.body_line(
+                        ctx,
+                        Line(self.locale.queued_actions(self.pending_command_count())),
+                    )
+                    .into_widget(ctx),
+                    Widget::row(vec![
+                        ctx.style()
+                            .btn_solid_primary
+                            .text(self.tr(Msg::RunQueuedFirst))
+                            .build_widget(ctx, "run queued actions"),
+                        ctx.style()
+                            .btn_outline
+                            .text(self.tr(Msg::DiscardQueued))
+                            .build_widget(ctx, "discard queued actions")
+                            .margin_left(4),
+                        ctx.style()
+                            .btn_plain
+                            .text(self.tr(Msg::SendAnyway))
+                            .build_widget(ctx, "send with queued actions")
+                            .margin_left(4),
+                        ctx.style()
+                            .btn_plain
+                            .text(self.tr(Msg::Cancel))
+                            .build_widget(ctx, "cancel send")
+                            .margin_left(4),
+                    ])
+                    .margin_above(4),
+                ])
+                .margin_above(4),
+            );
+        }
+
+        let layout = InputLayout::new(
+            ctx.canvas.get_window_dims(),
+            self.width_pct,
+            self.height_pct,
+        );
+        let old = self.panel.maybe_find::<MultilineTextBox>("chat_input");
+        // Rebuilding shouldn't make the player click back into the input
+        let autofocus = std::mem::take(&mut self.focus_input)
+            || old.map(|old| old.has_focus()).unwrap_or(false);
+        let build_input = |dims: ScreenDims| {
+            let mut input = MultilineTextBox::new(
+                "chat_input".to_string(),
+                self.input_prefill.clone(),
+                dims,
+                autofocus,
+            )
+            .readline_keys(self.settings.readline_keys)
+            .placeholder(self.tr(self.settings.send_key.placeholder()));
+            // Keep the caret where it was, unless the text was replaced, like after sending
+            if let Some(old) = old {
+                if old.get_text() == self.input_prefill {
+                    input = input.initial_cursor(old.cursor_char_idx());
+                }
+            }
+            input
+        };
+        // The width decides how lines wrap, so measure at the full size first
+        let input_height = fit_input_height(
+            ctx,
+            &build_input(layout.input_dims),
+            layout.input_dims.height,
+        );
+        let input =
+            build_input(ScreenDims::new(layout.input_dims.width, input_height)).into_widget();
+        self.input_height = input_height;
+        self.input_has_text = !self.current_input().is_empty();
+        self.shown_send_button = self.send_button();
+        let send = ctx
+            .style()
+            .btn_outline
+            .text(self.shown_send_button.label(self.locale))
+            .tooltip(Text::tooltip(
+                ctx,
+                self.settings.send_key.hotkey(),
+                &self
+                    .locale
+                    .send_tooltip(self.tr(self.settings.send_key.hint())),
+            ))
+            .disabled(self.shown_send_button == SendButton::Disabled)
+            .disabled_tooltip(self.tr(Msg::OutOfCredit))
+            .build_widget(ctx, "send");
+        // On small windows, there's no room for the Send button beside the input
+        col.push(if layout.stacked {
+            Widget::col(vec![input, send.margin_above(INPUT_MARGIN)]).margin_above(6)
+        } else {
+            Widget::row(vec![input.margin_right(INPUT_MARGIN), send.centered_vert()])
+                .margin_above(6)
+        });
+        col.push(self.input_size(ctx));
+        col.push(
+            Widget::row(vec![
+                Toggle::choice(
+                    ctx,
+                    "send key",
+                    self.tr(Msg::EnterSends),
+                    self.tr(Msg::CtrlEnterSends),
+                    None,
+                    self.settings.send_key == SendKey::Enter,
+                ),
+                self.secondary_line(ctx, Line(self.tr(self.settings.send_key.hint())))
+                    .into_widget(ctx)
+                    .centered_vert(),
+                ctx.style()
+                    .btn_outline
+                    .text(self.tr(Msg::AskAgain))
+                    .hotkey(self.settings.resend_key.hotkey())
+                    .disabled(
+                        self.pending_rx.is_some()
+                            || !self.alternatives.is_empty()
+                            || self.last_message_from(Role::User).is_none(),
+                    )
+                    .disabled_tooltip(self.tr(Msg::NothingToResend))
+                    .build_widget(ctx, "resend last message")
+                    .margin_left(10),
+            ])
+            .margin_above(4),
+        );
+        col.push(
+            Widget::row(vec![
+                self.secondary_line(ctx, Line(self.tr(Msg::ContextMessages)))
+                    .into_widget(ctx)
+                    .centered_vert()
+                    .margin_right(4),
+                Spinner::widget(
+                    ctx,
+                    "context messages",
+                    (1, MAX_CONTEXT_MESSAGES),
+                    self.settings.context_messages,
+                    1,
+                ),
+                self.checkbox(ctx, "dry run", Msg::DryRun, None, self.settings.dry_run)
+                    .centered_vert()
+                    .margin_left(10),
+                self.checkbox(
+                    ctx,
+                    "attach sim state",
+                    Msg::AttachSimState,
+                    None,
+                    self.settings.attach_sim_state,
+                )
+                .centered_vert()
+                .margin_left(10),
+                self.checkbox(
+                    ctx,
+                    "attach scenario",
+                    Msg::AttachScenario,
+                    None,
+                    self.settings.attach_scenario_params,
+                )
+                .centered_vert()
+                .margin_left(10),
+                self.checkbox(
+                    ctx,
+                    "pause while typing",
+                    Msg::PauseWhileTyping,
+                    None,
+                    self.settings.pause_while_typing,
+                )
+                .centered_vert()
+                .margin_left(10),
+            ])
+            .margin_above(4),
+        );
+        col.push(
+            Widget::row(vec![
+                self.secondary_line(ctx, Line(self.tr(Msg::MaxReplyTokens)))
+                    .into_widget(ctx)
+                    .centered_vert()
+                    .margin_right(4),
+                Spinner::widget(
+                    ctx,
+                    "max reply tokens",
+                    (0, MAX_REPLY_TOKENS),
+                    self.settings.max_tokens.unwrap_or(0),
+                    128,
+                ),
+            ])
+            .margin_above(4),
+        );
+        col.push(
+            Widget::row(vec![
+                self.secondary_line(ctx, Line(self.tr(Msg::ReportStatsEvery)))
+                    .into_widget(ctx)
+                    .centered_vert()
+                    .margin_right(4),
+                Spinner::widget(
+                    ctx,
+                    "auto report minutes",
+                    (0, MAX_AUTO_REPORT_MINUTES),
+                    self.settings.auto_report_minutes,
+                    5,
+                ),
+                self.secondary_line(ctx, Line(self.tr(Msg::AtMost)))
+                    .into_widget(ctx)
+                    .centered_vert()
+                    .margin_left(10)
+                    .margin_right(4),
+                Spinner::widget(
+                    ctx,
+                    "max auto reports",
+                    (1, MAX_AUTO_REPORTS),
+                    self.settings.max_auto_reports.clamp(1, MAX_AUTO_REPORTS),
+                    1,
+                ),
+                self.secondary_line(ctx, Line(self.tr(Msg::InARow)))
+                    .into_widget(ctx)
+                    .centered_vert()
+                    .margin_left(4),
+                self.checkbox(
+                    ctx,
+                    "react to events",
+                    Msg::ReactToEvents,
+                    None,
+                    self.settings.auto_respond_to_events,
+                )
+                .centered_vert()
+                .margin_left(10),
+            ])
+            .margin_above(4),
+        );
+        col.push(
+            Widget::row(vec![
+                self.secondary_line(ctx, Line(self.tr(Msg::FrequencyPenalty)))
+                    .into_widget(ctx)
+                    .centered_vert()
+                    .margin_right(4),
+                Spinner::f64_widget(
+                    ctx,
+                    "frequency penalty",
+                    (-MAX_PENALTY, MAX_PENALTY),
+                    self.settings
+                        .frequency_penalty
+                        .clamp(-MAX_PENALTY, MAX_PENALTY),
+                    0.1,
+                ),
+                self.secondary_line(ctx, Line(self.tr(Msg::PresencePenalty)))
+                    .into_widget(ctx)
+                    .centered_vert()
+                    .margin_left(10)
+                    .margin_right(4),
+                Spinner::f64_widget(
+                    ctx,
+                    "presence penalty",
+                    (-MAX_PENALTY, MAX_PENALTY),
+                    self.settings
+                        .presence_penalty
+                        .clamp(-MAX_PENALTY, MAX_PENALTY),
+                    0.1,
+                ),
+            ])
+            .margin_above(4),
+        );
+        col.push(
+            Widget::row(vec![
+                self.secondary_line(ctx, Line(self.tr(Msg::Position)))
+                    .into_widget(ctx)
+                    .centered_vert()
+                    .margin_right(4),
+                Widget::dropdown(
+                    ctx,
+                    "chat position",
+                    self.settings.position,
+                    ChatPosition::ALL
+                        .into_iter()
+                        .map(|p| Choice::new(self.tr(p.label()), p))
+                        .collect(),
+                ),
+                self.secondary_line(ctx, Line(self.tr(Msg::ActionLines)))
+                    .into_widget(ctx)
+                    .centered_vert()
+                    .margin_left(10)
+                    .margin_right(4),
+                Widget::dropdown(
+                    ctx,
+                    "action lines",
+                    self.settings.action_lines,
+                    ActionLines::ALL
+                        .into_iter()
+                        .map(|a| Choice::new(self.tr(a.label()), a))
+                        .collect(),
+                ),
+            ])
+            .margin_above(4),
+        );
+        if self.settings.show_status_line {
+            col.push(
+                self.secondary_line(ctx, Line(self.status_line()))
+                    .into_widget(ctx)
+                    .margin_above(4),
+            );
+        }
+
+        let (x, y) = self
+            .settings
+            .position
+            .corner(self.width_pct, self.height_pct);
+        self.panel = Panel::new_builder(
+            Widget::col(col)
+                .padding(PANEL_PADDING)
+                .bg(ctx.style().panel_bg),
+        )
+            .aligned_pair((
+                HorizontalAlignment::Percent(x),
+                VerticalAlignment::Percent(y),
+            ))
+            .exact_size_percent(self.width_pct, self.height_pct)
+            .build_custom(ctx);
+        if let Some(input) = self.panel.maybe_find_mut::<MultilineTextBox>("chat_input") {
+            input.set_high_contrast(self.settings.high_contrast);
+        }
+    }
+
+    /// Like "deepseek-chat · temp 0.2 · 3,412 tok"
+    pub fn status_line(&self) -> String {
+        let model = if self.session.backend.is_some() {
+            self.tr(Msg::RecordedReplay).to_string()
+        } else {
+            LlmConfig::from_env()
+                .map(|config| config.model)
+                .unwrap_or_else(|_| self.tr(Msg::NoModelConfigured).to_string())
+        };
+        self.locale.status_line(
+            &model,
+            TEMPERATURE,
+            self.settings.max_tokens,
+            self.session.tokens_used,
+        )
+    }
+
+    /// Click to check again.
+    fn connection_dot(&self, ctx: &mut EventCtx) -> Widget {
+        let radius = 6.0;
+        let batch = GeomBatch::from(vec![(
+            self.connection.color(),
+            Circle::new(Pt2D::new(radius, radius), Distance::meters(radius)).to_polygon(),
+        )]);
+        let bounds = batch.get_bounds();
+        let mut tooltip = Text::from(self.connection.describe(self.locale));
+        if self.connection != ConnectionStatus::Checking {
+            tooltip.add_line(Line(self.tr(Msg::ClickToCheckAgain)).secondary());
+        }
+        ctx.style()
+            .btn_plain
+            .image_batch(batch, bounds)
+            .tooltip(tooltip)
+            .build_widget(ctx, "connection status")
+            .centered_vert()
+    }
+
+    /// Reasoning is collapsed by default, since it's usually long.
+    fn thoughts(&self, ctx: &mut EventCtx, idx: usize, msg: &str) -> Widget {
+        let expanded = self.expanded_thoughts.contains(&idx);
+        let toggle = ctx
+            .style()
+            .btn_plain
+            .text(if expanded {
+                self.tr(Msg::HideThoughts)
+            } else {
+                self.tr(Msg::ShowThoughts)
+            })
+            .build_widget(ctx, format!("toggle thoughts {idx}"));
+        if !expanded {
+            return toggle.margin_above(4);
+        }
+        Widget::col(vec![
+            toggle,
+            Text::from(self.secondary_line(ctx, Line(msg)))
+                .wrap_to_pixels(ctx, self.wrap_width(ctx, 10.0))
+                .into_widget(ctx)
+                .margin_left(10),
+        ])
+        .margin_above(4)
+    }
+
+    /// A reply containing tables. The text around them wraps as usual, but tables stay on one
+    /// line per row, so their columns line up.
+    fn reply_with_tables(&self, ctx: &mut EventCtx, blocks: Vec<ReplyBlock>) -> Widget {
+        let mut col = Vec::new();
+        let mut prefix = self.tr(Msg::LlmPrefix);
+        for block in blocks {
+            match block {
+                ReplyBlock::Text(text) => {
+                    col.push(
+                        Text::from(self.body_line(ctx, Line(format!("{prefix}{text}"))))
+                            .wrap_to_pixels(ctx, self.wrap_width(ctx, 0.0))
+                            .into_widget(ctx),
+                    );
+                }
+                ReplyBlock::Table(table) => {
+                    if !prefix.is_empty() {
+                        col.push(
+                            self.body_line(ctx, Line(prefix.trim_end()))
+                                .into_widget(ctx),
+                        );
+                    }
+                    col.push(
+                        Text::from_multiline(
+                            table
+                                .lines()
+                                .into_iter()
+                                .map(|l| self.body_line(ctx, Line(l).small_monospaced()))
+                                .collect(),
+                        )
+                        .into_widget(ctx)
+                        .margin_above(4),
+                    );
+                }
+            }
+            prefix = "";
+        }
+        Widget::col(col).margin_above(4)
+    }
+
+    /// Lets the player run a reply's actions when they choose, even in dry run, or ask the LLM
+    /// to justify them
+    fn action_buttons(&self, ctx: &mut EventCtx, idx: usize, msg: &str) -> Widget {
+        let steps: Vec<String> = parse_commands(msg)
+            .iter()
+            .flatten()
+            .map(|(cmd, _)| cmd.describe(self.locale))
+            .collect();
+        let mut row = vec![ctx
+            .style()
+            .btn_plain
+            .text(self.tr(Msg::Apply))
+            .tooltip(self.locale.apply_now(&self.locale.steps(&steps)))
+            .disabled(steps.is_empty())
+            .disabled_tooltip(self.tr(Msg::NoRecognizedActions))
+            .build_widget(ctx, format!("apply actions {idx}"))];
+        if !steps.is_empty() {
+            row.push(
+                ctx.style()
+                    .btn_plain
+                    .text(self.tr(Msg::Why))
+                    .tooltip(self.tr(Msg::ExplainActions))
+                    .disabled(self.pending_rx.is_some() || self.out_of_credits)
+                    .disabled_tooltip(self.tr(Msg::WaitForReply))
+                    .build_widget(ctx, format!("explain actions {idx}"))
+                    .margin_left(4),
+            );
+        }
+        Widget::row(row).margin_left(10)
+    }
+
+    /// A collapsed chip under the user message, so players can see exactly what else was sent
+    fn sim_state(&self, ctx: &mut EventCtx, idx: usize, msg: &str) -> Widget {
+        let expanded = self.expanded_thoughts.contains(&idx);
+        let toggle = ctx
+            .style()
+            .btn_plain
+            .text(if expanded {
+                format!("▾ {}", self.tr(Msg::ContextAttached))
+            } else {
+                format!("▸ {}", self.tr(Msg::ContextAttached))
+            })
+            .build_widget(ctx, format!("toggle sim state {idx}"));
+        if !expanded {
+            return toggle.margin_left(10);
+        }
+        Widget::col(vec![
+            toggle,
+            Text::from(self.secondary_line(ctx, Line(msg)))
+                .wrap_to_pixels(ctx, self.wrap_width(ctx, 20.0))
+                .into_widget(ctx)
+                .margin_left(10),
+        ])
+        .margin_left(10)
+    }
+
+    /// Lets the player pin the latest reply, then regenerate it and see both side by side, with
+    /// the words that differ highlighted.
+    fn comparison_section(&self, ctx: &mut EventCtx) -> Option<Widget> {
+        let pinned = match self.pinned_reply {
+            Some(ref pinned) => pinned,
+            None => {
+                self.last_assistant_reply()?;
+                return Some(
+                    ctx.style()
+                        .btn_plain
+                        .text(self.tr(Msg::PinReply))
+                        .build_widget(ctx, "pin reply")
+                        .margin_above(4),
+                );
+            }
+        };
+
+        let mut buttons = Vec::new();
+        if self.pending_rx.is_none() {
+            buttons.push(
+                ctx.style()
+                    .btn_outline
+                    .text(self.tr(Msg::RegenerateToCompare))
+                    .build_widget(ctx, "regenerate to compare"),
+            );
+        }
+        buttons.push(
+            ctx.style()
+                .btn_plain
+                .text(self.tr(Msg::Unpin))
+                .build_widget(ctx, "unpin reply")
+                .margin_left(4),
+        );
+        let mut col = vec![Widget::row(buttons).margin_above(4)];
+
+        let comparison = match self.comparison {
+            Some(ref comparison) => comparison,
+            None => {
+                col.push(
+                    self.secondary_line(ctx, Line(self.tr(Msg::ReplyPinned)))
+                        .into_widget(ctx)
+                        .margin_above(4),
+                );
+                return Some(Widget::col(col));
+            }
+        };
+        let (old, new) = word_diff(pinned, comparison);
+        // Each column has a margin on its right
+        let width = self.wrap_width(ctx, 20.0) / 2.0;
+        let mut columns = Vec::new();
+        for (title, words) in [(Msg::Pinned, old), (Msg::Regenerated, new)] {
+            let mut txt = Text::from(self.secondary_line(ctx, Line(self.tr(title))));
+            txt.add_line(Line(""));
+            for (word, changed) in words {
+                let line = self.body_line(ctx, Line(format!("{word} ")));
+                txt.append(if changed {
+                    line.fg(ctx.style().text_hotkey_color)
+                } else {
+                    line
+                });
+            }
+            columns.push(
+                txt.wrap_to_pixels(ctx, width)
+                    .into_widget(ctx)
+                    .margin_right(10),
+            );
+        }
+        col.push(Widget::row(columns).margin_above(4));
+        Some(Widget::col(col))
+    }
+
+    /// How big the next request will be, so players can keep an eye on the context window
+    fn input_size(&self, ctx: &mut EventCtx) -> Widget {
+        let window = &self.session.messages
+            [context_window_start(&self.session.messages, self.settings.context_messages)..];
+        let context_chars = system_prompt(&self.session.context).chars().count()
+            + self.settings.custom_prompt.chars().count()
+            + self
+                .scenario_block()
+                .map_or(0, |block| block.chars().count())
+            + window
+                .iter()
+                .filter(|(role, _)| !matches!(role, Role::Thoughts))
+                .map(|(_, msg)| msg.chars().count())
+                .sum::<usize>();
+        self.secondary_line(
+            ctx,
+            Line(describe_prompt_size(
+                &self.input_prefill,
+                context_chars,
+                self.locale,
+            )),
+        )
+        .into_widget(ctx)
+        .margin_above(2)
+        .named("input size")
+    }
+
+    /// Empty until a streamed reply starts arriving
+    fn partial_reply_widget(&self, ctx: &mut EventCtx) -> Widget {
+        match self.partial_reply {
+            Some(ref partial) => Text::from(
+                self.body_line(ctx, Line(format!("{}{partial}▌", self.tr(Msg::LlmPrefix)))),
+            )
+            .wrap_to_pixels(ctx, self.wrap_width(ctx, 0.0))
+            .into_widget(ctx)
+            .margin_above(4),
+            None => Widget::nothing(),
+        }
+        .named("partial reply")
+    }
+
+    /// Stops waiting for the current reply. Whatever was streamed so far is kept, marked as
+    /// cancelled, and its commands are never run.
+    fn cancel_request(&mut self) {
+        // Dropping the receiver makes the worker close the connection when the next chunk arrives
+        self.pending_rx = None;
+        if let Some(req) = self.inflight.take() {
+            for (res, stale) in self.reply_order.cancel(req.seq) {
+                self.add_result(res, stale);
+            }
+        }
+        self.waiting_secs = 0;
+        self.comparing = false;
+        finish_cancelled(
+            &mut self.session.messages,
+            self.partial_reply.take(),
+            self.locale,
+        );
+        self.save();
+    }
+
+    fn resume_choice_row(&self, ctx: &mut EventCtx, choice: &ResumeChoice) -> Widget {
+        let context = match choice.saved {
+            Some(ref saved) => &saved.context,
+            None => &self.session.context,
+        };
+        let about = self
+            .locale
+            .scenario_on_map(&context.scenario, &context.map.describe());
+        let age = choice.age.map(|age| describe_age(age, self.locale));
+        let msg = if choice.saved.is_none() {
+            self.locale.continued_conversation(&about, age.as_deref())
+        } else {
+            self.locale.started_new_conversation(&about, age.as_deref())
+        };
+        Widget::col(vec![
+            self.body_line(ctx, Line(msg)).into_widget(ctx),
+            Widget::row(vec![
+                ctx.style()
+                    .btn_outline
+                    .text(self.tr(Msg::ContinueSession))
+                    .disabled(choice.saved.is_none())
+                    .build_widget(ctx, "continue conversation"),
+                ctx.style()
+                    .btn_outline
+                    .text(self.tr(Msg::NewConversation))
+                    .tooltip(self.tr(Msg::ReplacesSavedConversation))
+                    .disabled(choice.saved.is_some())
+                    .build_widget(ctx, "new conversation")
+                    .margin_left(4),
+            ])
+            .margin_above(4),
+        ])
+        .margin_above(4)
+    }
+
+    fn waiting_status(&self, ctx: &mut EventCtx) -> Widget {
+        let msg = if self.waiting_secs == 0 {
+            self.tr(Msg::Waiting).to_string()
+        } else {
+            format!("{} {}s", self.tr(Msg::Waiting), self.waiting_secs)
+        };
+        Widget::row(vec![
+            self.secondary_line(ctx, Line(msg))
+                .into_widget(ctx)
+                .centered_vert(),
+            ctx.style()
+                .btn_plain
+                .text(self.tr(Msg::Stop))
+                .build_widget(ctx, "cancel request")
+                .margin_left(10),
+        ])
+        .margin_above(4)
+        .named("waiting status")
+    }
+
+    fn templates_section(&self, ctx: &mut EventCtx) -> Widget {
+        let mut col = Vec::new();
+        for (idx, template) in self.templates.all().into_iter().enumerate() {
+            let mut row = vec![ctx
+                .style()
+                .btn_outline
+                .text(template.name)
+                .build_widget(ctx, format!("insert template {idx}"))];
+            if idx > 0 {
+                row.push(
+                    ctx.style()
+                        .btn_plain
+                        .text(self.tr(Msg::Delete))
+                        .build_widget(ctx, format!("delete template {idx}"))
+                        .margin_left(4),
+                );
+            }
+            col.push(Widget::row(row).centered_vert().margin_above(4));
+        }
+        col.push(
+            ctx.style()
+                .btn_outline
+                .text(self.tr(Msg::SaveTemplate))
+                .build_widget(ctx, "save template")
+                .margin_above(4),
+        );
+
+        // Instructions asking for each action, for writing prompts that get commands back
+        let mut examples = vec![self
+            .secondary_line(ctx, Line(self.tr(Msg::AskForActions)))
+            .into_widget(ctx)
+            .centered_vert()];
+        for (idx, example) in ACTION_EXAMPLES.iter().enumerate() {
+            examples.push(
+                ctx.style()
+                    .btn_plain
+                    .text(example.syntax)
+                    .tooltip(example.instruction())
+                    .build_widget(ctx, format!("insert example action {idx}"))
+                    .margin_left(4),
+            );
+        }
+        col.push(Widget::row(examples).margin_above(4));
+        Widget::col(col).margin_above(6)
+    }
+
+    /// Types `text` at the input's caret, or at the end of the draft if the input isn't showing.
+    fn insert_into_input(&mut self, ctx: &mut EventCtx, text: &str) {
+        if let Some(input) = self.panel.maybe_find_mut::<MultilineTextBox>("chat_input") {
+            input.insert_at_cursor(text);
+        } else {
+            self.input_prefill.push_str(text);
+            self.rebuild_panel(ctx);
+        }
+    }
+
+    /// A checkbox whose label is translated. `action` is the widget's name, which stays the same
+    /// in every language.
+    fn checkbox<MK: Into<Option<MultiKey>>>(
+        &self,
+        ctx: &EventCtx,
+        action: &str,
+        label: Msg,
+        hotkey: MK,
+        enabled: bool,
+    ) -> Widget {
+        Toggle::custom_checkbox(ctx, action, vec![Line(self.tr(label))], hotkey, enabled)
+    }
+
+    /// Styles transcript text, respecting the high-contrast setting.
+    fn body_line(&self, ctx: &EventCtx, line: TextSpan) -> TextSpan {
+        if self.settings.high_contrast {
+            line.fg(ctx.style().text_primary_color)
+                .size(HIGH_CONTRAST_FONT_SIZE)
+        } else {
+            line
+        }
+    }
+
+    fn search_row(&self, ctx: &mut EventCtx) -> Widget {
+        let old = self
+            .panel
+            .maybe_find::<MultilineTextBox>("transcript search");
+        let mut search = MultilineTextBox::new(
+            "transcript search".to_string(),
+            self.search.clone(),
+            ScreenDims::new(180.0, MIN_INPUT_HEIGHT),
+            old.map(|old| old.has_focus()).unwrap_or(false),
+        )
+        .single_line(ctx);
+        if let Some(old) = old {
+            search = search.initial_cursor(old.cursor_char_idx());
+        }
+        let status = if self.search.is_empty() {
+            self.tr(Msg::SearchTranscript).to_string()
+        } else {
+            let matches = self.search_matches();
+            match self
+                .search_match
+                .and_then(|idx| matches.iter().position(|m| *m == idx))
+            {
+                Some(n) => self.locale.search_match(n + 1, matches.len()),
+                None => self.locale.matching_messages(matches.len()),
+            }
+        };
+        Widget::row(vec![
+            search.into_widget().margin_right(6),
+            self.checkbox(
+                ctx,
+                "match case",
+                Msg::MatchCase,
+                None,
+                self.search_case_sensitive,
+            )
+            .centered_vert()
+            .margin_right(6),
+            self.checkbox(ctx, "select text", Msg::SelectText, None, self.select_text)
+                .centered_vert()
+                .margin_right(6),
+            self.secondary_line(ctx, Line(status))
+                .into_widget(ctx)
+                .centered_vert(),
+        ])
+        .margin_above(4)
+    }
+
+    /// Indices into `messages` of everything containing the search text, oldest first
+    fn search_matches(&self) -> Vec<usize> {
+        self.session
+            .messages
+            .iter()
+            .enumerate()
+            .filter(|(_, (role, msg))| {
+                !matches!(role, Role::Thoughts | Role::SimState)
+                    && !find_in(msg, &self.search, self.search_case_sensitive).is_empty()
+            })
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    /// Moves to the next older or newer match, wrapping around, and scrolls the transcript to show
+    /// it. With no match selected yet, this starts from the newest.
+    fn jump_to_match(&mut self, older: bool) {
+        self.search_match = step_match(&self.search_matches(), self.search_match, older);
+        if let Some(idx) = self.search_match {
+            self.scroll_back = scroll_back_to_show(
+                self.session.messages.len(),
+                idx,
+                self.visible_messages(),
+                self.scroll_back,
+            );
+        }
+    }
+
+    /// Styles less important text, which is dimmed unless high contrast is on.
+    fn secondary_line(&self, ctx: &EventCtx, line: TextSpan) -> TextSpan {
+        if self.settings.high_contrast {
+            self.body_line(ctx, line)
+        } else {
+            line.secondary()
+        }
+    }
+
+    /// Swaps out one part of the panel without disturbing the input box. If the panel doesn't have
+    /// that part right now, it's rebuilt instead.
+    fn replace_widget(&mut self, ctx: &mut EventCtx, name: &str, widget: Widget) {
+        if self.panel.has_widget(name) {
+            self.panel.replace(ctx, name, widget);
+        } else {
+            self.rebuild_panel(ctx);
+        }
+    }
+
+    /// Copies the input box's text, so a rebuild doesn't lose it. The panel might not have the
+    /// input yet, so this and `current_input` fall back to the local copy.
+    fn sync_input(&mut self) {
+        if let Some(input) = self.panel.maybe_find::<MultilineTextBox>("chat_input") {
+            self.input_prefill = input.get_text();
+        }
+    }
+
+    fn current_input(&self) -> String {
+        match self.panel.maybe_find::<MultilineTextBox>("chat_input") {
+            Some(input) => normalize_message(&input.get_text()),
+            None => normalize_message(&self.input_prefill),
+        }
+    }
+
+    fn tr(&self, msg: Msg) -> &'static str {
+        msg.text(self.locale)
+    }
+
+    /// How wide wrapped text can be, in pixels, when it's indented by `indent`
+    fn wrap_width(&self, ctx: &EventCtx, indent: f64) -> f64 {
+        (content_width(ctx.canvas.get_window_dims(), self.width_pct) - indent).max(1.0)
+    }
+
+    fn send_button(&self) -> SendButton {
+        SendButton::new(
+            self.out_of_credits,
+            self.pending_rx.is_some(),
+            !self.alternatives.is_empty(),
+            self.queued_messages.len(),
+            self.input_has_text,
+        )
+    }
+
+    fn request_settings(&self) -> RequestSettings {
+        RequestSettings {
+            context_messages: self.settings.context_messages,
+            seed: self.seed,
+            custom_prompt: self.settings.custom_prompt.clone(),
+            scenario_params: self.scenario_block(),
+            stop: parse_stop_sequences(&self.settings.stop_sequences),
+            max_tokens: self.settings.max_tokens,
+            frequency_penalty: penalty(self.settings.frequency_penalty),
+            presence_penalty: penalty(self.settings.presence_penalty),
+        }
+    }
+
+    /// The current quota changes without the scenario changing, so it's filled in here.
+    fn scenario_block(&self) -> Option<String> {
+        if !self.settings.attach_scenario_params {
+            return None;
+        }
+        let mut params = self.scenario_params.clone()?;
+        params.ride_hail_quota = self.ride_hail_quota;
+        params.quota_range = self.settings.ride_hail_quota_range;
+        Some(params.to_block())
+    }
+
+    fn visible_messages(&self) -> usize {
+        self.settings.visible_messages.max(1)
+    }
+
+    /// Long messages wait for confirmation first, if the player asked for that.
+    pub fn send(&mut self, ctx: &mut EventCtx) {
+        let input = self.current_input();
+        if needs_send_confirmation(self.settings.confirm_send_above, &input) {
+            if !self.confirming_send {
+                self.confirming_send = true;
+                self.rebuild_panel(ctx);
+            }
+            return;
+        }
+        self.check_queued_actions(ctx, input);
+    }
+
+    /// Actions from an earlier reply that haven't run yet might not fit the new message. Depending
+    /// on the setting, this mentions them, or holds the message until the player decides.
+    fn check_queued_actions(&mut self, ctx: &mut EventCtx, input: String) {
+        let queued = self.pending_command_count();
+        if queued > 0 && !input.is_empty() && !self.out_of_credits {
+            match self.settings.queued_actions_on_send {
+                QueuedActionsPolicy::Ignore => {}
+                QueuedActionsPolicy::Notice => {
+                    self.session.messages.push((
+                        Role::System,
+                        format!(
+                            "{} {}",
+                            self.locale.queued_actions(queued),
+                            self.tr(Msg::ClearStaleQueue)
+                        ),
+                    ));
+                }
+                QueuedActionsPolicy::Ask => {
+                    if !self.confirming_queued_actions {
+                        self.confirming_queued_actions = true;
+                        self.rebuild_panel(ctx);
+                    }
+                    return;
+                }
+            }
+        }
+        self.dispatch(ctx, input);
+    }
+
+    /// Sends what was typed in the input box, or queues it if a request is already in flight.
+    fn dispatch(&mut self, ctx: &mut EventCtx, input: String) {
+        // Leave the message in the input box for later
+        if input.is_empty() || self.out_of_credits {
+            return;
+        }
+        if self.pending_rx.is_some() || !self.alternatives.is_empty() {
+            self.queue_message(ctx, input);
+            return;
+        }
+        self.clear_input();
+        self.submit(ctx, input);
+    }
+
+    fn clear_input(&mut self) {
+        self.input_prefill.clear();
+        self.unsaved_draft_since = None;
+        SavedDraft::clear();
+    }
+
+    /// Starts a new turn with a user message. Nothing else can be in flight. The input box isn't
+    /// touched, since the message might not have come from it.
+    fn submit(&mut self, ctx: &mut EventCtx, input: String) {
+        if let Some(ref mut callback) = self.on_submit {
+            callback(&input);
+        }
+        // The player is paying attention again
+        self.auto_report.sent = 0;
+        if let Some(snapshot) = self.sim_snapshot.filter(|_| self.settings.attach_sim_state) {
+            self.session.set_sim_snapshot(snapshot);
+        }
+        let history = self.session.push_user_message(&input);
+        self.alternatives.clear();
+        self.auto_corrected = false;
+        self.save();
+        self.scroll_back = 0;
+        // Start first, so the panel shows the request in flight
+        let image = self.attachment.take().filter(|_| {
+            let vision = LlmConfig::from_env()
+                .map(|config| config.vision)
+                .unwrap_or(false);
+            if !vision {
+                self.session
+                    .messages
+                    .push((Role::System, self.tr(Msg::VisionUnsupported).to_string()));
+            }
+            vision
+        });
+        self.start_request(history, image);
+        self.rebuild_panel(ctx);
+    }
+
+    /// Holds onto a message until the inflight request finishes, within the player's limit.
+    fn queue_message(&mut self, ctx: &mut EventCtx, input: String) {
+        let max = self.settings.max_queued_messages;
+        match enqueue_bounded(
+            &mut self.queued_messages,
+            input,
+            max,
+            self.settings.queue_full_policy,
+        ) {
+            Enqueued::Added => {}
+            Enqueued::Rejected => {
+                // Leave the message in the input box, so it isn't lost
+                self.session
+                    .messages
+                    .push((Role::System, self.locale.queue_full(max)));
+                self.rebuild_panel(ctx);
+                return;
+            }
+            Enqueued::DroppedOldest(dropped) => {
+                self.session.messages.push((
+                    Role::System,
+                    self.locale.dropped_oldest(max, &template_name(&dropped)),
+                ));
+            }
+        }
+        self.clear_input();
+        self.rebuild_panel(ctx);
+    }
+
+    /// Sends the oldest queued message, once nothing else is in flight or waiting on the player.
+    fn send_next_queued(&mut self, ctx: &mut EventCtx) {
+        if self.pending_rx.is_some() || !self.alternatives.is_empty() || self.out_of_credits {
+            return;
+        }
+        if let Some(input) = self.queued_messages.pop_front() {
+            self.submit(ctx, input);
+        }
+    }
+
+    /// Sends the last user message again as a new turn, so the LLM answers with whatever changed
+    /// since. Unlike regenerating, the earlier reply stays in the conversation.
+    fn resend_last(&mut self, ctx: &mut EventCtx) {
+        if self.pending_rx.is_some() || !self.alternatives.is_empty() || self.out_of_credits {
+            return;
+        }
+        if let Some(input) = self.last_message_from(Role::User) {
+            self.submit(ctx, input);
+        }
+    }
+
+    fn last_message_from(&self, role: Role) -> Option<String> {
+        last_message(&self.session.messages, role).map(|msg| msg.to_string())
+    }
+
+    /// Asks again for a reply to the last user message, as if the last reply never happened.
+    fn regenerate_for_comparison(&mut self) {
+        if self.pending_rx.is_some() {
+            return;
+        }
+        let idx = match self
+            .messages
+            .iter()
+            .rposition(|(role, _)| *role == Role::User)
+        {
+            Some(idx) => idx,
+            None => return,
+        };
+        self.comparing = true;
+        self.comparison = None;
+        self.start_request(self.session.messages[..=idx].to_vec(), None);
+    }
+
+    /// Only the reply actually added to the conversation gets its commands run.
+    ///
+    /// In dry run mode, the commands are only described in the transcript.
+    pub fn add_reply(&mut self, reply: LlmReply) {
+        let problems = malformed_commands(&reply.content, self.locale);
+        // The LLM is asked to fix them in English, like the rest of its prompt
+        let correction = malformed_commands(&reply.content, Locale::English);
+        let batches = self.session.add_reply(self.archived, reply);
+        for problem in &problems {
+            self.session.messages.push((Role::System, problem.clone()));
+        }
+        // Only once, so a model that keeps getting it wrong can't loop forever
+        if !problems.is_empty() && self.settings.auto_correct_commands && !self.auto_corrected {
+            self.auto_corrected = true;
+            self.start_request(
+                self.history_with_prompt(format!(
+                    "Some actions in your last reply were invalid: {} Reply again with valid \
+                     ACTION lines.",
+                    correction.join(" ")
+                )),
+                None,
+            );
+        }
+        if self.settings.dry_run {
+            for msg in dry_run_messages(&batches, self.locale) {
+                self.session.messages.push((Role::System, msg));
+            }
+            return;
+        }
+        if let Some(ref mut callback) = self.on_command {
+            for (cmd, _) in batches.iter().flatten() {
+                callback(cmd);
+            }
+        }
+        self.session.commands.extend(batches);
+    }
+
+    /// Puts a saved conversation before whatever the chatbox has said since opening.
+    fn load_saved(&mut self, saved: SavedConversation) {
+        let mut messages = saved.messages;
+        // The new conversation was greeted, but the saved one already was
+        self.session
+            .messages
+            .retain(|(role, msg)| !is_greeting(role, msg));
+        messages.append(&mut self.session.messages);
+        self.session.messages = messages;
+        self.session.context = saved.context;
+        self.archived = saved.archived;
+        self.seed = saved.seed;
+        self.session.tokens_used = saved.tokens_used;
+        self.expanded_thoughts.clear();
+        self.search_match = None;
+        self.scroll_back = 0;
+    }
+
+    /// Drops the first `loaded` messages, which came from the saved conversation, and replaces it
+    /// on disk.
+    fn start_fresh(&mut self, context: ChatContext, loaded: usize) {
+        self.session
+            .messages
+            .drain(..loaded.min(self.session.messages.len()));
+        greet(&mut self.session.messages, true, self.locale);
+        self.session.context = context;
+        self.archived = 0;
+        self.seed = None;
+        self.session.tokens_used = 0;
+        self.expanded_thoughts.clear();
+        self.search_match = None;
+        self.scroll_back = 0;
+        abstio::delete_file(TranscriptArchive::path());
+        self.write_conversation();
+    }
+
+    /// Queues actions the player chose to apply from a reply, ahead of anything else.
+    fn apply_manually(&mut self, ctx: &mut EventCtx, batches: Vec<Vec<SourcedCommand>>) {
+        let steps: Vec<String> = batches
+            .iter()
+            .flatten()
+            .map(|(cmd, _)| cmd.describe(self.locale))
+            .collect();
+        if steps.is_empty() {
+            return;
+        }
+        if let Some(ref mut callback) = self.on_command {
+            for (cmd, _) in batches.iter().flatten() {
+                callback(cmd);
+            }
+        }
+        self.manual_commands.extend(batches);
+        self.add_system_message(ctx, self.locale.applying(&self.locale.steps(&steps)));
+    }
+
+    /// Also moves old messages to the archive, if the transcript has grown too long.
+    fn save(&mut self) {
+        // The saved conversation is about to be overwritten, so its archive goes too
+        if let Some(choice) = self.resume_choice.take() {
+            if choice.saved.is_some() {
+                abstio::delete_file(TranscriptArchive::path());
+            }
+        }
+        let spilled = spill_old_messages(
+            &mut self.session.messages,
+            self.settings.max_messages_in_memory,
+            self.visible_messages(),
+        );
+        if !spilled.is_empty() {
+            let spilled_len = spilled.len();
+            let mut archive = TranscriptArchive::load();
+            archive.messages.extend(spilled);
+            archive.save();
+            self.archived = archive.messages.len();
+            self.expanded_thoughts = self
+                .expanded_thoughts
+                .iter()
+                .filter_map(|idx| idx.checked_sub(spilled_len))
+                .collect();
+            self.search_match = self
+                .search_match
+                .and_then(|idx| idx.checked_sub(spilled_len));
+            self.scroll_back = self.scroll_back.min(
+                self.session
+                    .messages
+                    .len()
+                    .saturating_sub(self.visible_messages()),
+            );
+        }
+        self.write_conversation();
+    }
+
+    fn write_conversation(&self) {
+        abstio::write_json(
+            SavedConversation::path(),
+            &SavedConversation {
+                context: self.session.context.clone(),
+                messages: self.session.messages.clone(),
+                archived: self.archived,
+                seed: self.seed,
+                tokens_used: self.session.tokens_used,
+            },
+        );
+    }
+
+    /// Moves the most recent batch of archived messages back into memory. They'll be spilled again
+    /// the next time the transcript is saved, once the player moves on.
+    fn load_earlier_messages(&mut self) {
+        let mut archive = TranscriptArchive::load();
+        let split = archive.messages.len().saturating_sub(LOAD_EARLIER_BATCH);
+        let mut messages = archive.messages.split_off(split);
+        self.expanded_thoughts = self
+            .expanded_thoughts
+            .iter()
+            .map(|idx| idx + messages.len())
+            .collect();
+        messages.append(&mut self.session.messages);
+        self.session.messages = messages;
+        archive.save();
+        self.archived = archive.messages.len();
+        self.write_conversation();
+    }
+
+    fn start_health_check(&mut self) {
+        if self.session.backend.is_some() {
+            self.connection = ConnectionStatus::Ok;
+            return;
+        }
+        let (tx, rx) = mpsc::channel();
+        self.health_rx = Some(rx);
+        self.connection = ConnectionStatus::Checking;
+        let locale = self.locale;
+        std::thread::spawn(move || {
+            let status = match LlmConfig::from_env() {
+                Ok(config) => check_connection(&config, locale),
+                Err(err) => ConnectionStatus::Failed(format!("{err:#}")),
+            };
+            let _ = tx.send(status);
+        });
+    }
+
+    /// The conversation so far, followed by a prompt the chatbox wrote itself. The prompt is only
+    /// sent, not added to the transcript.
+    fn history_with_prompt(&self, prompt: String) -> Vec<(Role, String)> {
+        let mut history = self.session.messages.clone();
+        history.push((Role::User, prompt));
+        history
+    }
+
+    /// `history` ends with the message to send. `image` is the path to a PNG file sent along with
+    /// it.
+    fn start_request(&mut self, history: Vec<(Role, String)>, image: Option<String>) {
+        let context = self.session.context.clone();
+        let settings = self.request_settings();
+        self.inflight = Some(InflightRequest {
+            seq: self.reply_order.issue(),
+            history: history.clone(),
+            image: image.clone(),
+            settings: settings.clone(),
+        });
+        let (tx, rx) = mpsc::channel();
+        self.pending_rx = Some(rx);
+        self.waiting_secs = 0;
+        if let Some(ref mut backend) = self.session.backend {
+            // Picked up by poll_pending like any other result
+            let res = backend.fetch(context, history, None, settings, None);
+            let _ = tx.send(WorkerMsg::Done(res));
+            return;
+        }
+        let stream = self.settings.stream_replies;
+        let chunk_tx = tx.clone();
+        std::thread::spawn(move || {
+            run_with_heartbeats(tx, HEARTBEAT_PERIOD, move || {
+                let config = LlmConfig::from_env()?;
+                let image = match image {
+                    Some(path) => Some(fs_err::read(path)?),
+                    None => None,
+                };
+                // Sending fails once the chatbox stops listening, like after a cancel
+                let on_chunk =
+                    |text: &str| chunk_tx.send(WorkerMsg::Chunk(text.to_string())).is_ok();
+                config.fetch(
+                    context,
+                    history,
+                    image,
+                    settings,
+                    if stream { Some(&on_chunk) } else { None },
+                )
+            });
+        });
+    }
+}
+
+// Hooks and accessors for code embedding the chatbox. The sandbox itself doesn't use them.
+impl Chatbox {
+    /// Calls this with every message the user sends, after whitespace is cleaned up. Like all
+    /// chatbox callbacks, it runs on the UI thread, so it should be quick.
+    #[allow(dead_code)]
+    pub fn on_submit(mut self, callback: Box<dyn FnMut(&str)>) -> Self {
+        self.on_submit = Some(callback);
+        self
+    }
+
+    /// Calls this with every command parsed from the reply the user keeps, as soon as it's
+    /// queued, not when the sandbox applies it. This runs on the UI thread.
+    #[allow(dead_code)]
+    pub fn on_command(mut self, callback: Box<dyn FnMut(&ChatCommand)>) -> Self {
+        self.on_command = Some(callback);
+        self
+    }
+
+    /// The most recent reply from the LLM, or `None` before there's been one. Messages moved to
+    /// the on-disk archive aren't checked.
+    pub fn last_assistant_reply(&self) -> Option<&str> {
+        last_message(&self.session.messages, Role::Assistant)
+    }
+
+    /// The most recent message the user sent, or `None` before there's been one. Messages moved
+    /// to the on-disk archive aren't checked.
+    #[allow(dead_code)]
+    pub fn last_user_message(&self) -> Option<&str> {
+        last_message(&self.session.messages, Role::User)
+    }
+
+    /// How many messages of the transcript are shown at once. At least one always is.
+    #[allow(dead_code)]
+    pub fn set_visible_messages(&mut self, ctx: &mut EventCtx, n: usize) {
+        self.settings.visible_messages = n;
+        self.settings.save();
+        self.rebuild_panel(ctx);
+    }
+
+    /// Fixes the seed for the rest of this conversation, to rerun an earlier experiment. Only
+    /// some providers honor it; see `RequestSettings::seed`.
+    #[allow(dead_code)]
+    pub fn set_seed(&mut self, ctx: &mut EventCtx, seed: Option<u64>) {
+        self.seed = seed;
+        self.save();
+        self.rebuild_panel(ctx);
+    }
+
+    /// True while the player is typing in the input box, so that code embedding the chatbox can
+    /// hold off on its own keyboard shortcuts.
+    #[allow(dead_code)]
+    pub fn is_editing(&self) -> bool {
+        self.editing
+    }
+}
+
+/// Marks the words of each reply that aren't part of their longest common sequence of words.
+fn word_diff<'a>(a: &'a str, b: &'a str) -> (Vec<(&'a str, bool)>, Vec<(&'a str, bool)>) {
+    let a: Vec<&str> = a.split_whitespace().collect();
+    let b: Vec<&str> = b.split_whitespace().collect();
+    // common[i][j] is how many words a[i..] and b[j..] have in common
+    let mut common = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            common[i][j] = if a[i] == b[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut a_out = Vec::new();
+    let mut b_out = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            a_out.push((a[i], false));
+            b_out.push((b[j], false));
+            i += 1;
+            j += 1;
+        } else if common[i + 1][j] >= common[i][j + 1] {
+            a_out.push((a[i], true));
+            i += 1;
+        } else {
+            b_out.push((b[j], true));
+            j += 1;
+        }
+    }
+    a_out.extend(a[i..].iter().map(|word| (*word, true)));
+    b_out.extend(b[j..].iter().map(|word| (*word, true)));
+    (a_out, b_out)
+}
+
+/// What happened to a message offered to a bounded queue
+#[derive(Debug, PartialEq)]
+enum Enqueued {
+    Added,
+    /// The queue was full, so the new message wasn't added
+    Rejected,
+    /// The queue was full, so this oldest message was removed to make room
+    DroppedOldest(String),
+}
+
+fn enqueue_bounded(
+    queue: &mut VecDeque<String>,
+    msg: String,
+    max: usize,
+    policy: QueueFullPolicy,
+) -> Enqueued {
+    if queue.len() < max {
+        queue.push_back(msg);
+        return Enqueued::Added;
+    }
+    if policy == QueueFullPolicy::DropOldest {
+        // With no room at all, there's nothing older to drop
+        if let Some(oldest) = queue.pop_front() {
+            queue.push_back(msg);
+            return Enqueued::DroppedOldest(oldest);
+        }
+    }
+    Enqueued::Rejected
+}
+
+/// When to send the next automatic report, by the sim's clock
+#[derive(Default)]
+struct AutoReport {
+    next: Option<Time>,
+    /// How many went out since the player last sent something
+    sent: usize,
+}
+
+impl AutoReport {
+    /// True once a full interval has passed since the last report, or since reports were turned
+    /// on. Starts counting over if the sim's clock goes backwards, like after a reset.
+    fn due(&mut self, interval: Option<Duration>, now: Time) -> bool {
+        let interval = match interval {
+            Some(interval) => interval,
+            None => {
+                self.next = None;
+                return false;
+            }
+        };
+        match self.next {
+            Some(next) if now >= next => {
+                self.next = Some(now + interval);
+                true
+            }
+            Some(next) if now + interval >= next => false,
+            _ => {
+                self.next = Some(now + interval);
+                false
+            }
+        }
+    }
+}
+
+fn can_auto_respond(enabled: bool, last: Option<Instant>, now: Instant) -> bool {
+    enabled
+        && last
+            .map(|last| now.saturating_duration_since(last) >= AUTO_RESPOND_COOLDOWN)
+            .unwrap_or(true)
+}
+
+fn needs_send_confirmation(threshold: Option<usize>, msg: &str) -> bool {
+    threshold
+        .map(|max| msg.chars().count() > max)
+        .unwrap_or(false)
+}
+
+/// How tall the input box should be to fit its text, wrapped at its current width, without
+/// scrolling. It never shrinks below `MIN_INPUT_LINES` or grows past `max`.
+fn fit_input_height(ctx: &EventCtx, input: &MultilineTextBox, max: f64) -> f64 {
+    let lines = input.wrapped_line_count(ctx).max(MIN_INPUT_LINES);
+    input
+        .height_for_lines(ctx, lines)
+        .max(MIN_INPUT_HEIGHT)
+        .min(max)
+}
+
+/// A rough guess of how many tokens some text uses, from its length in characters. Real
+/// tokenizers average about 4 characters of English per token.
+fn estimate_tokens(chars: usize) -> usize {
+    (chars + 3) / 4
+}
+
+/// Summarizes the typed message and the context sent along with it, like "12 words, about 20
+/// tokens, plus about 300 tokens of context"
+fn describe_prompt_size(input: &str, context_chars: usize, locale: Locale) -> String {
+    locale.prompt_size(
+        input.split_whitespace().count(),
+        estimate_tokens(input.chars().count()),
+        estimate_tokens(context_chars),
+    )
+}
+
+/// The range of messages to show, given how far back the transcript is scrolled
+fn visible_range(len: usize, scroll_back: usize, visible: usize) -> (usize, usize) {
+    let end = len.saturating_sub(scroll_back);
+    (end.saturating_sub(visible), end)
+}
+
+/// A piece of an LLM reply, as it's drawn in the transcript
+#[derive(Debug, PartialEq)]
+enum ReplyBlock {
+    Text(String),
+    Table(Table),
+}
+
+/// A Markdown-style table. Every row has the same number of cells, and the first is the header.
+#[derive(Debug, PartialEq)]
+struct Table {
+    rows: Vec<Vec<String>>,
+    /// Per column, from a `---:` separator
+    right_aligned: Vec<bool>,
+}
+
+impl Table {
+    /// Each row padded to the widest cell of its column, for a monospaced font
+    pub fn lines(&self) -> Vec<String> {
+        let widths: Vec<usize> = (0..self.right_aligned.len())
+            .map(|c| {
+                self.rows
+                    .iter()
+                    .map(|row| row[c].chars().count())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+        let pad = |row: &Vec<String>| {
+            row.iter()
+                .zip(&widths)
+                .zip(&self.right_aligned)
+                .map(|((cell, w), right)| {
+                    if *right {
+                        format!("{cell:>w$}")
+                    } else {
+                        format!("{cell:<w$}")
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" | ")
+                .trim_end()
+                .to_string()
+        };
+        let mut lines = vec![pad(&self.rows[0])];
+        lines.push(
+            widths
+                .iter()
+                .map(|w| "-".repeat(*w))
+                .collect::<Vec<_>>()
+                .join("-+-"),
+        );
+        lines.extend(self.rows[1..].iter().map(pad));
+        lines
+    }
+}
+
+/// Splits a reply into plain text and tables. Anything that doesn't parse cleanly as a table,
+/// like rows with a different number of cells than the header, stays as text.
+fn reply_blocks(msg: &str) -> Vec<ReplyBlock> {
+    let lines: Vec<&str> = msg.lines().collect();
+    let mut blocks = Vec::new();
+    let mut text: Vec<&str> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        if let Some((table, used)) = parse_table(&lines[i..]) {
+            let before = text.join("\n");
+            if !before.trim().is_empty() {
+                blocks.push(ReplyBlock::Text(before.trim().to_string()));
+            }
+            text.clear();
+            blocks.push(ReplyBlock::Table(table));
+            i += used;
+        } else {
+            text.push(lines[i]);
+            i += 1;
+        }
+    }
+    let after = text.join("\n");
+    if !after.trim().is_empty() {
+        blocks.push(ReplyBlock::Text(after.trim().to_string()));
+    }
+    blocks
+}
+
+/// A table starting at the first line, and how many lines it takes up
+fn parse_table(lines: &[&str]) -> Option<(Table, usize)> {
+    let header = table_cells(lines.first()?)?;
+    let separator = table_cells(lines.get(1)?)?;
+    if separator.len() != header.len()
+        || !separator.iter().all(|cell| {
+            let dashes = cell.trim_matches(':');
+            !dashes.is_empty() && dashes.chars().all(|c| c == '-')
+        })
+    {
+        return None;
+    }
+    let right_aligned = separator
+        .iter()
+        .map(|cell| cell.ends_with(':') && !cell.starts_with(':'))
+        .collect();
+
+    let mut rows = vec![header];
+    let mut used = 2;
+    for line in &lines[2..] {
+        let Some(row) = table_cells(line) else {
+            break;
+        };
+        // A ragged table is ambiguous; leave the whole thing as text
+        if row.len() != rows[0].len() {
+            return None;
+        }
+        rows.push(row);
+        used += 1;
+    }
+    Some((
+        Table {
+            rows,
+            right_aligned,
+        },
+        used,
+    ))
+}
+
+/// The cells of one table row, like `| a | b |` or `a | b`
+fn table_cells(line: &str) -> Option<Vec<String>> {
+    let line = line.trim();
+    if !line.contains('|') {
+        return None;
+    }
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = line.strip_suffix('|').unwrap_or(line);
+    let cells: Vec<String> = line.split('|').map(|c| c.trim().to_string()).collect();
+    if cells.len() < 2 {
+        return None;
+    }
+    Some(cells)
+}
+
+/// The byte ranges of `query` in `text`, not overlapping
+fn find_in(text: &str, query: &str, case_sensitive: bool) -> Vec<(usize, usize)> {
+    let mut found = Vec::new();
+    if query.is_empty() {
+        return found;
+    }
+    let mut idx = 0;
+    while let Some(c) = text[idx..].chars().next() {
+        if let Some(len) = match_len(&text[idx..], query, case_sensitive) {
+            found.push((idx, idx + len));
+            idx += len;
+        } else {
+            idx += c.len_utf8();
+        }
+    }
+    found
+}
+
+/// If `text` starts with `query`, how many bytes of `text` that covers. Comparing one character at
+/// a time handles characters whose lowercase form is a different length.
+fn match_len(text: &str, query: &str, case_sensitive: bool) -> Option<usize> {
+    let mut chars = text.char_indices();
+    for q in query.chars() {
+        let (_, c) = chars.next()?;
+        let same = if case_sensitive {
+            c == q
+        } else {
+            c.to_lowercase().eq(q.to_lowercase())
+        };
+        if !same {
+            return None;
+        }
+    }
+    Some(chars.next().map(|(idx, _)| idx).unwrap_or(text.len()))
+}
+
+/// Picks the match after `current`, going towards older or newer messages and wrapping around.
+/// Without a current match, starts from the newest.
+fn step_match(matches: &[usize], current: Option<usize>, older: bool) -> Option<usize> {
+    let last = *matches.last()?;
+    let current = match current {
+        Some(current) => current,
+        None => return Some(last),
+    };
+    if older {
+        Some(
+            matches
+                .iter()
+                .rev()
+                .find(|idx| **idx < current)
+                .copied()
+                .unwrap_or(last),
+        )
+    } else {
+        Some(
+            matches
+                .iter()
+                .find(|idx| **idx > current)
+                .copied()
+                .unwrap_or(matches[0]),
+        )
+    }
+}
+
+/// How far to scroll the transcript back so message `idx` is shown. If it's already visible, the
+/// scroll stays put.
+fn scroll_back_to_show(len: usize, idx: usize, visible: usize, scroll_back: usize) -> usize {
+    let (start, end) = visible_range(len, scroll_back, visible);
+    if (start..end).contains(&idx) {
+        return scroll_back;
+    }
+    (len - idx - 1).min(len.saturating_sub(visible))
+}
+
+/// How many messages to scroll the transcript back (positive) or forward (negative), based on
+/// keys that don't conflict with editing the input box.
+fn transcript_scroll(ctx: &mut EventCtx, page: usize) -> Option<isize> {
+    let page = page as isize;
+    if ctx.input.pressed(lctrl(Key::PageUp)) {
+        return Some(page);
+    }
+    if ctx.input.pressed(lctrl(Key::PageDown)) {
+        return Some(-page);
+    }
+    if ctx.is_key_down(Key::LeftAlt) {
+        if ctx.input.pressed(Key::UpArrow) {
+            return Some(1);
+        }
+        if ctx.input.pressed(Key::DownArrow) {
+            return Some(-1);
+        }
+    }
+    None
+}
+
+/// The lines of a reply as the transcript shows them, each marked if it's an action chip. Only
+/// action lines that parse are changed, so malformed ones stay visible. Hiding everything would
+/// leave a blank reply, so then the chips are shown instead.
+fn shown_reply(reply: &str, mode: ActionLines) -> Vec<(String, bool)> {
+    let mut lines = Vec::new();
+    let mut chips = Vec::new();
+    for raw_line in reply.lines() {
+        let line = raw_line.trim().to_lowercase();
+        let phrase = match line
+            .strip_prefix("action:")
+            .or_else(|| line.strip_prefix('/'))
+        {
+            Some(phrase) if mode != ActionLines::Show => phrase.trim(),
+            _ => {
+                lines.push((raw_line.to_string(), false));
+                continue;
+            }
+        };
+        if phrase == "begin" || phrase == "end" {
+            continue;
+        }
+        if command_from_phrase(phrase).is_none() {
+            lines.push((raw_line.to_string(), false));
+            continue;
+        }
+        let chip = (format!("[action: {phrase}]"), true);
+        if mode == ActionLines::Chip {
+            lines.push(chip);
+        } else {
+            chips.push(chip);
+        }
+    }
+    while lines
+        .last()
+        .map(|(line, _)| line.trim().is_empty())
+        .unwrap_or(false)
+    {
+        lines.pop();
+    }
+    if lines.is_empty() {
+        return chips;
+    }
+    lines
+}
+
+/// Once the transcript grows past `cap` messages, removes and returns the oldest ones, keeping
+/// half of `cap`, but never fewer than `visible`. Spilling in big chunks means the on-disk archive
+/// is rewritten rarely.
+fn spill_old_messages(
+    messages: &mut Vec<(Role, String)>,
+    cap: usize,
+    visible: usize,
+) -> Vec<(Role, String)> {
+    if messages.len() <= cap.max(visible) {
+        return Vec::new();
+    }
+    let keep = (cap / 2).max(visible);
+    messages.drain(..messages.len() - keep).collect()
+}
+
+/// Picks the language for the chatbox's own text. Some languages need an extra font; if that can't
+/// be loaded, English is used instead of drawing unreadable text.
+fn load_locale(ctx: &mut EventCtx, app: &App) -> Locale {
+    let locale = Locale::detect(app.opts.language.as_deref());
+    if let Some(font) = locale.extra_font() {
+        if !ctx.is_font_loaded(font) {
+            match fs_err::read(abstio::path(format!("system/extra_fonts/{font}"))) {
+                Ok(bytes) => ctx.load_font(font, bytes),
+                Err(err) => {
+                    warn!("Showing the chatbox in English, since {font} couldn't be loaded: {err}");
+                    return Locale::English;
+                }
+            }
+        }
+    }
+    locale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sandbox::chat::persistence::VISIBLE_MESSAGES;
+
+    #[test]
+    fn test_word_diff() {
+        let (old, new) = word_diff(
+            "Pause the sim now.\nACTION: pause",
+            "Slow the sim down.\nACTION: slow down",
+        );
+        assert_eq!(
+            old,
+            vec![
+                ("Pause", true),
+                ("the", false),
+                ("sim", false),
+                ("now.", true),
+                ("ACTION:", false),
+                ("pause", true),
+            ]
+        );
+        assert_eq!(
+            new,
+            vec![
+                ("Slow", true),
+                ("the", false),
+                ("sim", false),
+                ("down.", true),
+                ("ACTION:", false),
+                ("slow", true),
+                ("down", true),
+            ]
+        );
+
+        let (old, new) = word_diff("same", "same");
+        assert_eq!(old, vec![("same", false)]);
+        assert_eq!(new, vec![("same", false)]);
+    }
+
+    #[test]
+    fn test_enqueue_bounded() {
+        let mut queue = VecDeque::new();
+        for msg in ["a", "b", "c"] {
+            assert_eq!(
+                enqueue_bounded(&mut queue, msg.to_string(), 3, QueueFullPolicy::Reject),
+                Enqueued::Added
+            );
+        }
+        assert_eq!(
+            enqueue_bounded(&mut queue, "d".to_string(), 3, QueueFullPolicy::Reject),
+            Enqueued::Rejected
+        );
+        assert_eq!(queue, vec!["a", "b", "c"]);
+
+        assert_eq!(
+            enqueue_bounded(&mut queue, "d".to_string(), 3, QueueFullPolicy::DropOldest),
+            Enqueued::DroppedOldest("a".to_string())
+        );
+        assert_eq!(queue, vec!["b", "c", "d"]);
+
+        // A limit of 0 turns queueing off
+        let mut queue = VecDeque::new();
+        assert_eq!(
+            enqueue_bounded(&mut queue, "a".to_string(), 0, QueueFullPolicy::DropOldest),
+            Enqueued::Rejected
+        );
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_auto_report() {
+        let mut report = AutoReport::default();
+        let every = Some(Duration::minutes(15));
+        let at = |minutes: usize| Time::START_OF_DAY + Duration::minutes(minutes);
+        // The first interval starts when reports are turned on
+        assert!(!report.due(every, at(60)));
+        assert!(!report.due(every, at(74)));
+        assert!(report.due(every, at(75)));
+        assert!(!report.due(every, at(76)));
+        // A big jump forward sends one report, not one per missed interval
+        assert!(report.due(every, at(200)));
+        assert!(!report.due(every, at(201)));
+
+        // The clock going backwards starts over
+        assert!(!report.due(every, at(10)));
+        assert!(report.due(every, at(25)));
+
+        // Turning it off and on again starts over too
+        assert!(!report.due(None, at(100)));
+        assert!(!report.due(every, at(100)));
+        assert!(report.due(every, at(115)));
+    }
+
+    #[test]
+    fn test_can_auto_respond() {
+        let now = Instant::now();
+        // Off by default
+        assert!(!can_auto_respond(
+            ChatSettings::default().auto_respond_to_events,
+            None,
+            now
+        ));
+        assert!(can_auto_respond(true, None, now));
+        assert!(!can_auto_respond(true, Some(now), now));
+        assert!(can_auto_respond(
+            true,
+            Some(now),
+            now + AUTO_RESPOND_COOLDOWN
+        ));
+    }
+
+    #[test]
+    fn test_send_confirmation() {
+        let long = "x".repeat(5_000);
+        // Off by default
+        assert!(!needs_send_confirmation(
+            ChatSettings::default().confirm_send_above,
+            &long
+        ));
+        assert!(needs_send_confirmation(Some(4_000), &long));
+        // Below the threshold, messages send without asking
+        assert!(!needs_send_confirmation(Some(4_000), "How do quotas work?"));
+        assert!(!needs_send_confirmation(Some(5_000), &long));
+        // Characters, not bytes
+        assert!(!needs_send_confirmation(Some(3), "héé"));
+
+        assert_eq!(estimate_tokens(0), 0);
+        assert_eq!(estimate_tokens(5), 2);
+    }
+
+    #[test]
+    fn test_describe_prompt_size() {
+        assert_eq!(
+            describe_prompt_size("", 0, Locale::English),
+            "0 words, about 0 tokens, plus about 0 tokens of context"
+        );
+        assert_eq!(
+            describe_prompt_size("  Pause\nplease ", 4_000, Locale::English),
+            "2 words, about 4 tokens, plus about 1,000 tokens of context"
+        );
+        assert_eq!(
+            describe_prompt_size("hi", 1, Locale::English),
+            "1 word, about 1 tokens, plus about 1 tokens of context"
+        );
+        assert_eq!(
+            describe_prompt_size("  Pause\nplease ", 4_000, Locale::Chinese),
+            "2 个词，约 4 个 token，另加约 1,000 个 token 的上下文"
+        );
+    }
+
+    #[test]
+    fn test_input_fits_small_window() {
+        let window = ScreenDims::new(640.0, 480.0);
+        // Every size reachable with the - and + buttons
+        for width_pct in (15..=50).step_by(5) {
+            for height_pct in (15..=60).step_by(5) {
+                let layout = InputLayout::new(window, width_pct, height_pct);
+                let content_w = content_width(window, width_pct);
+                let content_h = (height_pct as f64 / 100.0) * window.height - 2.0 * PANEL_PADDING;
+
+                let row_width = if layout.stacked {
+                    layout.input_dims.width.max(SEND_BUTTON_WIDTH)
+                } else {
+                    layout.input_dims.width + INPUT_MARGIN as f64 + SEND_BUTTON_WIDTH
+                };
+                assert!(
+                    row_width <= content_w,
+                    "{width_pct}% x {height_pct}% overflows horizontally"
+                );
+                assert!(
+                    layout.input_dims.height <= content_h.max(MIN_INPUT_HEIGHT),
+                    "{width_pct}% x {height_pct}% overflows vertically"
+                );
+            }
+        }
+        // The default size still fits both side by side
+        assert!(!InputLayout::new(window, 35, 35).stacked);
+    }
+
+    #[test]
+    fn test_header_fits_small_window() {
+        // Roughly the header's controls, from the title to "Reset context here"
+        let widths = [
+            190.0, 12.0, 20.0, 20.0, 120.0, 80.0, 120.0, 70.0, 130.0, 140.0,
+        ];
+        // The title alone needs more than the smallest panel on a tiny window
+        let window = ScreenDims::new(1920.0, 1080.0);
+        for width_pct in (15..=50).step_by(5) {
+            let content_w = content_width(window, width_pct);
+            let rows = pack_rows(&widths, HEADER_SPACING, content_w);
+            assert_eq!(rows.iter().sum::<usize>(), widths.len());
+            let mut start = 0;
+            for len in rows {
+                let row = &widths[start..start + len];
+                let row_width = row.iter().sum::<f64>() + HEADER_SPACING * (len - 1) as f64;
+                assert!(
+                    row_width <= content_w,
+                    "{width_pct}%: a header row is {row_width} wide, but only {content_w} fits"
+                );
+                start += len;
+            }
+        }
+        // At the default size, it still takes more than one row
+        let rows = pack_rows(&widths, HEADER_SPACING, content_width(window, 35));
+        assert!(rows.len() > 1 && rows.len() < widths.len());
+
+        // Something wider than a whole row still gets placed
+        assert_eq!(pack_rows(&[50.0, 500.0, 50.0], 10.0, 100.0), vec![1, 1, 1]);
+        assert_eq!(pack_rows(&[], 10.0, 100.0), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_content_width() {
+        let window = ScreenDims::new(1920.0, 1080.0);
+        // Text fills the whole panel besides its padding, at the smallest and largest sizes
+        assert_eq!(content_width(window, 15), 288.0 - 2.0 * PANEL_PADDING);
+        assert_eq!(content_width(window, 50), 960.0 - 2.0 * PANEL_PADDING);
+    }
+
+    #[test]
+    fn test_chat_position() {
+        // The default is where the chatbox always was
+        assert_eq!(ChatPosition::BottomLeft.corner(35, 35), (0.02, 0.65));
+        for position in ChatPosition::ALL {
+            // Every preset keeps the panel on screen, at every size the resize buttons allow
+            for width_pct in (15..=50).step_by(5) {
+                for height_pct in (15..=60).step_by(5) {
+                    let (x, y) = position.corner(width_pct, height_pct);
+                    assert!(x >= 0.0 && x + width_pct as f64 / 100.0 <= 1.0);
+                    assert!(y >= 0.0 && y + height_pct as f64 / 100.0 <= 1.0 + 1e-9);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_reply_tables() {
+        let msg = "Here's the comparison:\n\n| Mode | Trips |\n|:--|--:|\n| Walk | 120 |\n| Bike | 8 |\n\nBikes are rare.";
+        let blocks = reply_blocks(msg);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(
+            blocks[0],
+            ReplyBlock::Text("Here's the comparison:".to_string())
+        );
+        assert_eq!(blocks[2], ReplyBlock::Text("Bikes are rare.".to_string()));
+        let ReplyBlock::Table(table) = &blocks[1] else {
+            panic!("no table in {blocks:?}");
+        };
+        assert_eq!(table.right_aligned, vec![false, true]);
+        assert_eq!(
+            table.lines(),
+            vec![
+                "Mode | Trips".to_string(),
+                "-----+------".to_string(),
+                "Walk |   120".to_string(),
+                "Bike |     8".to_string(),
+            ]
+        );
+
+        // A row with a missing cell makes the table ambiguous, so it stays as text
+        let ragged = "| a | b |\n|---|---|\n| 1 |";
+        assert_eq!(
+            reply_blocks(ragged),
+            vec![ReplyBlock::Text(ragged.to_string())]
+        );
+        // So do pipes without a separator row
+        let no_separator = "use a | b\nor c | d";
+        assert_eq!(
+            reply_blocks(no_separator),
+            vec![ReplyBlock::Text(no_separator.to_string())]
+        );
+    }
+
+    #[test]
+    fn test_spill_old_messages() {
+        let mut messages: Vec<(Role, String)> =
+            (0..20).map(|i| (Role::User, i.to_string())).collect();
+        assert!(spill_old_messages(&mut messages, 20, VISIBLE_MESSAGES).is_empty());
+        assert_eq!(messages.len(), 20);
+
+        messages.push((Role::Assistant, "20".to_string()));
+        let spilled = spill_old_messages(&mut messages, 20, VISIBLE_MESSAGES);
+        // The oldest messages leave, in order, and half the cap stays
+        assert_eq!(spilled.len(), 11);
+        assert_eq!(spilled[0].1, "0");
+        assert_eq!(spilled[10].1, "10");
+        assert_eq!(messages.len(), 10);
+        assert_eq!(messages[0].1, "11");
+
+        // A tiny cap still keeps enough to fill the panel
+        let mut messages: Vec<(Role, String)> =
+            (0..10).map(|i| (Role::User, i.to_string())).collect();
+        spill_old_messages(&mut messages, 0, VISIBLE_MESSAGES);
+        assert_eq!(messages.len(), VISIBLE_MESSAGES);
+    }
+
+    #[test]
+    fn test_find_in() {
+        assert_eq!(
+            find_in("Pause, then pause again", "pause", false),
+            vec![(0, 5), (12, 17)]
+        );
+        assert_eq!(
+            find_in("Pause, then pause again", "pause", true),
+            vec![(12, 17)]
+        );
+        assert!(find_in("anything", "", false).is_empty());
+        assert!(find_in("short", "shorter", false).is_empty());
+        // Byte ranges, even around multi-byte characters
+        assert_eq!(find_in("Ünïcode ÜNÏ", "ünï", false), vec![(0, 5), (10, 15)]);
+    }
+
+    #[test]
+    fn test_step_match() {
+        let matches = vec![2, 5, 9];
+        assert_eq!(step_match(&matches, None, true), Some(9));
+        assert_eq!(step_match(&matches, None, false), Some(9));
+        assert_eq!(step_match(&matches, Some(9), true), Some(5));
+        assert_eq!(step_match(&matches, Some(2), true), Some(9));
+        assert_eq!(step_match(&matches, Some(5), false), Some(9));
+        assert_eq!(step_match(&matches, Some(9), false), Some(2));
+        assert_eq!(step_match(&[], Some(3), true), None);
+    }
+
+    #[test]
+    fn test_scroll_back_to_show() {
+        // Already visible
+        assert_eq!(scroll_back_to_show(20, 17, 6, 0), 0);
+        // Scrolls so the match is the newest one shown
+        assert_eq!(scroll_back_to_show(20, 5, 6, 0), 14);
+        // But not past the oldest message
+        assert_eq!(scroll_back_to_show(20, 1, 6, 0), 14);
+    }
+
+    #[test]
+    fn test_visible_range() {
+        assert_eq!(visible_range(20, 0, VISIBLE_MESSAGES), (14, 20));
+        assert_eq!(visible_range(20, 0, 10), (10, 20));
+        assert_eq!(visible_range(20, 5, 10), (5, 15));
+        // Short transcripts show everything
+        assert_eq!(visible_range(4, 0, 10), (0, 4));
+    }
+
+    #[test]
+    fn test_shown_reply() {
+        let reply = "Slowing down for the rush.\nACTION: begin\nACTION: slower\n/set_quota 5000\n\
+                     ACTION: end\nACTION: step forever\n";
+        let shown = |mode| {
+            shown_reply(reply, mode)
+                .into_iter()
+                .map(|(line, chip)| if chip { format!("({line})") } else { line })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(shown(ActionLines::Show), reply.lines().collect::<Vec<_>>());
+        // A malformed action stays visible, so the player can see what went wrong
+        assert_eq!(
+            shown(ActionLines::Chip),
+            vec![
+                "Slowing down for the rush.",
+                "([action: slower])",
+                "([action: set_quota 5000])",
+                "ACTION: step forever",
+            ]
+        );
+        assert_eq!(
+            shown(ActionLines::Hide),
+            vec!["Slowing down for the rush.", "ACTION: step forever"]
+        );
+        // A reply of nothing but actions isn't left blank
+        assert_eq!(
+            shown_reply("ACTION: pause", ActionLines::Hide),
+            vec![("[action: pause]".to_string(), true)]
+        );
+
+        // Only the display changes; the stored reply still parses the same
+        assert_eq!(
+            parse_commands(reply),
+            vec![vec![
+                (ChatCommand::SlowDown, "ACTION: slower".to_string()),
+                (
+                    ChatCommand::SetRideHailQuota(5000),
+                    "/set_quota 5000".to_string()
+                ),
+            ]]
+        );
+    }
+
+    #[test]
+    fn test_send_button() {
+        let label = |out_of_credits, waiting, choosing, queued, has_input| {
+            SendButton::new(out_of_credits, waiting, choosing, queued, has_input)
+                .label(Locale::English)
+        };
+        assert_eq!(label(false, false, false, 0, true), "Send");
+        assert_eq!(label(false, false, false, 0, false), "Send");
+        // Waiting for a reply
+        assert_eq!(label(false, true, false, 0, true), "Queue");
+        assert_eq!(label(false, true, false, 0, false), "Cancel");
+        assert_eq!(label(false, true, false, 2, false), "Queued: 2");
+        assert_eq!(label(false, true, false, 2, true), "Queued: 2");
+        // Picking between choices, with nothing to cancel
+        assert_eq!(label(false, false, true, 0, false), "Queue");
+        assert_eq!(label(false, false, true, 1, true), "Queued: 1");
+        // Out of credit wins over everything
+        assert_eq!(
+            SendButton::new(true, true, false, 2, true),
+            SendButton::Disabled
+        );
+        assert_eq!(label(true, false, false, 0, true), "Send");
+    }
+
+    #[test]
+    fn test_send_key() {
+        use EnterAction::*;
+
+        // (ctrl, shift) for Enter, Shift+Enter, Ctrl+Enter
+        let combos = [(false, false), (false, true), (true, false)];
+        for (send_key, expected) in [
+            (SendKey::Enter, [Send, Newline, Send]),
+            (SendKey::CtrlEnter, [Newline, Newline, Send]),
+        ] {
+            for ((ctrl, shift), action) in combos.into_iter().zip(expected) {
+                assert_eq!(send_key.on_enter(ctrl, shift), action);
+            }
+        }
+    }
+
+    #[test]
+    fn test_shortcuts_dont_collide() {
+        // With Ctrl, the input box's editing keys (including readline ones), the keys for sending,
+        // resending, and scrolling, and the sandbox's own shortcuts
+        let taken = [
+            Key::A,
+            Key::E,
+            Key::K,
+            Key::U,
+            Key::W,
+            Key::Z,
+            Key::Y,
+            Key::V,
+            Key::C,
+            Key::R,
+            Key::Enter,
+            Key::PageUp,
+            Key::PageDown,
+            Key::S,
+            Key::J,
+            Key::D,
+        ];
+        let mut seen = BTreeSet::new();
+        for (action, key) in SHORTCUTS {
+            assert!(!taken.contains(&key), "{action} uses Ctrl+{key:?}");
+            assert!(seen.insert(key), "{action} reuses Ctrl+{key:?}");
+            assert_eq!(shortcut(action), lctrl(key));
+        }
+    }
+}
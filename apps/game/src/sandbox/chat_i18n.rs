@@ -0,0 +1,952 @@
+//! Translations of the chatbox's own text: its buttons, labels, tooltips, and the notices it adds
+//! to the transcript, including what happened to the LLM's actions. Some text stays in English:
+//!
+//! - anything the LLM wrote, including the action lines quoted from its replies
+//! - what's written for the LLM to read, like prompts, templates, example actions, automatic
+//!   reports, and the simulation state attached to messages
+//! - errors besides the provider's recognized ones, like a missing API key or a network failure's
+//!   details
+//! - names of maps and scenarios
+//! - the Markdown export and the headless `ChatSession`
+
+use abstutil::prettyprint_usize;
+
+/// A language the chatbox's text is available in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Locale {
+    English,
+    /// Simplified Chinese
+    Chinese,
+}
+
+impl Locale {
+    pub const ALL: [Locale; 2] = [Locale::English, Locale::Chinese];
+
+    /// Understands tags like `zh`, `zh-Hans`, or `zh_CN.UTF-8`, just by the language part.
+    fn parse(tag: &str) -> Option<Locale> {
+        let lang = tag.split(['-', '_', '.']).next()?.to_lowercase();
+        match lang.as_str() {
+            "en" => Some(Locale::English),
+            "zh" => Some(Locale::Chinese),
+            _ => None,
+        }
+    }
+
+    /// Prefers the app's language setting, then the usual environment variables. English is the
+    /// fallback.
+    pub fn detect(app_language: Option<&str>) -> Locale {
+        app_language
+            .map(|lang| lang.to_string())
+            .into_iter()
+            .chain(
+                ["LC_ALL", "LC_MESSAGES", "LANG"]
+                    .into_iter()
+                    .filter_map(|var| std::env::var(var).ok()),
+            )
+            .find_map(|tag| Locale::parse(&tag))
+            .unwrap_or(Locale::English)
+    }
+
+    /// A font from `system/extra_fonts` that has to be loaded to draw this language
+    pub fn extra_font(self) -> Option<&'static str> {
+        match self {
+            Locale::English => None,
+            Locale::Chinese => Some("NotoSerifCJKtc-Regular.otf"),
+        }
+    }
+}
+
+/// Everything the chatbox says in its own voice
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Msg {
+    Title,
+    Send,
+    Queue,
+    Queued,
+    Cancel,
+    AskAgain,
+    ChatboxReady,
+    LlmError,
+    Waiting,
+    Stop,
+    Templates,
+    HideTemplates,
+    AttachMapView,
+    RemoveMapView,
+    FixSeed,
+    Seed,
+    ShowThoughts,
+    HideThoughts,
+    ContextAttached,
+    PinReply,
+    CopyMarkdown,
+    ResetContext,
+    ContinueSession,
+    NewConversation,
+    Apply,
+    Why,
+    PlaceholderEnter,
+    PlaceholderCtrlEnter,
+    ContextWindowDivider,
+    ContextResetDivider,
+    YouPrefix,
+    LlmPrefix,
+    PickAlternative,
+    Clear,
+    RestartRequest,
+    Discard,
+    MapViewAttached,
+    SendAnyway,
+    RunQueuedFirst,
+    DiscardQueued,
+    ClearStaleQueue,
+    OutOfCredit,
+    EnterSends,
+    CtrlEnterSends,
+    HintEnter,
+    HintCtrlEnter,
+    NothingToResend,
+    ContextMessages,
+    MaxReplyTokens,
+    ReportStatsEvery,
+    AtMost,
+    InARow,
+    ReactToEvents,
+    FrequencyPenalty,
+    PresencePenalty,
+    Position,
+    ActionLines,
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    ShowActionLines,
+    ActionChips,
+    HideActionLines,
+    CheckingConnection,
+    Connected,
+    ClickToCheckAgain,
+    ConnectionUnverified,
+    InvalidApiKey,
+    AccessDenied,
+    WrongBaseUrl,
+    RateLimited,
+    ProviderError,
+    RequestFailed,
+    BalanceExhausted,
+    NoRecognizedActions,
+    ExplainActions,
+    WaitForReply,
+    CopyMarkdownTooltip,
+    ResetContextTooltip,
+    RegenerateToCompare,
+    Unpin,
+    ReplyPinned,
+    Pinned,
+    Regenerated,
+    ReplacesSavedConversation,
+    Delete,
+    SaveTemplate,
+    AskForActions,
+    SearchTranscript,
+    PausedWhileTyping,
+    CancelledBeforeReply,
+    ReplayingRecording,
+    VisionUnsupported,
+    RecordedReplay,
+    NoModelConfigured,
+    RandomSampling,
+    ProviderReplyLength,
+    DifferentCustomPrompt,
+    DifferentScenario,
+    DifferentStopSequences,
+    HighContrast,
+    DryRun,
+    AttachSimState,
+    AttachScenario,
+    PauseWhileTyping,
+    MatchCase,
+    SelectText,
+    PauseAction,
+    ResumeAction,
+    SlowDownAction,
+    SpeedUpAction,
+    StepByNothing,
+    ExpectedDuration,
+    ExpectedVehicles,
+    QuotaParam,
+    SpeedParam,
+    Unset,
+    Paused,
+}
+
+impl Msg {
+    pub fn text(self, locale: Locale) -> &'static str {
+        match locale {
+            Locale::English => self.english(),
+            Locale::Chinese => self.chinese(),
+        }
+    }
+
+    fn english(self) -> &'static str {
+        match self {
+            Msg::Title => "LLM Chat (Sylvia's Team)",
+            Msg::Send => "Send",
+            Msg::Queue => "Queue",
+            Msg::Queued => "Queued",
+            Msg::Cancel => "Cancel",
+            Msg::AskAgain => "Ask again",
+            Msg::ChatboxReady => "Chatbox ready.",
+            Msg::LlmError => "LLM error",
+            Msg::Waiting => "Waiting for the LLM...",
+            Msg::Stop => "Stop",
+            Msg::Templates => "Templates",
+            Msg::HideTemplates => "Hide templates",
+            Msg::AttachMapView => "Attach map view",
+            Msg::RemoveMapView => "Remove map view",
+            Msg::FixSeed => "Fix seed",
+            Msg::Seed => "Seed",
+            Msg::ShowThoughts => "Show thoughts",
+            Msg::HideThoughts => "Hide thoughts",
+            Msg::ContextAttached => "Context attached",
+            Msg::PinReply => "Pin reply to compare",
+            Msg::CopyMarkdown => "Copy as Markdown",
+            Msg::ResetContext => "Reset context here",
+            Msg::ContinueSession => "Continue last session",
+            Msg::NewConversation => "New conversation",
+            Msg::Apply => "Apply",
+            Msg::Why => "Why?",
+            Msg::PlaceholderEnter => "Describe a scenario, then Enter to send",
+            Msg::PlaceholderCtrlEnter => "Describe a scenario, then Ctrl+Enter to send",
+            Msg::ContextWindowDivider => "── context window: the LLM only sees messages below ──",
+            Msg::ContextResetDivider => "── context reset: the LLM doesn't see messages above ──",
+            Msg::YouPrefix => "You: ",
+            Msg::LlmPrefix => "LLM: ",
+            Msg::PickAlternative => "The LLM offered several replies. Pick one to continue with:",
+            Msg::Clear => "Clear",
+            Msg::RestartRequest => "Restart with new settings",
+            Msg::Discard => "Discard",
+            Msg::MapViewAttached => "The map view will be sent with your next message.",
+            Msg::SendAnyway => "Send anyway",
+            Msg::RunQueuedFirst => "Run them first",
+            Msg::DiscardQueued => "Discard them",
+            Msg::ClearStaleQueue => "Clear the queue if they no longer apply.",
+            Msg::OutOfCredit => "Out of credit with the LLM provider",
+            Msg::EnterSends => "Enter sends",
+            Msg::CtrlEnterSends => "Ctrl+Enter sends",
+            Msg::HintEnter => "Shift+Enter starts a new line",
+            Msg::HintCtrlEnter => "Enter starts a new line",
+            Msg::NothingToResend => "Waiting for a reply, or nothing has been sent yet",
+            Msg::ContextMessages => "Messages sent as context",
+            Msg::MaxReplyTokens => "Max reply tokens (0 for no cap)",
+            Msg::ReportStatsEvery => "Report stats every (sim minutes, 0 for off)",
+            Msg::AtMost => "At most",
+            Msg::InARow => "in a row",
+            Msg::ReactToEvents => "Let the LLM react to sim events",
+            Msg::FrequencyPenalty => "Frequency penalty",
+            Msg::PresencePenalty => "Presence penalty",
+            Msg::Position => "Position",
+            Msg::ActionLines => "Action lines",
+            Msg::TopLeft => "Top left",
+            Msg::TopRight => "Top right",
+            Msg::BottomLeft => "Bottom left",
+            Msg::BottomRight => "Bottom right",
+            Msg::ShowActionLines => "Show",
+            Msg::ActionChips => "As chips",
+            Msg::HideActionLines => "Hide",
+            Msg::CheckingConnection => "Checking the connection to the LLM...",
+            Msg::Connected => "Connected to the LLM",
+            Msg::ClickToCheckAgain => "Click to check again",
+            Msg::ConnectionUnverified => {
+                "The LLM provider is reachable, but couldn't confirm the API key. Send a message \
+                 to check."
+            }
+            Msg::InvalidApiKey => "Invalid API key. Check DEEPSEEK_API_KEY",
+            Msg::AccessDenied => "Access denied. The API key may lack permission or quota",
+            Msg::WrongBaseUrl => "Wrong base URL or model. Check DEEPSEEK_BASE_URL",
+            Msg::RateLimited => "Rate limited. Wait a moment and try again",
+            Msg::ProviderError => "The LLM provider had an error. Try again",
+            Msg::RequestFailed => "The LLM request failed",
+            Msg::BalanceExhausted => {
+                "DeepSeek balance exhausted. Top up at https://platform.deepseek.com/top_up, then \
+                 click the connection light to send again"
+            }
+            Msg::NoRecognizedActions => "No recognized actions in this reply",
+            Msg::ExplainActions => "Ask the LLM to explain these actions",
+            Msg::WaitForReply => "Wait for the current reply",
+            Msg::CopyMarkdownTooltip => "Copy the whole conversation, including archived messages",
+            Msg::ResetContextTooltip => {
+                "The LLM forgets everything so far, but the transcript stays"
+            }
+            Msg::RegenerateToCompare => "Regenerate to compare",
+            Msg::Unpin => "Unpin",
+            Msg::ReplyPinned => "A reply is pinned for comparison",
+            Msg::Pinned => "Pinned",
+            Msg::Regenerated => "Regenerated",
+            Msg::ReplacesSavedConversation => "The saved conversation will be replaced",
+            Msg::Delete => "Delete",
+            Msg::SaveTemplate => "Save input as template",
+            Msg::AskForActions => "Ask for actions:",
+            Msg::SearchTranscript => "Search the transcript",
+            Msg::PausedWhileTyping => {
+                "Paused the simulation while you type. It resumes when you click away."
+            }
+            Msg::CancelledBeforeReply => "Cancelled before the LLM replied.",
+            Msg::ReplayingRecording => {
+                "Replaying a recorded conversation instead of calling the LLM."
+            }
+            Msg::VisionUnsupported => {
+                "The model isn't marked as understanding images (set LLM_VISION=1), so only the \
+                 text was sent."
+            }
+            Msg::RecordedReplay => "recorded replay",
+            Msg::NoModelConfigured => "no model configured",
+            Msg::RandomSampling => "random sampling",
+            Msg::ProviderReplyLength => "the provider's reply length",
+            Msg::DifferentCustomPrompt => "sent with different custom instructions",
+            Msg::DifferentScenario => "sent with different scenario parameters",
+            Msg::DifferentStopSequences => "sent with different stop sequences",
+            Msg::HighContrast => "High contrast",
+            Msg::DryRun => "Dry run",
+            Msg::AttachSimState => "Attach sim state",
+            Msg::AttachScenario => "Attach scenario",
+            Msg::PauseWhileTyping => "Pause while typing",
+            Msg::MatchCase => "Match case",
+            Msg::SelectText => "Select text",
+            Msg::PauseAction => "pause",
+            Msg::ResumeAction => "resume",
+            Msg::SlowDownAction => "slow down",
+            Msg::SpeedUpAction => "speed up",
+            Msg::StepByNothing => "Ignored a request to step by no time at all.",
+            Msg::ExpectedDuration => "a duration with a unit, like step 5min or step 30s",
+            Msg::ExpectedVehicles => "a whole number of vehicles, like set_quota 5000",
+            Msg::QuotaParam => "quota",
+            Msg::SpeedParam => "speed",
+            Msg::Unset => "unset",
+            Msg::Paused => "paused",
+        }
+    }
+
+    fn chinese(self) -> &'static str {
+        match self {
+            Msg::Title => "LLM 聊天（Sylvia 团队）",
+            Msg::Send => "发送",
+            Msg::Queue => "排队",
+            Msg::Queued => "已排队",
+            Msg::Cancel => "取消",
+            Msg::AskAgain => "重新提问",
+            Msg::ChatboxReady => "聊天框已就绪。",
+            Msg::LlmError => "LLM 错误",
+            Msg::Waiting => "正在等待 LLM……",
+            Msg::Stop => "停止",
+            Msg::Templates => "模板",
+            Msg::HideTemplates => "隐藏模板",
+            Msg::AttachMapView => "附加地图视图",
+            Msg::RemoveMapView => "移除地图视图",
+            Msg::FixSeed => "固定随机种子",
+            Msg::Seed => "种子",
+            Msg::ShowThoughts => "显示思考过程",
+            Msg::HideThoughts => "隐藏思考过程",
+            Msg::ContextAttached => "已附带模拟状态",
+            Msg::PinReply => "固定回复以便比较",
+            Msg::CopyMarkdown => "复制为 Markdown",
+            Msg::ResetContext => "从此处重置上下文",
+            Msg::ContinueSession => "继续上次会话",
+            Msg::NewConversation => "新对话",
+            Msg::Apply => "应用",
+            Msg::Why => "为什么？",
+            Msg::PlaceholderEnter => "描述一个场景，然后按 Enter 发送",
+            Msg::PlaceholderCtrlEnter => "描述一个场景，然后按 Ctrl+Enter 发送",
+            Msg::ContextWindowDivider => "── 上下文窗口：LLM 只能看到下面的消息 ──",
+            Msg::ContextResetDivider => "── 上下文已重置：LLM 看不到上面的消息 ──",
+            Msg::YouPrefix => "你：",
+            Msg::LlmPrefix => "LLM：",
+            Msg::PickAlternative => "LLM 给出了几个回复。选择一个继续：",
+            Msg::Clear => "清空",
+            Msg::RestartRequest => "用新设置重新发送",
+            Msg::Discard => "丢弃",
+            Msg::MapViewAttached => "地图视图将随下一条消息发送。",
+            Msg::SendAnyway => "仍然发送",
+            Msg::RunQueuedFirst => "先运行它们",
+            Msg::DiscardQueued => "丢弃它们",
+            Msg::ClearStaleQueue => "如果它们不再适用，请清空队列。",
+            Msg::OutOfCredit => "LLM 服务商的余额已用完",
+            Msg::EnterSends => "Enter 发送",
+            Msg::CtrlEnterSends => "Ctrl+Enter 发送",
+            Msg::HintEnter => "Shift+Enter 换行",
+            Msg::HintCtrlEnter => "Enter 换行",
+            Msg::NothingToResend => "正在等待回复，或者还没有发送过消息",
+            Msg::ContextMessages => "作为上下文发送的消息数",
+            Msg::MaxReplyTokens => "回复 token 上限（0 表示不限）",
+            Msg::ReportStatsEvery => "每隔多久报告统计（模拟分钟，0 表示关闭）",
+            Msg::AtMost => "最多连续",
+            Msg::InARow => "次",
+            Msg::ReactToEvents => "让 LLM 回应模拟事件",
+            Msg::FrequencyPenalty => "频率惩罚",
+            Msg::PresencePenalty => "存在惩罚",
+            Msg::Position => "位置",
+            Msg::ActionLines => "动作行",
+            Msg::TopLeft => "左上",
+            Msg::TopRight => "右上",
+            Msg::BottomLeft => "左下",
+            Msg::BottomRight => "右下",
+            Msg::ShowActionLines => "显示",
+            Msg::ActionChips => "显示为标签",
+            Msg::HideActionLines => "隐藏",
+            Msg::CheckingConnection => "正在检查与 LLM 的连接……",
+            Msg::Connected => "已连接到 LLM",
+            Msg::ClickToCheckAgain => "点击重新检查",
+            Msg::ConnectionUnverified => "可以访问 LLM 服务商，但无法确认 API 密钥。发送一条消息来检查。",
+            Msg::InvalidApiKey => "API 密钥无效。请检查 DEEPSEEK_API_KEY",
+            Msg::AccessDenied => "访问被拒绝。API 密钥可能缺少权限或额度",
+            Msg::WrongBaseUrl => "基础 URL 或模型有误。请检查 DEEPSEEK_BASE_URL",
+            Msg::RateLimited => "请求过于频繁。请稍等片刻再试",
+            Msg::ProviderError => "LLM 服务商出错。请重试",
+            Msg::RequestFailed => "LLM 请求失败",
+            Msg::BalanceExhausted => {
+                "DeepSeek 余额已用完。请在 https://platform.deepseek.com/top_up 充值，然后点击连接指示灯重新发送"
+            }
+            Msg::NoRecognizedActions => "这条回复里没有可识别的动作",
+            Msg::ExplainActions => "让 LLM 解释这些动作",
+            Msg::WaitForReply => "请等待当前回复",
+            Msg::CopyMarkdownTooltip => "复制整段对话，包括已归档的消息",
+            Msg::ResetContextTooltip => "LLM 会忘记之前的一切，但聊天记录会保留",
+            Msg::RegenerateToCompare => "重新生成以比较",
+            Msg::Unpin => "取消固定",
+            Msg::ReplyPinned => "已固定一条回复用于比较",
+            Msg::Pinned => "已固定",
+            Msg::Regenerated => "重新生成",
+            Msg::ReplacesSavedConversation => "保存的对话将被替换",
+            Msg::Delete => "删除",
+            Msg::SaveTemplate => "将输入保存为模板",
+            Msg::AskForActions => "请求动作：",
+            Msg::SearchTranscript => "搜索聊天记录",
+            Msg::PausedWhileTyping => "输入时已暂停模拟。点击其他地方即可继续。",
+            Msg::CancelledBeforeReply => "在 LLM 回复前已取消。",
+            Msg::ReplayingRecording => "正在回放录制的对话，而不是调用 LLM。",
+            Msg::VisionUnsupported => "该模型未标记为支持图像（设置 LLM_VISION=1），所以只发送了文字。",
+            Msg::RecordedReplay => "录制的回放",
+            Msg::NoModelConfigured => "未配置模型",
+            Msg::RandomSampling => "随机采样",
+            Msg::ProviderReplyLength => "服务商默认的回复长度",
+            Msg::DifferentCustomPrompt => "发送时使用了不同的自定义指令",
+            Msg::DifferentScenario => "发送时使用了不同的场景参数",
+            Msg::DifferentStopSequences => "发送时使用了不同的停止序列",
+            Msg::HighContrast => "高对比度",
+            Msg::DryRun => "试运行",
+            Msg::AttachSimState => "附带模拟状态",
+            Msg::AttachScenario => "附带场景参数",
+            Msg::PauseWhileTyping => "输入时暂停",
+            Msg::MatchCase => "区分大小写",
+            Msg::SelectText => "选择文本",
+            Msg::PauseAction => "暂停",
+            Msg::ResumeAction => "继续",
+            Msg::SlowDownAction => "减速",
+            Msg::SpeedUpAction => "加速",
+            Msg::StepByNothing => "忽略了一个时长为零的步进请求。",
+            Msg::ExpectedDuration => "带单位的时长，比如 step 5min 或 step 30s",
+            Msg::ExpectedVehicles => "整数的车辆数，比如 set_quota 5000",
+            Msg::QuotaParam => "配额",
+            Msg::SpeedParam => "速度",
+            Msg::Unset => "未设置",
+            Msg::Paused => "已暂停",
+        }
+    }
+}
+
+// Sentences with numbers or names filled in. Word order differs between languages, so each one is
+// written out whole.
+impl Locale {
+    pub fn load_earlier(self, archived: usize) -> String {
+        let archived = prettyprint_usize(archived);
+        match self {
+            Locale::English => format!("Load earlier messages ({archived} archived)"),
+            Locale::Chinese => format!("加载更早的消息（已归档 {archived} 条）"),
+        }
+    }
+
+    pub fn earlier_messages(self, count: usize) -> String {
+        match self {
+            Locale::English => {
+                format!("{count} earlier messages (Ctrl+Page Up or Alt+↑ to scroll back)")
+            }
+            Locale::Chinese => {
+                format!("前面还有 {count} 条消息（按 Ctrl+Page Up 或 Alt+↑ 向上滚动）")
+            }
+        }
+    }
+
+    pub fn newer_messages(self, count: usize) -> String {
+        match self {
+            Locale::English => {
+                format!("{count} newer messages (Ctrl+Page Down or Alt+↓ to scroll forward)")
+            }
+            Locale::Chinese => {
+                format!("后面还有 {count} 条消息（按 Ctrl+Page Down 或 Alt+↓ 向下滚动）")
+            }
+        }
+    }
+
+    /// One of several replies to choose between, counting from 1
+    pub fn option(self, n: usize) -> String {
+        match self {
+            Locale::English => format!("Option {n}"),
+            Locale::Chinese => format!("选项 {n}"),
+        }
+    }
+
+    pub fn last_action_reason(self, sources: &str) -> String {
+        match self {
+            Locale::English => format!("Last action ran because the reply said: {sources}"),
+            Locale::Chinese => format!("上一个动作是根据回复中的这句话运行的：{sources}"),
+        }
+    }
+
+    pub fn actions_queued(self, count: usize) -> String {
+        match (self, count) {
+            (Locale::English, 1) => "1 action queued".to_string(),
+            (Locale::English, _) => format!("{count} actions queued"),
+            (Locale::Chinese, _) => format!("已排队 {count} 个动作"),
+        }
+    }
+
+    /// Offered before sending a new message while actions from an earlier reply are queued
+    pub fn queued_actions(self, count: usize) -> String {
+        match (self, count) {
+            (Locale::English, 1) => {
+                "1 action from an earlier reply hasn't run yet and may be out of date.".to_string()
+            }
+            (Locale::English, _) => format!(
+                "{} actions from an earlier reply haven't run yet and may be out of date.",
+                prettyprint_usize(count)
+            ),
+            (Locale::Chinese, _) => format!(
+                "之前回复中的 {} 个动作还没有运行，可能已经过时。",
+                prettyprint_usize(count)
+            ),
+        }
+    }
+
+    pub fn ride_hail_quota(self, quota: usize) -> String {
+        let quota = prettyprint_usize(quota);
+        match self {
+            Locale::English => format!("Ride-hailing quota: {quota} vehicles"),
+            Locale::Chinese => format!("网约车配额：{quota} 辆"),
+        }
+    }
+
+    /// `change` comes from `describe_change`
+    pub fn request_was(self, change: &str) -> String {
+        match self {
+            Locale::English => format!("This request was {change}."),
+            Locale::Chinese => format!("这个请求{change}。"),
+        }
+    }
+
+    /// `change` comes from `describe_change`
+    pub fn reply_was(self, change: &str) -> String {
+        match self {
+            Locale::English => format!("That reply was {change}."),
+            Locale::Chinese => format!("那条回复{change}。"),
+        }
+    }
+
+    /// Part of `describe_change`, like "sent with seed 42, not the current random sampling"
+    pub fn sent_with(self, then: &str, now: &str) -> String {
+        match self {
+            Locale::English => format!("sent with {then}, not the current {now}"),
+            Locale::Chinese => format!("发送时为{then}，而当前为{now}"),
+        }
+    }
+
+    pub fn context_message_count(self, count: usize) -> String {
+        match self {
+            Locale::English => format!("{count} context messages"),
+            Locale::Chinese => format!("{count} 条上下文消息"),
+        }
+    }
+
+    pub fn seed(self, seed: u64) -> String {
+        match self {
+            Locale::English => format!("seed {seed}"),
+            Locale::Chinese => format!("种子 {seed}"),
+        }
+    }
+
+    pub fn reply_cap(self, max_tokens: usize) -> String {
+        let max_tokens = prettyprint_usize(max_tokens);
+        match self {
+            Locale::English => format!("replies capped at {max_tokens} tokens"),
+            Locale::Chinese => format!("回复上限 {max_tokens} 个 token"),
+        }
+    }
+
+    /// `frequency` picks the frequency penalty, or else the presence penalty
+    pub fn penalty(self, frequency: bool, value: f64) -> String {
+        match (self, frequency) {
+            (Locale::English, true) => format!("a frequency penalty of {value}"),
+            (Locale::English, false) => format!("a presence penalty of {value}"),
+            (Locale::Chinese, true) => format!("频率惩罚 {value}"),
+            (Locale::Chinese, false) => format!("存在惩罚 {value}"),
+        }
+    }
+
+    pub fn messages_waiting(self, count: usize, max: usize) -> String {
+        match self {
+            Locale::English => format!("{count} of at most {max} messages waiting to be sent"),
+            Locale::Chinese => format!("{count} 条消息等待发送（最多 {max} 条）"),
+        }
+    }
+
+    /// Asked before sending a long message
+    pub fn confirm_send(self, chars: usize, tokens: usize) -> String {
+        let (chars, tokens) = (prettyprint_usize(chars), prettyprint_usize(tokens));
+        match self {
+            Locale::English => {
+                format!("This message is {chars} characters (about {tokens} tokens). Send it?")
+            }
+            Locale::Chinese => {
+                format!("这条消息有 {chars} 个字符（约 {tokens} 个 token）。要发送吗？")
+            }
+        }
+    }
+
+    /// `hint` says which key starts a new line instead
+    pub fn send_tooltip(self, hint: &str) -> String {
+        match self {
+            Locale::English => format!("Send. {hint}"),
+            Locale::Chinese => format!("发送。{hint}"),
+        }
+    }
+
+    /// `steps` describes the actions, which stay in English
+    pub fn apply_now(self, steps: &str) -> String {
+        match self {
+            Locale::English => format!("Apply now: {steps}"),
+            Locale::Chinese => format!("立即应用：{steps}"),
+        }
+    }
+
+    /// What a saved conversation was about
+    pub fn scenario_on_map(self, scenario: &str, map: &str) -> String {
+        match self {
+            Locale::English => format!("the \"{scenario}\" scenario on {map}"),
+            Locale::Chinese => format!("{map} 上的“{scenario}”场景"),
+        }
+    }
+
+    /// When a saved conversation is continued while running something else
+    pub fn different_experiment(self, saved: &str, current: &str) -> String {
+        match self {
+            Locale::English => format!(
+                "Warning: this conversation was about {saved}, but you're now running \
+                 {current}."
+            ),
+            Locale::Chinese => {
+                format!("警告：这段对话是关于{saved}的，但你现在运行的是{current}。")
+            }
+        }
+    }
+
+    /// `age` is like "5 minutes ago"
+    pub fn continued_conversation(self, about: &str, age: Option<&str>) -> String {
+        match (self, age) {
+            (Locale::English, Some(age)) => {
+                format!("Continued the conversation about {about} from {age}.")
+            }
+            (Locale::English, None) => format!("Continued the conversation about {about}."),
+            (Locale::Chinese, Some(age)) => format!("已继续{age}关于{about}的对话。"),
+            (Locale::Chinese, None) => format!("已继续关于{about}的对话。"),
+        }
+    }
+
+    /// `age` is like "5 minutes ago"
+    pub fn started_new_conversation(self, about: &str, age: Option<&str>) -> String {
+        match (self, age) {
+            (Locale::English, Some(age)) => {
+                format!("Started a new conversation. The saved one, about {about}, is from {age}.")
+            }
+            (Locale::English, None) => {
+                format!("Started a new conversation. The saved one, about {about}, is.")
+            }
+            (Locale::Chinese, Some(age)) => {
+                format!("已开始新对话。保存的对话关于{about}，来自{age}。")
+            }
+            (Locale::Chinese, None) => format!("已开始新对话。保存的对话关于{about}。"),
+        }
+    }
+
+    pub fn minutes_ago(self, n: u64) -> String {
+        match (self, n) {
+            (Locale::English, 1) => "1 minute ago".to_string(),
+            (Locale::English, _) => format!("{n} minutes ago"),
+            (Locale::Chinese, _) => format!("{n} 分钟前"),
+        }
+    }
+
+    pub fn hours_ago(self, n: u64) -> String {
+        match (self, n) {
+            (Locale::English, 1) => "1 hour ago".to_string(),
+            (Locale::English, _) => format!("{n} hours ago"),
+            (Locale::Chinese, _) => format!("{n} 小时前"),
+        }
+    }
+
+    pub fn days_ago(self, n: u64) -> String {
+        match (self, n) {
+            (Locale::English, 1) => "1 day ago".to_string(),
+            (Locale::English, _) => format!("{n} days ago"),
+            (Locale::Chinese, _) => format!("{n} 天前"),
+        }
+    }
+
+    /// `n` counts from 1
+    pub fn search_match(self, n: usize, total: usize) -> String {
+        match self {
+            Locale::English => {
+                format!("Match {n} of {total} (Enter for older, Shift+Enter for newer)")
+            }
+            Locale::Chinese => {
+                format!("第 {n} 个，共 {total} 个匹配（Enter 查看更早的，Shift+Enter 查看更新的）")
+            }
+        }
+    }
+
+    pub fn matching_messages(self, count: usize) -> String {
+        match self {
+            Locale::English => format!("{count} matching messages"),
+            Locale::Chinese => format!("{count} 条匹配的消息"),
+        }
+    }
+
+    pub fn queue_full(self, max: usize) -> String {
+        match self {
+            Locale::English => {
+                format!("Not sent: {max} messages are already waiting for the current reply.")
+            }
+            Locale::Chinese => format!("未发送：已有 {max} 条消息在等待当前回复。"),
+        }
+    }
+
+    /// `name` is a short version of the discarded message
+    pub fn dropped_oldest(self, max: usize, name: &str) -> String {
+        match self {
+            Locale::English => {
+                format!("Discarded the oldest waiting message to stay within {max}: {name}")
+            }
+            Locale::Chinese => format!("为了不超过 {max} 条，丢弃了最早等待的消息：{name}"),
+        }
+    }
+
+    /// `last` when no more reports are sent until the player sends a message
+    pub fn auto_report(self, n: usize, max: usize, time: &str, last: bool) -> String {
+        match self {
+            Locale::English => {
+                let mut notice =
+                    format!("⟳ Automatic report {n} of {max}: sent the stats at {time}.");
+                if last {
+                    notice.push_str(" That's the last until you send a message.");
+                }
+                notice
+            }
+            Locale::Chinese => {
+                let mut notice = format!("⟳ 自动报告 {n}/{max}：已发送 {time} 的统计数据。");
+                if last {
+                    notice.push_str("在你发送消息之前，这是最后一次。");
+                }
+                notice
+            }
+        }
+    }
+
+    /// Like "12 words, about 20 tokens, plus about 300 tokens of context"
+    pub fn prompt_size(self, words: usize, tokens: usize, context_tokens: usize) -> String {
+        let (tokens, context_tokens) =
+            (prettyprint_usize(tokens), prettyprint_usize(context_tokens));
+        match (self, words) {
+            (Locale::English, 1) => {
+                format!(
+                    "1 word, about {tokens} tokens, plus about {context_tokens} tokens of context"
+                )
+            }
+            (Locale::English, _) => format!(
+                "{} words, about {tokens} tokens, plus about {context_tokens} tokens of context",
+                prettyprint_usize(words)
+            ),
+            (Locale::Chinese, _) => format!(
+                "{} 个词，约 {tokens} 个 token，另加约 {context_tokens} 个 token 的上下文",
+                prettyprint_usize(words)
+            ),
+        }
+    }
+
+    /// Like "deepseek-chat · temp 0.2 · max 1,024 per reply · 3,412 tok"
+    pub fn status_line(
+        self,
+        model: &str,
+        temperature: f32,
+        max_tokens: Option<usize>,
+        tokens_used: usize,
+    ) -> String {
+        let tokens_used = prettyprint_usize(tokens_used);
+        let max_tokens = max_tokens.map(prettyprint_usize);
+        match self {
+            Locale::English => {
+                let cap = max_tokens
+                    .map(|max| format!(" · max {max} per reply"))
+                    .unwrap_or_default();
+                format!("{model} · temp {temperature}{cap} · {tokens_used} tok")
+            }
+            Locale::Chinese => {
+                let cap = max_tokens
+                    .map(|max| format!(" · 每条回复最多 {max}"))
+                    .unwrap_or_default();
+                format!("{model} · 温度 {temperature}{cap} · {tokens_used} token")
+            }
+        }
+    }
+
+    /// Joins what several actions do, in the order they run
+    pub fn steps(self, steps: &[String]) -> String {
+        match self {
+            Locale::English => steps.join(", then "),
+            Locale::Chinese => steps.join("，然后"),
+        }
+    }
+
+    pub fn set_quota_step(self, quota: usize) -> String {
+        let quota = prettyprint_usize(quota);
+        match self {
+            Locale::English => format!("set the ride-hailing quota to {quota} vehicles"),
+            Locale::Chinese => format!("将网约车配额设为 {quota} 辆"),
+        }
+    }
+
+    pub fn step_by_step(self, dt: &str) -> String {
+        match self {
+            Locale::English => format!("step forward {dt}"),
+            Locale::Chinese => format!("前进 {dt}"),
+        }
+    }
+
+    /// `steps` comes from `steps`
+    pub fn applying(self, steps: &str) -> String {
+        match self {
+            Locale::English => format!("Applying: {steps}."),
+            Locale::Chinese => format!("正在应用：{steps}。"),
+        }
+    }
+
+    /// What a batch of actions would have done, if dry run were off
+    pub fn dry_run(self, steps: &str) -> String {
+        match self {
+            Locale::English => format!("[dry-run] would {steps}"),
+            Locale::Chinese => format!("[试运行] 将会{steps}"),
+        }
+    }
+
+    /// `source` is the action line as the LLM wrote it
+    pub fn malformed_action(self, source: &str, expected: Msg) -> String {
+        let expected = expected.text(self);
+        match self {
+            Locale::English => format!("Couldn't run \"{source}\": expected {expected}."),
+            Locale::Chinese => format!("无法运行“{source}”：应为{expected}。"),
+        }
+    }
+
+    pub fn quota_out_of_range(self, quota: usize, min: usize, max: usize) -> String {
+        let (quota, min, max) = (
+            prettyprint_usize(quota),
+            prettyprint_usize(min),
+            prettyprint_usize(max),
+        );
+        match self {
+            Locale::English => {
+                format!(
+                    "Ignored ride-hailing quota of {quota}; it must be between {min} and {max}."
+                )
+            }
+            Locale::Chinese => {
+                format!("忽略了网约车配额 {quota}；配额必须在 {min} 到 {max} 之间。")
+            }
+        }
+    }
+
+    pub fn quota_set(self, quota: usize) -> String {
+        let quota = prettyprint_usize(quota);
+        match self {
+            Locale::English => format!(
+                "Ride-hailing quota recorded as {quota} vehicles. The simulation doesn't model a \
+                 ride-hailing fleet yet, so this won't change the traffic."
+            ),
+            Locale::Chinese => {
+                format!("网约车配额已记录为 {quota} 辆。模拟尚未包含网约车车队，因此不会影响交通。")
+            }
+        }
+    }
+
+    pub fn step_too_long(self, dt: &str, max: &str) -> String {
+        match self {
+            Locale::English => {
+                format!("Ignored a request to step by {dt}; the most allowed is {max}.")
+            }
+            Locale::Chinese => format!("忽略了步进 {dt} 的请求；最多允许 {max}。"),
+        }
+    }
+
+    pub fn step_without_time_panel(self, dt: &str) -> String {
+        match self {
+            Locale::English => format!("Can't step forward {dt} without a time panel."),
+            Locale::Chinese => format!("没有时间面板，无法前进 {dt}。"),
+        }
+    }
+
+    pub fn stepped(self, dt: &str, time: &str) -> String {
+        match self {
+            Locale::English => format!("Stepped forward {dt} and paused at {time}."),
+            Locale::Chinese => format!("已前进 {dt}，暂停在 {time}。"),
+        }
+    }
+
+    /// Marks what was streamed of a reply before the player cancelled it
+    pub fn cancelled_reply(self, partial: &str) -> String {
+        match self {
+            Locale::English => format!("{partial} [cancelled]"),
+            Locale::Chinese => format!("{partial} [已取消]"),
+        }
+    }
+
+    pub fn unreachable(self, err: &str) -> String {
+        match self {
+            Locale::English => format!("Couldn't reach the LLM provider: {err}"),
+            Locale::Chinese => format!("无法连接 LLM 服务商：{err}"),
+        }
+    }
+
+    pub fn replay_failed(self, err: &str) -> String {
+        match self {
+            Locale::English => {
+                format!("Couldn't load the recorded conversation, so using the LLM: {err}")
+            }
+            Locale::Chinese => format!("无法加载录制的对话，所以改用 LLM：{err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_locale() {
+        assert_eq!(Locale::parse("zh"), Some(Locale::Chinese));
+        assert_eq!(Locale::parse("zh-Hans"), Some(Locale::Chinese));
+        assert_eq!(Locale::parse("zh_CN.UTF-8"), Some(Locale::Chinese));
+        assert_eq!(Locale::parse("en_US.UTF-8"), Some(Locale::English));
+        assert_eq!(Locale::parse("C.UTF-8"), None);
+        assert_eq!(Locale::parse(""), None);
+
+        // The app's setting wins over the environment
+        assert_eq!(Locale::detect(Some("zh")), Locale::Chinese);
+    }
+}
@@ -1,6 +1,7 @@
 use anyhow::Result;
 use maplit::btreeset;
 
+use abstutil::Timer;
 use geom::{Circle, Distance, Time};
 use map_gui::colors::ColorSchemeChoice;
 use map_gui::load::MapLoader;
@@ -12,6 +13,8 @@ use synthpop::Scenario;
 use widgetry::tools::{ChooseSomething, FileLoader, FutureLoader, URLManager};
 use widgetry::{lctrl, Choice, EventCtx, GfxCtx, Key, Outcome, Panel, State, UpdateType};
 
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::chat::run_chat_script;
 pub use self::gameplay::{spawn_agents_around, GameplayMode, TutorialPointer, TutorialState};
 pub use self::minimap::MinimapController;
 use self::misc_tools::{RoutePreview, TrafficRecorder};
@@ -30,10 +33,12 @@ use crate::pregame::TitleScreen;
 use crate::render::{unzoomed_agent_radius, UnzoomedAgents};
 use crate::ID;
 
-pub mod dashboards;
-pub mod gameplay;
 #[cfg(not(target_arch = "wasm32"))]
 mod chat;
+#[cfg(not(target_arch = "wasm32"))]
+mod chat_i18n;
+pub mod dashboards;
+pub mod gameplay;
 mod minimap;
 mod misc_tools;
 mod speed;
@@ -58,6 +63,8 @@ pub struct SandboxControls {
     minimap: Option<Minimap<App, MinimapController>>,
     #[cfg(not(target_arch = "wasm32"))]
     chatbox: Option<chat::Chatbox>,
+    #[cfg(not(target_arch = "wasm32"))]
+    gridlock_watch: chat::GridlockWatch,
 }
 
 impl SandboxMode {
@@ -140,14 +147,54 @@ impl State<App> for SandboxMode {
         // Let chatbox consume focused keypresses before gameplay hotkeys run.
         #[cfg(not(target_arch = "wasm32"))]
         if let Some(ref mut c) = self.controls.chatbox {
+            // `None` while paused
+            let sim_speed =
+                |tp: Option<&TimePanel>| tp.filter(|tp| !tp.is_paused()).map(|tp| tp.speed());
+            let speed = sim_speed(self.controls.time_panel.as_ref());
+            c.set_sim_snapshot(chat::SimSnapshot::current(app, speed));
+            c.refresh_scenario_params(app);
+            if let Some(event) = self.controls.gridlock_watch.check(app) {
+                c.push_system_event(ctx, event, true);
+            }
             c.event(ctx);
-            if let Some(cmd) = c.take_command() {
-                if let Some(ref mut tp) = self.controls.time_panel {
-                    match cmd {
-                        chat::ChatCommand::Pause => tp.pause(ctx, app),
-                        chat::ChatCommand::Resume => tp.resume(ctx, app, SpeedSetting::Realtime),
+            for applied in c.take_commands() {
+                let speed_before = sim_speed(self.controls.time_panel.as_ref());
+                match (applied.cmd, self.controls.time_panel.as_mut()) {
+                    (chat::ChatCommand::Pause, Some(tp)) => tp.pause(ctx, app),
+                    (chat::ChatCommand::Resume, Some(tp)) => {
+                        tp.resume(ctx, app, SpeedSetting::Realtime)
+                    }
+                    (chat::ChatCommand::SlowDown, Some(tp)) => {
+                        let setting = tp.speed().slower();
+                        tp.set_speed(ctx, app, setting);
                     }
+                    (chat::ChatCommand::SpeedUp, Some(tp)) => {
+                        let setting = tp.speed().faster();
+                        tp.set_speed(ctx, app, setting);
+                    }
+                    (chat::ChatCommand::StepBy(dt), Some(tp)) => {
+                        if c.validate_step(ctx, applied, dt) {
+                            app.primary.sim.timed_step(
+                                &app.primary.map,
+                                dt,
+                                &mut app.primary.sim_cb,
+                                &mut Timer::throwaway(),
+                            );
+                            tp.pause(ctx, app);
+                            app.recalculate_current_selection(ctx);
+                            c.report_step(ctx, applied, dt, app.primary.sim.time());
+                        }
+                    }
+                    // The sim doesn't model a ride-hailing fleet yet, so the chatbox just tracks
+                    // the quota for the study
+                    (chat::ChatCommand::SetRideHailQuota(quota), _) => {
+                        c.set_ride_hail_quota(ctx, applied, quota)
+                    }
+                    (chat::ChatCommand::StepBy(dt), None) => c.reject_step(ctx, applied, dt),
+                    (_, None) => {}
                 }
+                let speed_after = sim_speed(self.controls.time_panel.as_ref());
+                c.report_speed_change(ctx, applied, speed_before, speed_after);
             }
         }
 
@@ -273,10 +320,21 @@ impl State<App> for SandboxMode {
     }
 
     fn on_destroy(&mut self, _: &mut EventCtx, app: &mut App) {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(ref mut c) = self.controls.chatbox {
+            c.on_close();
+        }
         app.primary.layer = None;
         app.primary.agents.borrow_mut().unzoomed_agents = UnzoomedAgents::new();
         self.gameplay.on_destroy(app);
     }
+
+    fn on_quit(&mut self, _: &mut App) {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(ref mut c) = self.controls.chatbox {
+            c.on_close();
+        }
+    }
 }
 
 pub fn maybe_exit_sandbox(ctx: &mut EventCtx) -> Transition {
@@ -746,7 +804,9 @@ impl SandboxControls {
                 None
             },
             #[cfg(not(target_arch = "wasm32"))]
-            chatbox: Some(chat::Chatbox::new(ctx)),
+            chatbox: Some(chat::Chatbox::new(ctx, app)),
+            #[cfg(not(target_arch = "wasm32"))]
+            gridlock_watch: chat::GridlockWatch::default(),
         }
     }
 
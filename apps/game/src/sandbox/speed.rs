@@ -37,6 +37,26 @@ pub enum SpeedSetting {
     Fastest,
 }
 
+impl SpeedSetting {
+    /// The next slower setting, or this one if it's already the slowest.
+    pub fn slower(self) -> SpeedSetting {
+        match self {
+            SpeedSetting::Realtime | SpeedSetting::Fast => SpeedSetting::Realtime,
+            SpeedSetting::Faster => SpeedSetting::Fast,
+            SpeedSetting::Fastest => SpeedSetting::Faster,
+        }
+    }
+
+    /// The next faster setting, or this one if it's already the fastest.
+    pub fn faster(self) -> SpeedSetting {
+        match self {
+            SpeedSetting::Realtime => SpeedSetting::Fast,
+            SpeedSetting::Fast => SpeedSetting::Faster,
+            SpeedSetting::Faster | SpeedSetting::Fastest => SpeedSetting::Fastest,
+        }
+    }
+}
+
 impl TimePanel {
     pub fn new(ctx: &mut EventCtx, app: &App) -> TimePanel {
         let mut time = TimePanel {
@@ -563,4 +583,16 @@ impl TimePanel {
     pub fn is_paused(&self) -> bool {
         self.paused
     }
+
+    pub fn speed(&self) -> SpeedSetting {
+        self.setting
+    }
+
+    /// Changes the speed without affecting whether the simulation is paused.
+    pub fn set_speed(&mut self, ctx: &mut EventCtx, app: &App, setting: SpeedSetting) {
+        if self.setting != setting {
+            self.setting = setting;
+            self.recreate_panel(ctx, app);
+        }
+    }
 }
@@ -34,6 +34,14 @@ pub(crate) struct App<A: SharedAppState> {
 }
 
 impl<A: 'static + SharedAppState> App<A> {
+    /// Lets every state, then the shared state, save anything before a normal exit.
+    pub(crate) fn before_quit(&mut self, canvas: &Canvas) {
+        for state in self.states.iter_mut().rev() {
+            state.on_quit(&mut self.shared_app_state);
+        }
+        self.shared_app_state.before_quit(canvas);
+    }
+
     pub(crate) fn event(&mut self, ctx: &mut EventCtx) {
         self.shared_app_state.before_event();
 
@@ -101,7 +109,7 @@ impl<A: 'static + SharedAppState> App<A> {
                         self.states.push(state);
                     // TODO Once PopupMsg is lifted here, add an explanation
                     } else {
-                        self.shared_app_state.before_quit(ctx.canvas);
+                        self.before_quit(ctx.canvas);
                         std::process::exit(0);
                     }
                 }
@@ -188,6 +196,9 @@ pub trait State<A>: downcast_rs::Downcast {
 
     /// Before this state is popped or replaced, call this.
     fn on_destroy(&mut self, _: &mut EventCtx, _: &mut A) {}
+    /// Before a normal exit, like window close, this is called on every state from the top of the
+    /// stack down. `on_destroy` isn't called then, and the process exits right after.
+    fn on_quit(&mut self, _: &mut A) {}
     // We don't need an on_enter -- the constructor for the state can just do it.
 
     /// Respond to `Transition::Recreate` by assuming state in the app has changed, but preserving
@@ -299,9 +310,11 @@ impl<A: 'static> State<A> for SimpleStateWrapper<A> {
                 .inner
                 .panel_changed(ctx, app, &mut self.panel)
                 .unwrap_or_else(|| self.inner.other_event(ctx, app)),
-            Outcome::DragDropReleased(_, _, _) | Outcome::Focused(_) | Outcome::Nothing => {
-                self.inner.other_event(ctx, app)
-            }
+            Outcome::DragDropReleased(_, _, _)
+            | Outcome::Focused(_)
+            | Outcome::FocusGained(_)
+            | Outcome::FocusLost(_)
+            | Outcome::Nothing => self.inner.other_event(ctx, app),
         }
     }
 
@@ -182,6 +182,7 @@ impl RenderOnly {
             num_uploads: Cell::new(0),
             inner: prerender_innards,
             scale_factor: Cell::new(settings.scale_factor.unwrap_or(1.0)),
+            follow_monitor_scale: Cell::new(false),
         };
         let canvas = Canvas::new(initial_size, settings.canvas_settings);
 
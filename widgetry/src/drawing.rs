@@ -257,6 +257,9 @@ pub struct Prerender {
     pub(crate) assets: Assets,
     pub(crate) num_uploads: Cell<usize>,
     pub(crate) scale_factor: Cell<f64>,
+    /// If true, the scale factor changes when the window moves to a monitor with a different
+    /// one. Apps that pick their own scale factor turn this off.
+    pub(crate) follow_monitor_scale: Cell<bool>,
 }
 
 impl Prerender {
@@ -203,6 +203,8 @@ pub enum Key {
     RightArrow,
     UpArrow,
     DownArrow,
+    PageUp,
+    PageDown,
     F1,
     F2,
     F3,
@@ -291,6 +293,8 @@ impl Key {
             | Key::RightArrow
             | Key::UpArrow
             | Key::DownArrow
+            | Key::PageUp
+            | Key::PageDown
             | Key::F1
             | Key::F2
             | Key::F3
@@ -320,6 +324,8 @@ impl Key {
             Key::RightArrow => "→ arrow".to_string(),
             Key::UpArrow => "↑".to_string(),
             Key::DownArrow => "↓".to_string(),
+            Key::PageUp => "Page Up".to_string(),
+            Key::PageDown => "Page Down".to_string(),
             Key::F1 => "F1".to_string(),
             Key::F2 => "F2".to_string(),
             Key::F3 => "F3".to_string(),
@@ -400,6 +406,8 @@ impl Key {
             VirtualKeyCode::Right => Key::RightArrow,
             VirtualKeyCode::Up => Key::UpArrow,
             VirtualKeyCode::Down => Key::DownArrow,
+            VirtualKeyCode::PageUp => Key::PageUp,
+            VirtualKeyCode::PageDown => Key::PageDown,
             VirtualKeyCode::F1 => Key::F1,
             VirtualKeyCode::F2 => Key::F2,
             VirtualKeyCode::F3 => Key::F3,
@@ -21,6 +21,10 @@ pub enum UpdateType {
         zoom: f64,
         dims: ScreenDims,
     },
+    /// Saves what's currently in the window to a PNG file, after the next draw
+    ScreenCapture {
+        path: String,
+    },
 }
 
 pub struct EventCtx<'a> {
@@ -231,6 +235,7 @@ impl<'a> EventCtx<'a> {
     /// The app will need to recreate its panels for this to take effect
     pub fn set_scale_factor(&mut self, scale_factor: f64) {
         self.prerender.scale_factor.set(scale_factor);
+        self.prerender.follow_monitor_scale.set(false);
         let new_size = self.prerender.window_size();
         self.prerender.window_resized(new_size);
         self.canvas.window_width = new_size.width;
@@ -345,6 +345,7 @@ pub fn run<
         num_uploads: Cell::new(0),
         inner: prerender_innards,
         scale_factor: Cell::new(settings.scale_factor.unwrap_or(monitor_scale_factor)),
+        follow_monitor_scale: Cell::new(settings.scale_factor.is_none()),
     };
     if let Some(min_width) = settings.require_minimum_width {
         let initial_size = prerender.window_size();
@@ -355,6 +356,7 @@ pub fn run<
                 monitor_scale_factor, initial_size.width, min_width
             );
             prerender.scale_factor.set(1.0);
+            prerender.follow_monitor_scale.set(false);
         }
     }
 
@@ -421,7 +423,7 @@ pub fn run<
                 // ControlFlow::Exit cleanly shuts things down, meaning on larger maps, lots of
                 // GPU stuff is dropped. Better to just abort violently and let the OS clean
                 // up.
-                state.app.shared_app_state.before_quit(&state.canvas);
+                state.app.before_quit(&state.canvas);
                 std::process::exit(0);
             }
             winit::event::Event::WindowEvent { event, .. } => {
@@ -441,6 +443,15 @@ pub fn run<
                     previous_keycode = input.virtual_keycode;
                 }
 
+                if let winit::event::WindowEvent::ScaleFactorChanged { scale_factor, .. } = event {
+                    // The window moved to a monitor with a different scale. A Resized event
+                    // follows, and panels recompute their layout in the new logical size then.
+                    if prerender.follow_monitor_scale.get() {
+                        prerender.scale_factor.set(scale_factor);
+                    }
+                    return;
+                }
+
                 let scale_factor = prerender.get_scale_factor();
                 if let Some(ev) =
                     Event::from_winit_event(event, scale_factor, previous_left_click_at)
@@ -519,6 +530,13 @@ pub fn run<
                         error!("Couldn't screenshot everything: {}", err);
                     }
                 }
+                UpdateType::ScreenCapture { path } => {
+                    state.draw(&prerender, true);
+                    let dims = state.canvas.get_window_dims();
+                    if let Err(err) = prerender.inner.screencap(dims, path) {
+                        error!("Couldn't capture the screen: {}", err);
+                    }
+                }
             }
         }
     });
@@ -10,11 +10,12 @@ use taffy::style::{
 use abstutil::CloneableAny;
 use geom::{CornerRadii, Distance, Percent, Polygon};
 
+use crate::assets::Assets;
 use crate::widgets::containers::{Container, Nothing};
 pub use crate::widgets::panel::{Panel, PanelBuilder, PanelDims};
 use crate::{
     Button, Choice, Color, DeferDraw, Drawable, Dropdown, EventCtx, GeomBatch, GfxCtx, JustDraw,
-    OutlineStyle, ScreenDims, ScreenPt, ScreenRectangle, Text, Toggle,
+    MultilineTextBox, OutlineStyle, ScreenDims, ScreenPt, ScreenRectangle, Text, Toggle,
 };
 
 pub mod autocomplete;
@@ -83,6 +84,10 @@ pub enum Outcome {
     DragDropReleased(String, usize, usize),
     /// Some named widget currently holds focus
     Focused(String),
+    /// A text box was just clicked and now takes typed keys
+    FocusGained(String),
+    /// A text box just stopped taking typed keys
+    FocusLost(String),
     /// Nothing happened
     Nothing,
 }
@@ -95,6 +100,8 @@ impl Outcome {
             Outcome::Changed(x) => format!("Outcome::Changed({x})"),
             Outcome::DragDropReleased(x, _, _) => format!("Outcome::DragDropReleased({x}, ...)"),
             Outcome::Focused(x) => format!("Outcome::Focused({x})"),
+            Outcome::FocusGained(x) => format!("Outcome::FocusGained({x})"),
+            Outcome::FocusLost(x) => format!("Outcome::FocusLost({x})"),
             Outcome::Nothing => format!("Outcome::Nothing"),
         }
     }
@@ -726,6 +733,20 @@ impl Widget {
         }
     }
 
+    /// Whether some text box under the cursor scrolls its own overflow with the mouse wheel
+    fn text_box_owns_wheel(&self, cursor: Option<ScreenPt>, assets: &Assets) -> bool {
+        if let Some(text_box) = self.widget.downcast_ref::<MultilineTextBox>() {
+            text_box.owns_wheel(cursor, assets)
+        } else if let Some(container) = self.widget.downcast_ref::<Container>() {
+            container
+                .members
+                .iter()
+                .any(|w| w.text_box_owns_wheel(cursor, assets))
+        } else {
+            false
+        }
+    }
+
     fn currently_hovering(&self) -> Option<&String> {
         if let Some(btn) = self.widget.downcast_ref::<Button>() {
             if btn.hovering {
@@ -1,48 +1,306 @@
+use std::cell::RefCell;
+
 use geom::{Distance, Polygon};
+use unicode_segmentation::UnicodeSegmentation;
 
+use crate::text::{DEFAULT_FONT, DEFAULT_FONT_SIZE};
+use crate::tools::{get_clipboard, set_clipboard};
 use crate::{
     assets::Assets, EdgeInsets, EventCtx, GeomBatch, GfxCtx, Key, Line, Outcome, ScreenDims,
-    ScreenPt, ScreenRectangle, Style, Text, Widget, WidgetImpl, WidgetOutput,
+    ScreenPt, ScreenRectangle, Text, Widget, WidgetImpl, WidgetOutput,
 };
 
-// A multiline text input widget. Enter inserts a newline.
+const HIGH_CONTRAST_FONT_SIZE: usize = 26;
+const CARET_WIDTH: f64 = 2.0;
+
+// A multiline text input widget. Enter inserts a newline, unless `single_line` is used.
+//
+// Only the box with focus receives typed keys. Clicking inside a box gives it focus, and clicking
+// anywhere else takes focus away. Every box in a panel sees the same click, so at most one of them
+// has focus at a time. `autofocus` just decides whether a box starts with focus.
+//
+// Gaining or losing focus produces `Outcome::FocusGained` or `Outcome::FocusLost`, but on the
+// event after the click. Reporting an outcome right away would stop the rest of the panel from
+// seeing the click, so another box might keep focus, or a button might not be pressed.
+//
+// Text can be selected by dragging the mouse or with Shift+Left/Right, and Ctrl+C copies it. Each
+// box has its own selection, and clicking anywhere else clears it, so a selection never spans two
+// boxes. Typing, Backspace, or pasting replaces the selection.
+//
+// The mouse wheel scrolls the box only when its text doesn't fit. Then the box owns the wheel
+// while the cursor is over it, even at the first or last line. The panel around it checks for
+// that before scrolling, and the box consumes the event. When everything fits, the event is left
+// alone for whatever is underneath.
 pub struct MultilineTextBox {
     text: String,
     label: String,
     cursor_x: usize,
     has_focus: bool,
-    autofocus: bool,
     padding: EdgeInsets,
+    dirty: bool,
+    high_contrast: bool,
+    /// Drawn dimmed, can't get focus, and ignores all input. The text can still be changed by the
+    /// caller.
+    disabled: bool,
+    /// The text can be selected and copied, but not edited
+    read_only: bool,
+    /// The other end of the selection from the caret, as a byte offset. Nothing is selected when
+    /// this is `None` or the same as the caret.
+    selection_anchor: Option<usize>,
+    /// The mouse was pressed inside the box and hasn't been released yet
+    dragging: bool,
+    auto_close_pairs: bool,
+    readline_keys: bool,
+    single_line: bool,
+    /// Multiplies the distance from one line to the next
+    line_spacing: f64,
+    /// Drawn dimmed while the box is empty and has focus. Never part of `text`.
+    placeholder: String,
+    /// The text was changed by the caller, and the next event should report it
+    changed_externally: bool,
+    /// Whether focus was gained or lost since the last report
+    focus_change: Option<bool>,
+    /// The first line drawn, when there's more text than fits
+    first_line: usize,
+    /// Wrapping measures every word, so it's only redone when something it depends on changes
+    layout_cache: RefCell<Option<LayoutCache>>,
+
+    undo_stack: Vec<Snapshot>,
+    redo_stack: Vec<Snapshot>,
+    /// Consecutive edits of the same kind are undone together. Moving the caret ends the group.
+    current_group: Option<EditKind>,
 
     top_left: ScreenPt,
     dims: ScreenDims,
 }
 
+struct Snapshot {
+    text: String,
+    cursor_x: usize,
+}
+
+/// The wrapped lines, along with what they were wrapped for
+struct LayoutCache {
+    text: String,
+    width: f64,
+    font_size: usize,
+    lines: Vec<VisualLine>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum EditKind {
+    Insert,
+    Delete,
+}
+
 impl MultilineTextBox {
     pub fn widget<I: Into<String>>(
-        ctx: &EventCtx,
+        _ctx: &EventCtx,
         label: I,
         prefilled: String,
         dims: ScreenDims,
         autofocus: bool,
     ) -> Widget {
-        let label = label.into();
-        Widget::new(Box::new(MultilineTextBox::new(
-            ctx,
-            label.clone(),
-            prefilled,
-            dims,
-            autofocus,
-        )))
-        .named(label)
+        MultilineTextBox::new(label.into(), prefilled, dims, autofocus).into_widget()
+    }
+
+    /// Starts the caret at a character index, instead of the end of the text. Out-of-range indices
+    /// are clamped, and indices inside a grapheme cluster move to its start.
+    pub fn initial_cursor(mut self, char_idx: usize) -> Self {
+        let byte_idx = self
+            .text
+            .char_indices()
+            .nth(char_idx)
+            .map(|(idx, _)| idx)
+            .unwrap_or(self.text.len());
+        self.cursor_x = 0;
+        for g in self.text.graphemes(true) {
+            if self.cursor_x + g.len() > byte_idx {
+                break;
+            }
+            self.cursor_x += g.len();
+        }
+        self
+    }
+
+    /// Typing an opening bracket or quote also inserts the closing one after the caret, and typing
+    /// a closing character right before the same one just moves past it. Off by default, since
+    /// it's only helpful for structured text.
+    pub fn auto_close_pairs(mut self, enabled: bool) -> Self {
+        self.auto_close_pairs = enabled;
+        self
+    }
+
+    /// Adds the Emacs-style shortcuts from terminals: Ctrl+A and Ctrl+E move to the start and end
+    /// of the line, Ctrl+K and Ctrl+U delete to the end and start of the line, and Ctrl+W deletes
+    /// the word before the caret. Ctrl+A selects everything when this is off, but not when it's on.
+    /// Deleted text isn't kept for pasting back. Off by default.
+    pub fn readline_keys(mut self, enabled: bool) -> Self {
+        self.readline_keys = enabled;
+        self
+    }
+
+    /// Shows the box dimmed and without a caret. It can't be focused, and it ignores clicks, keys,
+    /// and scrolling until enabled again.
+    pub fn disabled(mut self, disabled: bool) -> Self {
+        self.disabled = disabled;
+        if disabled {
+            self.has_focus = false;
+        }
+        self
+    }
+
+    /// Lets the text be selected and copied, but not changed by typing or pasting. Unlike
+    /// `disabled`, the box still takes focus. Keys it doesn't use, which includes every letter, are
+    /// left for something else. The caller can still change the text.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Spreads lines apart to make dense text easier to read. 1.0 is the normal spacing, and
+    /// anything less is treated as 1.0, so lines never overlap. The caret stays as tall as the
+    /// text.
+    pub fn line_spacing(mut self, spacing: f64) -> Self {
+        self.line_spacing = spacing.max(1.0);
+        self
+    }
+
+    /// A hint about what to type, shown dimmed while the box is empty and has focus. It's only
+    /// drawn, so `get_text` never returns it.
+    pub fn placeholder<I: Into<String>>(mut self, hint: I) -> Self {
+        self.placeholder = hint.into();
+        self
+    }
+
+    /// Makes this a one-line field, like for searching. Enter produces `Outcome::Clicked` with the
+    /// box's label instead of a newline, pasted newlines become spaces, and long text scrolls
+    /// sideways to keep the caret visible. The height is fixed to one line of text, regardless of
+    /// the dims passed in.
+    pub fn single_line(mut self, ctx: &EventCtx) -> Self {
+        self.single_line = true;
+        self.dims.height = ctx
+            .prerender
+            .assets
+            .line_height(DEFAULT_FONT, self.font_size())
+            + self.padding.top
+            + self.padding.bottom;
+        self.text = self.clean_input(&self.text);
+        self.cursor_x = self.cursor_x.min(self.text.len());
+        self
+    }
+
+    /// Sizes the box to fit its text, from the height in the dims passed in up to `max`. Longer
+    /// text scrolls inside the box instead of making it taller. The height is only worked out here,
+    /// so rebuild the box to resize it after the text changes.
+    pub fn max_height(mut self, ctx: &EventCtx, max: f64) -> Self {
+        let lines = self.wrapped_line_count(ctx);
+        self.dims.height = fitted_height(self.height_for_lines(ctx, lines), self.dims.height, max);
+        self
+    }
+
+    pub fn into_widget(self) -> Widget {
+        let label = self.label.clone();
+        Widget::new(Box::new(self)).named(label)
     }
 
     pub fn get_text(&self) -> String {
         self.text.clone()
     }
 
-    pub(crate) fn new(
-        _ctx: &EventCtx,
+    /// The selected part of the text, or `None` if nothing is selected
+    pub fn selected_text(&self) -> Option<String> {
+        let (start, end) = self.selection()?;
+        Some(self.text[start..end].to_string())
+    }
+
+    /// The caret position, as a character index. This can be passed to `initial_cursor` when
+    /// rebuilding the box.
+    pub fn cursor_char_idx(&self) -> usize {
+        self.text[..self.cursor_x].chars().count()
+    }
+
+    /// True if the box was clicked last, so that it's receiving typed keys. Pass this as
+    /// `autofocus` when rebuilding the box to keep focus.
+    pub fn has_focus(&self) -> bool {
+        self.has_focus
+    }
+
+    /// How many lines the text takes up at the box's current width, including long lines that
+    /// wrap. A single line box is always one line.
+    pub fn wrapped_line_count(&self, ctx: &EventCtx) -> usize {
+        self.layout(&ctx.prerender.assets).len()
+    }
+
+    /// How tall the box has to be to show this many lines without scrolling
+    pub fn height_for_lines(&self, ctx: &EventCtx, lines: usize) -> f64 {
+        let line_pitch = ctx
+            .prerender
+            .assets
+            .line_height(DEFAULT_FONT, self.font_size())
+            * self.line_spacing;
+        (lines as f64 * line_pitch).ceil() + self.padding.top + self.padding.bottom
+    }
+
+    /// Draws with full-strength colors, a thicker outline, and larger text, for low-vision users.
+    pub fn set_high_contrast(&mut self, high_contrast: bool) {
+        self.high_contrast = high_contrast;
+    }
+
+    /// Like `disabled`, without rebuilding the box. Disabling a focused box reports
+    /// `Outcome::FocusLost`.
+    pub fn set_disabled(&mut self, disabled: bool) {
+        if disabled {
+            self.click(false);
+        }
+        self.disabled = disabled;
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+
+    /// Changes the hint from `placeholder` without rebuilding the box.
+    pub fn set_placeholder<I: Into<String>>(&mut self, hint: I) {
+        self.placeholder = hint.into();
+    }
+
+    /// True if the text has changed since the last call to `take_dirty`.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Returns true if the text has changed since the last call, then resets the flag. Lets
+    /// callers avoid copying the text when nothing happened.
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.dirty)
+    }
+
+    /// Replaces all of the text, moving the caret to the end. This can be undone.
+    pub fn set_text(&mut self, text: String) {
+        let text = self.clean_input(&text);
+        self.record_edit(None);
+        self.cursor_x = text.len();
+        self.text = text;
+        self.mark_changed();
+    }
+
+    /// Inserts a snippet at the caret, leaving the caret after it. This is one undo step.
+    pub fn insert_at_cursor(&mut self, snippet: &str) {
+        self.insert_str(snippet);
+        self.mark_changed();
+    }
+
+    /// Adds a snippet to the end of the text, moving the caret there. This is one undo step.
+    pub fn append(&mut self, snippet: &str) {
+        self.cursor_x = self.text.len();
+        self.insert_str(snippet);
+        self.mark_changed();
+    }
+
+    /// The caret starts at the end of the text. Use `widget` unless you need builder options like
+    /// `initial_cursor`.
+    pub fn new(
         label: String,
         prefilled: String,
         dims: ScreenDims,
@@ -54,34 +312,673 @@ impl MultilineTextBox {
             bottom: 8.0,
             right: 8.0,
         };
+        let prefilled = normalize_newlines(&prefilled);
         MultilineTextBox {
             label,
             cursor_x: prefilled.len(),
             text: prefilled,
-            has_focus: false,
-            autofocus,
+            has_focus: autofocus,
             padding,
+            dirty: false,
+            high_contrast: false,
+            disabled: false,
+            read_only: false,
+            selection_anchor: None,
+            dragging: false,
+            auto_close_pairs: false,
+            readline_keys: false,
+            single_line: false,
+            line_spacing: 1.0,
+            placeholder: String::new(),
+            changed_externally: false,
+            focus_change: None,
+            first_line: 0,
+            layout_cache: RefCell::new(None),
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            current_group: None,
             top_left: ScreenPt::new(0.0, 0.0),
             dims,
         }
     }
 
-    fn calculate_text(&self, style: &Style, assets: &Assets) -> Text {
-        let mut s = self.text.clone();
-        if self.cursor_x <= s.len() {
-            s.insert(self.cursor_x, '|');
+    fn mark_changed(&mut self) {
+        self.selection_anchor = None;
+        self.dirty = true;
+        self.changed_externally = true;
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            text: self.text.clone(),
+            cursor_x: self.cursor_x,
+        }
+    }
+
+    /// Call before changing the text. Starts a new undo step, unless the edit continues the
+    /// current group. `None` means the edit is always its own step.
+    fn record_edit(&mut self, kind: Option<EditKind>) {
+        if kind.is_none() || kind != self.current_group {
+            self.undo_stack.push(self.snapshot());
+        }
+        self.redo_stack.clear();
+        self.current_group = kind;
+    }
+
+    fn insert_char(&mut self, c: char) {
+        self.record_edit(Some(EditKind::Insert));
+        self.text.insert(self.cursor_x, c);
+        self.cursor_x += c.len_utf8();
+    }
+
+    /// Returns true if the text changed.
+    fn type_char(&mut self, c: char) -> bool {
+        if self.auto_close_pairs {
+            if matches!(c, ')' | ']' | '}' | '"') && self.text[self.cursor_x..].starts_with(c) {
+                self.cursor_x += c.len_utf8();
+                self.current_group = None;
+                return false;
+            }
+            let close = match c {
+                '(' => Some(')'),
+                '[' => Some(']'),
+                '{' => Some('}'),
+                '"' => Some('"'),
+                _ => None,
+            };
+            if let Some(close) = close {
+                // Both characters are undone together
+                self.record_edit(None);
+                self.text.insert(self.cursor_x, close);
+                self.text.insert(self.cursor_x, c);
+                self.cursor_x += c.len_utf8();
+                return true;
+            }
+        }
+        self.insert_char(c);
+        true
+    }
+
+    /// Fixes up text coming from outside, like the clipboard, before it's inserted.
+    fn clean_input(&self, s: &str) -> String {
+        let s = normalize_newlines(s);
+        if self.single_line {
+            s.replace('\n', " ")
         } else {
-            s.push('|');
+            s
         }
-        let txt = Text::from_multiline(
-            s.split('\n')
-                .map(|l| Line(l).fg(style.text_primary_color))
-                .collect::<Vec<_>>(),
-        );
-        // Wrap lines to fit inside box width.
-        let limit = (self.dims.width - (self.padding.left + self.padding.right) as f64).max(1.0);
-        txt.inner_wrap_to_pixels(limit, assets)
     }
+
+    /// Inserts many characters at once, such as from pasting. This is undone in one step.
+    fn insert_str(&mut self, s: &str) {
+        if s.is_empty() {
+            return;
+        }
+        let s = self.clean_input(s);
+        self.record_edit(None);
+        self.text.insert_str(self.cursor_x, &s);
+        self.cursor_x += s.len();
+    }
+
+    /// Empties the text, which can be undone in one step.
+    fn clear(&mut self) -> bool {
+        if self.text.is_empty() {
+            return false;
+        }
+        self.record_edit(None);
+        self.text.clear();
+        self.cursor_x = 0;
+        true
+    }
+
+    fn backspace(&mut self) -> bool {
+        if let Some(len) = self.prev_grapheme_len() {
+            self.record_edit(Some(EditKind::Delete));
+            self.text
+                .replace_range(self.cursor_x - len..self.cursor_x, "");
+            self.cursor_x -= len;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The byte range of the line the caret is on, not including the newline
+    fn line_bounds(&self) -> (usize, usize) {
+        let start = self.text[..self.cursor_x]
+            .rfind('\n')
+            .map(|idx| idx + 1)
+            .unwrap_or(0);
+        let end = self.text[self.cursor_x..]
+            .find('\n')
+            .map(|idx| self.cursor_x + idx)
+            .unwrap_or(self.text.len());
+        (start, end)
+    }
+
+    fn move_to_line_start(&mut self) {
+        self.cursor_x = self.line_bounds().0;
+        self.current_group = None;
+    }
+
+    fn move_to_line_end(&mut self) {
+        self.cursor_x = self.line_bounds().1;
+        self.current_group = None;
+    }
+
+    /// Deletes a byte range as one undo step, leaving the caret at its start.
+    fn delete_range(&mut self, start: usize, end: usize) -> bool {
+        if start == end {
+            return false;
+        }
+        self.record_edit(None);
+        self.text.replace_range(start..end, "");
+        self.cursor_x = start;
+        true
+    }
+
+    /// The byte range selected, in order
+    fn selection(&self) -> Option<(usize, usize)> {
+        let anchor = self.selection_anchor?;
+        match anchor.cmp(&self.cursor_x) {
+            std::cmp::Ordering::Less => Some((anchor, self.cursor_x)),
+            std::cmp::Ordering::Equal => None,
+            std::cmp::Ordering::Greater => Some((self.cursor_x, anchor)),
+        }
+    }
+
+    /// Returns true if there was a selection to delete.
+    fn delete_selection(&mut self) -> bool {
+        match self.selection_anchor.take() {
+            Some(anchor) => {
+                let (start, end) = (anchor.min(self.cursor_x), anchor.max(self.cursor_x));
+                self.delete_range(start, end)
+            }
+            None => false,
+        }
+    }
+
+    /// At the end of a line, this joins the next line instead, like in a terminal.
+    fn kill_to_line_end(&mut self) -> bool {
+        let end = self.line_bounds().1;
+        if end == self.cursor_x && end < self.text.len() {
+            return self.delete_range(end, end + 1);
+        }
+        self.delete_range(self.cursor_x, end)
+    }
+
+    fn kill_to_line_start(&mut self) -> bool {
+        self.delete_range(self.line_bounds().0, self.cursor_x)
+    }
+
+    /// Deletes back to the previous whitespace, skipping any right before the caret.
+    fn kill_word_before(&mut self) -> bool {
+        let before = self.text[..self.cursor_x].trim_end();
+        let start = before
+            .rfind(char::is_whitespace)
+            .map(|idx| idx + before[idx..].chars().next().unwrap().len_utf8())
+            .unwrap_or(0);
+        self.delete_range(start, self.cursor_x)
+    }
+
+    fn move_left(&mut self) {
+        if let Some(len) = self.prev_grapheme_len() {
+            self.cursor_x -= len;
+        }
+        self.current_group = None;
+    }
+
+    fn move_right(&mut self) {
+        if let Some(len) = self.next_grapheme_len() {
+            self.cursor_x += len;
+        }
+        self.current_group = None;
+    }
+
+    // The caret moves by grapheme clusters, not chars, so that multi-codepoint emoji stay intact.
+    fn prev_grapheme_len(&self) -> Option<usize> {
+        self.text[..self.cursor_x]
+            .graphemes(true)
+            .next_back()
+            .map(|g| g.len())
+    }
+
+    fn next_grapheme_len(&self) -> Option<usize> {
+        self.text[self.cursor_x..]
+            .graphemes(true)
+            .next()
+            .map(|g| g.len())
+    }
+
+    fn undo(&mut self) -> bool {
+        if let Some(prev) = self.undo_stack.pop() {
+            self.redo_stack.push(self.snapshot());
+            self.text = prev.text;
+            self.cursor_x = prev.cursor_x;
+            self.current_group = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn redo(&mut self) -> bool {
+        if let Some(next) = self.redo_stack.pop() {
+            self.undo_stack.push(self.snapshot());
+            self.text = next.text;
+            self.cursor_x = next.cursor_x;
+            self.current_group = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Updates focus for a click inside or outside the box, remembering any change to report.
+    fn click(&mut self, inside: bool) {
+        if inside != self.has_focus {
+            // Changing back before the first change was reported cancels it out
+            self.focus_change = match self.focus_change {
+                Some(_) => None,
+                None => Some(inside),
+            };
+        }
+        self.has_focus = inside;
+    }
+
+    /// Applies one key press. Returns whether the text changed, or `None` if the key isn't for the
+    /// box, so that something else can use it.
+    fn handle_key(&mut self, key: Key, ctrl: bool, alt: bool, shift: bool) -> Option<bool> {
+        if self.disabled {
+            return None;
+        }
+        if key == Key::C && ctrl {
+            // With nothing selected, Ctrl+C is left for someone else
+            set_clipboard(self.selected_text()?);
+            return Some(false);
+        }
+        if matches!(key, Key::LeftArrow | Key::RightArrow) {
+            if shift {
+                self.selection_anchor.get_or_insert(self.cursor_x);
+            } else {
+                self.selection_anchor = None;
+            }
+            self.move_visually(key == Key::LeftArrow);
+            return Some(false);
+        }
+        if key == Key::A && ctrl && !self.readline_keys {
+            self.selection_anchor = Some(0);
+            self.cursor_x = self.text.len();
+            return Some(false);
+        }
+        if self.read_only {
+            return None;
+        }
+        let changed = match key {
+            Key::K if ctrl && shift => self.clear(),
+            Key::A if ctrl && self.readline_keys => {
+                self.move_to_line_start();
+                false
+            }
+            Key::E if ctrl && self.readline_keys => {
+                self.move_to_line_end();
+                false
+            }
+            Key::K if ctrl && self.readline_keys => self.kill_to_line_end(),
+            Key::U if ctrl && self.readline_keys => self.kill_to_line_start(),
+            Key::W if ctrl && self.readline_keys => self.kill_word_before(),
+            Key::Z if ctrl => self.undo(),
+            Key::Y if ctrl => self.redo(),
+            Key::V if ctrl => match get_clipboard() {
+                Ok(contents) => {
+                    let deleted = self.delete_selection();
+                    self.insert_str(&contents);
+                    deleted || !contents.is_empty()
+                }
+                Err(err) => {
+                    warn!("Couldn't paste: {}", err);
+                    false
+                }
+            },
+            Key::Backspace => self.delete_selection() || self.backspace(),
+            Key::Enter => {
+                self.delete_selection();
+                self.insert_char('\n');
+                true
+            }
+            // Other keys with Ctrl or Alt held are shortcuts for someone else, not text
+            _ if ctrl || alt => {
+                return None;
+            }
+            _ => {
+                let c = key.to_char(shift)?;
+                let deleted = self.delete_selection();
+                self.type_char(c) || deleted
+            }
+        };
+        // Anything else, like undoing or moving to the line start, ends the selection
+        self.selection_anchor = None;
+        Some(changed)
+    }
+
+    /// Left/Right move in visual order, so on a right-to-left line, Left moves forwards through
+    /// the text.
+    fn move_visually(&mut self, left: bool) {
+        if left != self.cursor_line_is_rtl() {
+            self.move_left();
+        } else {
+            self.move_right();
+        }
+    }
+
+    fn cursor_line_is_rtl(&self) -> bool {
+        let (start, end) = self.line_bounds();
+        is_rtl_line(&self.text[start..end])
+    }
+
+    fn shows_placeholder(&self) -> bool {
+        self.has_focus && self.text.is_empty() && !self.placeholder.is_empty()
+    }
+
+    fn font_size(&self) -> usize {
+        if self.high_contrast {
+            HIGH_CONTRAST_FONT_SIZE
+        } else {
+            DEFAULT_FONT_SIZE
+        }
+    }
+
+    /// Splits the text into the lines that'll be drawn, wrapping to fit inside the box. A single
+    /// line isn't wrapped, so only the part around the caret is kept.
+    fn layout(&self, assets: &Assets) -> Vec<VisualLine> {
+        if self.single_line {
+            let (start, end) = self.visible_single_line(assets);
+            return vec![VisualLine {
+                start,
+                end,
+                rtl: is_rtl_line(&self.text),
+            }];
+        }
+
+        self.cached_layout(|| {
+            let space = self.measure("a a", assets) - self.measure("aa", assets);
+            wrap_lines(&self.text, self.text_width(), space, |text| {
+                self.measure(text, assets)
+            })
+        })
+    }
+
+    /// Returns the last lines from `wrap`, unless the text, width, or font size changed since.
+    fn cached_layout<F: FnOnce() -> Vec<VisualLine>>(&self, wrap: F) -> Vec<VisualLine> {
+        let width = self.text_width();
+        let font_size = self.font_size();
+        if let Some(cache) = self.layout_cache.borrow().as_ref() {
+            if cache.width == width && cache.font_size == font_size && cache.text == self.text {
+                return cache.lines.clone();
+            }
+        }
+        let lines = wrap();
+        *self.layout_cache.borrow_mut() = Some(LayoutCache {
+            text: self.text.clone(),
+            width,
+            font_size,
+            lines: lines.clone(),
+        });
+        lines
+    }
+
+    /// Returns the range of text around the caret that fits in one line.
+    fn visible_single_line(&self, assets: &Assets) -> (usize, usize) {
+        let limit = self.text_width();
+        let mut start = 0;
+        while start < self.cursor_x
+            && self.measure(&self.text[start..self.cursor_x], assets) > limit
+        {
+            start += self.text[start..].graphemes(true).next().unwrap().len();
+        }
+        let mut end = self.text.len();
+        while end > self.cursor_x && self.measure(&self.text[start..end], assets) > limit {
+            end -= self.text[..end].graphemes(true).next_back().unwrap().len();
+        }
+        (start, end)
+    }
+
+    /// How wide some text is when drawn. Whitespace at the end doesn't count.
+    fn measure(&self, text: &str, assets: &Assets) -> f64 {
+        Text::from(Line(text).size(self.font_size()))
+            .render(assets)
+            .get_dims()
+            .width
+    }
+
+    /// Finds the line the caret is on. When the caret is exactly where a line wraps, it's drawn at
+    /// the start of the next line.
+    fn caret_line(&self, lines: &[VisualLine]) -> usize {
+        for (idx, line) in lines.iter().enumerate() {
+            let wraps_here = lines
+                .get(idx + 1)
+                .map(|next| next.start == line.end)
+                .unwrap_or(false);
+            if line.start <= self.cursor_x
+                && (self.cursor_x < line.end || (self.cursor_x == line.end && !wraps_here))
+            {
+                return idx;
+            }
+        }
+        lines.len() - 1
+    }
+
+    /// How far from the start of a line the caret is drawn after `before`. Unlike `measure`, this
+    /// counts trailing spaces.
+    fn caret_offset(&self, before: &str, assets: &Assets) -> f64 {
+        let trailing_spaces = before.len() - before.trim_end().len();
+        let space = self.measure("a a", assets) - self.measure("aa", assets);
+        self.measure(before, assets) + space * trailing_spaces as f64
+    }
+
+    /// The caret position closest to a point on the screen. Points outside the box go to the
+    /// nearest line.
+    fn caret_at(&self, pt: ScreenPt, assets: &Assets) -> usize {
+        let lines = self.layout(assets);
+        let line_pitch = assets.line_height(DEFAULT_FONT, self.font_size()) * self.line_spacing;
+        let row = ((pt.y - self.top_left.y - self.padding.top) / line_pitch).floor();
+        let idx = (self.first_line as f64 + row).max(0.0) as usize;
+        let line = &lines[idx.min(lines.len() - 1)];
+
+        let mut x = pt.x - self.top_left.x - self.padding.left;
+        if line.rtl {
+            // Right-aligned, with the text running from right to left
+            let width = self.measure(&self.text[line.start..line.end], assets);
+            x = width - (x - (self.text_width() - width).max(0.0));
+        }
+        let mut offset = line.start;
+        let mut left = 0.0;
+        for g in self.text[line.start..line.end].graphemes(true) {
+            let right = self.caret_offset(&self.text[line.start..offset + g.len()], assets);
+            if x < (left + right) / 2.0 {
+                return offset;
+            }
+            offset += g.len();
+            left = right;
+        }
+        line.end
+    }
+
+    /// Whether the mouse wheel at `cursor` scrolls this box's own overflow. Then the panel around
+    /// it leaves the wheel alone.
+    pub(crate) fn owns_wheel(&self, cursor: Option<ScreenPt>, assets: &Assets) -> bool {
+        !self.disabled
+            && cursor
+                .map(|pt| ScreenRectangle::top_left(self.top_left, self.dims).contains(pt))
+                .unwrap_or(false)
+            && self.layout(assets).len() > self.visible_lines(assets)
+    }
+
+    fn text_width(&self) -> f64 {
+        (self.dims.width - (self.padding.left + self.padding.right)).max(1.0)
+    }
+
+    /// How many lines fit in the box, always at least one
+    fn visible_lines(&self, assets: &Assets) -> usize {
+        let line_pitch = assets.line_height(DEFAULT_FONT, self.font_size()) * self.line_spacing;
+        lines_that_fit(
+            self.dims.height - (self.padding.top + self.padding.bottom),
+            line_pitch,
+        )
+    }
+
+    /// Keeps the caret's line in view after typing or moving it.
+    fn scroll_to_caret(&mut self, assets: &Assets) {
+        let lines = self.layout(assets);
+        let caret_line = self.caret_line(&lines);
+        let visible = self.visible_lines(assets);
+        if caret_line < self.first_line {
+            self.first_line = caret_line;
+        } else if caret_line >= self.first_line + visible {
+            self.first_line = caret_line + 1 - visible;
+        }
+    }
+}
+
+fn lines_that_fit(height: f64, line_pitch: f64) -> usize {
+    ((height / line_pitch).floor() as usize).max(1)
+}
+
+/// The height of a box whose text needs `content` to be fully shown, between `min` and `max`
+fn fitted_height(content: f64, min: f64, max: f64) -> f64 {
+    content.max(min).min(max)
+}
+
+/// Where the first drawn line ends up after the mouse wheel moves by `dy`, or `None` if all `total`
+/// lines fit in the `visible` ones. In that case the scroll isn't for this box.
+fn scrolled_first_line(first_line: usize, total: usize, visible: usize, dy: f64) -> Option<usize> {
+    if total <= visible {
+        return None;
+    }
+    // Touchpads send fractions of a line
+    let step = dy.abs().ceil() as usize;
+    Some(if dy > 0.0 {
+        first_line.saturating_sub(step)
+    } else {
+        (first_line + step).min(total - visible)
+    })
+}
+
+/// Text copied from Windows uses \r\n, and old Mac text uses \r alone. Only \n is understood
+/// when splitting lines, so convert both.
+fn normalize_newlines(s: &str) -> String {
+    s.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// One line of text as drawn, after wrapping
+#[derive(Clone)]
+struct VisualLine {
+    /// Byte offsets into the text. The end excludes the newline.
+    start: usize,
+    end: usize,
+    rtl: bool,
+}
+
+/// Wraps text to fit within `limit`, using `measure` for the width of a piece of text and `space`
+/// for the width of one space. Lines break between words when possible, but a word too wide for a
+/// line by itself, like a long URL, is broken between grapheme clusters.
+fn wrap_lines<F: Fn(&str) -> f64>(
+    text: &str,
+    limit: f64,
+    space: f64,
+    measure: F,
+) -> Vec<VisualLine> {
+    let mut lines = Vec::new();
+    let mut line_start = 0;
+    for logical in text.split('\n') {
+        let rtl = is_rtl_line(logical);
+        let logical_start = line_start;
+        let logical_end = line_start + logical.len();
+        // Greedy approach, fit as many words on a line as possible
+        let mut width = 0.0;
+        for (offset, word, spaces) in words(logical) {
+            let word_start = logical_start + offset;
+            let mut word_width = measure(word);
+            if width + word_width > limit && word_start > line_start {
+                lines.push(VisualLine {
+                    start: line_start,
+                    end: word_start,
+                    rtl,
+                });
+                line_start = word_start;
+                width = 0.0;
+            }
+            if word_width > limit {
+                let mut piece_start = word_start;
+                for (idx, g) in word.grapheme_indices(true) {
+                    let g_start = word_start + idx;
+                    let g_end = g_start + g.len();
+                    if g_start > piece_start && measure(&text[piece_start..g_end]) > limit {
+                        lines.push(VisualLine {
+                            start: line_start,
+                            end: g_start,
+                            rtl,
+                        });
+                        line_start = g_start;
+                        piece_start = g_start;
+                    }
+                }
+                word_width = measure(&text[piece_start..word_start + word.len()]);
+            }
+            width += word_width + space * spaces as f64;
+        }
+        lines.push(VisualLine {
+            start: line_start,
+            end: logical_end,
+            rtl,
+        });
+        // Skip the newline
+        line_start = logical_end + 1;
+    }
+    lines
+}
+
+/// Splits a line into words, returning each word's byte offset, the word, and how many spaces
+/// follow it. The first word is empty if the line starts with whitespace.
+fn words(line: &str) -> Vec<(usize, &str, usize)> {
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut word_end = None;
+    for (idx, c) in line.char_indices() {
+        if c.is_whitespace() {
+            word_end.get_or_insert(idx);
+        } else if let Some(end) = word_end.take() {
+            words.push((start, &line[start..end], line[end..idx].chars().count()));
+            start = idx;
+        }
+    }
+    let end = word_end.unwrap_or(line.len());
+    words.push((start, &line[start..end], line[end..].chars().count()));
+    words
+}
+
+/// A line is laid out right-to-left if its first strongly directional character comes from a
+/// script like Hebrew or Arabic. This is whole-line directionality, not the full bidi algorithm.
+fn is_rtl_line(line: &str) -> bool {
+    line.chars()
+        .find_map(|c| {
+            if is_rtl_char(c) {
+                Some(true)
+            } else if c.is_alphabetic() {
+                Some(false)
+            } else {
+                None
+            }
+        })
+        .unwrap_or(false)
+}
+
+fn is_rtl_char(c: char) -> bool {
+    // Hebrew, Arabic, Syriac, Thaana, NKo, and friends, plus their presentation forms
+    matches!(
+        c as u32,
+        0x0590..=0x08FF | 0xFB1D..=0xFDFF | 0xFE70..=0xFEFF | 0x10800..=0x10FFF | 0x1E800..=0x1EFFF
+    )
 }
 
 impl WidgetImpl for MultilineTextBox {
@@ -94,56 +991,109 @@ impl WidgetImpl for MultilineTextBox {
     }
 
     fn event(&mut self, ctx: &mut EventCtx, output: &mut WidgetOutput) {
-        if !self.autofocus && ctx.redo_mouseover() {
-            if let Some(pt) = ctx.canvas.get_cursor_in_screen_space() {
-                self.has_focus = ScreenRectangle::top_left(self.top_left, self.dims).contains(pt);
-            } else {
-                self.has_focus = false;
+        if std::mem::take(&mut self.changed_externally) {
+            output.outcome = Outcome::Changed(self.label.clone());
+            self.scroll_to_caret(&ctx.prerender.assets);
+        }
+
+        // Don't consume the click, so that other boxes can lose focus
+        if ctx.input.left_mouse_button_pressed() {
+            let inside = ctx
+                .canvas
+                .get_cursor_in_screen_space()
+                .map(|pt| ScreenRectangle::top_left(self.top_left, self.dims).contains(pt))
+                .unwrap_or(false);
+            self.click(inside && !self.disabled);
+            self.selection_anchor = None;
+            self.dragging = false;
+            if let (true, false, Some(pt)) = (
+                inside,
+                self.disabled,
+                ctx.canvas.get_cursor_in_screen_space(),
+            ) {
+                self.cursor_x = self.caret_at(pt, &ctx.prerender.assets);
+                self.selection_anchor = Some(self.cursor_x);
+                self.current_group = None;
+                self.dragging = true;
+            }
+        } else if matches!(output.outcome, Outcome::Nothing) {
+            match self.focus_change.take() {
+                Some(true) => {
+                    output.outcome = Outcome::FocusGained(self.label.clone());
+                }
+                Some(false) => {
+                    output.outcome = Outcome::FocusLost(self.label.clone());
+                }
+                None => {}
             }
         }
 
-        if !self.autofocus && !self.has_focus {
+        if self.disabled {
             return;
         }
 
-        if let Some(key) = ctx.input.any_pressed() {
-            match key {
-                Key::LeftArrow => {
-                    if self.cursor_x > 0 {
-                        self.cursor_x -= 1;
-                    }
-                }
-                Key::RightArrow => {
-                    self.cursor_x = (self.cursor_x + 1).min(self.text.len());
-                }
-                Key::Backspace => {
-                    if self.cursor_x > 0 {
-                        output.outcome = Outcome::Changed(self.label.clone());
-                        self.text.remove(self.cursor_x - 1);
-                        self.cursor_x -= 1;
-                    }
+        if self.dragging {
+            if ctx.input.left_mouse_button_released() {
+                self.dragging = false;
+            } else if let Some(pt) = ctx.input.get_moved_mouse() {
+                self.cursor_x = self.caret_at(pt, &ctx.prerender.assets);
+                self.scroll_to_caret(&ctx.prerender.assets);
+            }
+        }
+
+        let hovering = ctx
+            .canvas
+            .get_cursor_in_screen_space()
+            .map(|pt| ScreenRectangle::top_left(self.top_left, self.dims).contains(pt))
+            .unwrap_or(false);
+        if let (true, Some((_, dy))) = (hovering, ctx.input.get_mouse_scroll()) {
+            let assets = &ctx.prerender.assets;
+            if let Some(first_line) = scrolled_first_line(
+                self.first_line,
+                self.layout(assets).len(),
+                self.visible_lines(assets),
+                dy,
+            ) {
+                self.first_line = first_line;
+                if !ctx.input.has_been_consumed() {
+                    ctx.input.consume_event();
                 }
-                Key::Enter => {
+            }
+            return;
+        }
+
+        if !self.has_focus {
+            return;
+        }
+
+        if self.single_line && ctx.input.pressed(Key::Enter) {
+            output.outcome = Outcome::Clicked(self.label.clone());
+            return;
+        }
+
+        if let Some(key) = ctx.input.any_pressed() {
+            let ctrl = ctx.is_key_down(Key::LeftControl);
+            let alt = ctx.is_key_down(Key::LeftAlt);
+            let shift = ctx.is_key_down(Key::LeftShift);
+            match self.handle_key(key, ctrl, alt, shift) {
+                Some(true) => {
                     output.outcome = Outcome::Changed(self.label.clone());
-                    self.text.insert(self.cursor_x, '\n');
-                    self.cursor_x += 1;
+                    self.dirty = true;
+                    self.scroll_to_caret(&ctx.prerender.assets);
                 }
-                _ => {
-                    if let Some(c) = key.to_char(ctx.is_key_down(Key::LeftShift)) {
-                        output.outcome = Outcome::Changed(self.label.clone());
-                        self.text.insert(self.cursor_x, c);
-                        self.cursor_x += 1;
-                    } else {
-                        ctx.input.unconsume_event();
-                    }
+                Some(false) => {
+                    self.scroll_to_caret(&ctx.prerender.assets);
                 }
-            };
+                None => {
+                    ctx.input.unconsume_event();
+                }
+            }
         }
     }
 
     fn draw(&self, g: &mut GfxCtx) {
         let mut batch = GeomBatch::from(vec![(
-            if self.autofocus || self.has_focus {
+            if (self.has_focus || self.high_contrast) && !self.disabled {
                 g.style().field_bg
             } else {
                 g.style().field_bg.dull(0.5)
@@ -151,19 +1101,631 @@ impl WidgetImpl for MultilineTextBox {
             Polygon::rounded_rectangle(self.dims.width, self.dims.height, 2.0),
         )]);
 
-        let outline_style = g.style().btn_outline.outline;
+        let (thickness, color) = if self.disabled {
+            (
+                g.style().btn_outline.outline.0,
+                g.style().btn_outline.fg_disabled,
+            )
+        } else if self.high_contrast {
+            // The background no longer dims when unfocused, so signal focus through the outline
+            let color = if self.has_focus {
+                g.style().text_primary_color
+            } else {
+                g.style().btn_outline.outline.1
+            };
+            (2.0 * g.style().btn_outline.outline.0, color)
+        } else {
+            g.style().btn_outline.outline
+        };
         batch.push(
-            outline_style.1,
+            color,
             Polygon::rounded_rectangle(self.dims.width, self.dims.height, 2.0)
-                .to_outline(Distance::meters(outline_style.0)),
+                .to_outline(Distance::meters(thickness)),
         );
 
-        batch.append(
-            self.calculate_text(g.style(), &g.prerender.assets)
-                .render_autocropped(g)
+        // Each line is placed separately, so right-to-left lines can be right-aligned
+        let assets = &g.prerender.assets;
+        let line_height = assets.line_height(DEFAULT_FONT, self.font_size());
+        let line_pitch = line_height * self.line_spacing;
+        if self.shows_placeholder() {
+            batch.append(
+                Text::from(
+                    Line(&self.placeholder)
+                        .fg(g.style().text_secondary_color)
+                        .size(self.font_size()),
+                )
+                .render(assets)
                 .translate(self.padding.left, self.padding.top),
-        );
+            );
+        }
+        let lines = self.layout(assets);
+        let caret_line = self.caret_line(&lines);
+        // The text may have shrunk since the box was last scrolled
+        let visible = self.visible_lines(assets);
+        let first_line = self.first_line.min(lines.len().saturating_sub(visible));
+        let text_color = if self.disabled {
+            g.style().btn_outline.fg_disabled
+        } else {
+            g.style().text_primary_color
+        };
+        let selection = self.selection();
+        for (idx, line) in lines.iter().enumerate().skip(first_line).take(visible) {
+            let y = self.padding.top + ((idx - first_line) as f64) * line_pitch;
+            let line_batch = Text::from(
+                Line(&self.text[line.start..line.end])
+                    .fg(text_color)
+                    .size(self.font_size()),
+            )
+            .render(assets);
+            let line_width = line_batch.get_dims().width;
+            let x = if line.rtl {
+                self.padding.left + (self.text_width() - line_width).max(0.0)
+            } else {
+                self.padding.left
+            };
+
+            if let Some((start, end)) = selection {
+                let (start, end) = (start.max(line.start), end.min(line.end));
+                if start < end {
+                    let x1 = self.caret_offset(&self.text[line.start..start], assets);
+                    let x2 = self.caret_offset(&self.text[line.start..end], assets);
+                    let left = if line.rtl {
+                        x + (line_width - x2).max(0.0)
+                    } else {
+                        x + x1
+                    };
+                    batch.push(
+                        g.style().btn_solid_primary.bg.alpha(0.4),
+                        Polygon::rectangle(x2 - x1, line_height).translate(left, y),
+                    );
+                }
+            }
+            batch.append(line_batch.translate(x, y));
+
+            // The caret is drawn, never inserted into the text, so it can't leak into get_text
+            // A read-only box only needs a caret while it's being selected in
+            if idx == caret_line && !self.disabled && (self.has_focus || !self.read_only) {
+                let offset = self.caret_offset(&self.text[line.start..self.cursor_x], assets);
+                let caret_x = if line.rtl {
+                    x + (line_width - offset).max(0.0)
+                } else {
+                    x + offset
+                };
+                batch.push(
+                    g.style().text_primary_color,
+                    Polygon::rectangle(CARET_WIDTH, line_height).translate(caret_x, y),
+                );
+            }
+        }
         let draw = g.upload(batch);
         g.redraw_at(self.top_left, &draw);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_box(text: &str) -> MultilineTextBox {
+        MultilineTextBox::new(
+            "test".to_string(),
+            text.to_string(),
+            ScreenDims::new(100.0, 100.0),
+            true,
+        )
+    }
+
+    fn type_str(tb: &mut MultilineTextBox, s: &str) {
+        for c in s.chars() {
+            tb.insert_char(c);
+        }
+    }
+
+    #[test]
+    fn test_undo_paste() {
+        let mut tb = text_box("");
+        type_str(&mut tb, "hi ");
+        tb.insert_str("first line\nsecond line");
+        assert_eq!(tb.text, "hi first line\nsecond line");
+
+        assert!(tb.undo());
+        assert_eq!(tb.text, "hi ");
+        assert_eq!(tb.cursor_x, 3);
+
+        assert!(tb.redo());
+        assert_eq!(tb.text, "hi first line\nsecond line");
+    }
+
+    #[test]
+    fn test_caret_movement_breaks_undo_group() {
+        let mut tb = text_box("");
+        type_str(&mut tb, "hello");
+        tb.move_left();
+        type_str(&mut tb, "XY");
+        assert_eq!(tb.text, "hellXYo");
+
+        assert!(tb.undo());
+        assert_eq!(tb.text, "hello");
+        assert!(tb.undo());
+        assert_eq!(tb.text, "");
+        assert!(!tb.undo());
+    }
+
+    #[test]
+    fn test_deletions_grouped_separately() {
+        let mut tb = text_box("");
+        type_str(&mut tb, "abc");
+        assert!(tb.backspace());
+        assert!(tb.backspace());
+        assert_eq!(tb.text, "a");
+
+        assert!(tb.undo());
+        assert_eq!(tb.text, "abc");
+        assert!(tb.undo());
+        assert_eq!(tb.text, "");
+    }
+
+    #[test]
+    fn test_grapheme_clusters() {
+        // A ZWJ sequence, a flag, and a skin tone modifier are each one cluster
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        let flag = "\u{1F1ED}\u{1F1F0}";
+        let thumb = "\u{1F44D}\u{1F3FD}";
+        let mut tb = text_box(&format!("a{family}{flag}{thumb}"));
+
+        assert!(tb.backspace());
+        assert_eq!(tb.text, format!("a{family}{flag}"));
+
+        tb.move_left();
+        assert_eq!(tb.cursor_x, 1 + family.len());
+        assert!(tb.backspace());
+        assert_eq!(tb.text, format!("a{flag}"));
+        assert_eq!(tb.cursor_x, 1);
+
+        tb.move_right();
+        assert_eq!(tb.cursor_x, tb.text.len());
+    }
+
+    #[test]
+    fn test_insert_and_append() {
+        let thumb = "\u{1F44D}\u{1F3FD}";
+        let mut tb = text_box(&format!("{thumb}{thumb}"));
+        tb.move_left();
+        tb.insert_at_cursor(" and ");
+        assert_eq!(tb.text, format!("{thumb} and {thumb}"));
+        assert_eq!(tb.cursor_x, thumb.len() + 5);
+
+        tb.append("!");
+        assert_eq!(tb.text, format!("{thumb} and {thumb}!"));
+        assert_eq!(tb.cursor_x, tb.text.len());
+        assert!(tb.take_dirty());
+
+        assert!(tb.undo());
+        assert!(tb.undo());
+        assert_eq!(tb.text, format!("{thumb}{thumb}"));
+    }
+
+    #[test]
+    fn test_initial_cursor() {
+        assert_eq!(text_box("hello").cursor_x, 5);
+        assert_eq!(text_box("hello").initial_cursor(2).cursor_x, 2);
+        assert_eq!(text_box("hello").initial_cursor(0).cursor_x, 0);
+        // Clamped
+        assert_eq!(text_box("hello").initial_cursor(99).cursor_x, 5);
+
+        // A character index, not bytes
+        let tb = text_box("héllo").initial_cursor(2);
+        assert_eq!(&tb.text[tb.cursor_x..], "llo");
+        assert_eq!(tb.cursor_char_idx(), 2);
+
+        // The skin tone modifier is the 3rd char, but it's part of the 2nd emoji
+        let thumb = "\u{1F44D}\u{1F3FD}";
+        let tb = text_box(&format!("{thumb}{thumb}")).initial_cursor(3);
+        assert_eq!(tb.cursor_x, thumb.len());
+    }
+
+    #[test]
+    fn test_auto_close_pairs() {
+        // Off by default
+        let mut tb = text_box("");
+        assert!(tb.type_char('('));
+        assert_eq!(tb.text, "(");
+
+        let mut tb = text_box("").auto_close_pairs(true);
+        for c in "f(x".chars() {
+            assert!(tb.type_char(c));
+        }
+        assert_eq!(tb.text, "f(x)");
+        assert_eq!(tb.cursor_x, 3);
+        // Typing the closer moves past the existing one
+        assert!(!tb.type_char(')'));
+        assert_eq!(tb.text, "f(x)");
+        assert_eq!(tb.cursor_x, 4);
+
+        tb.type_char('"');
+        assert_eq!(tb.text, "f(x)\"\"");
+        tb.type_char('"');
+        assert_eq!(tb.text, "f(x)\"\"");
+        assert_eq!(tb.cursor_x, 6);
+
+        // The pair is one undo step, separate from what was typed inside it
+        assert!(tb.undo());
+        assert_eq!(tb.text, "f(x)");
+        assert!(tb.undo());
+        assert_eq!(tb.text, "f()");
+        assert!(tb.undo());
+        assert_eq!(tb.text, "f");
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut tb = text_box("a long prompt");
+        tb.insert_char('!');
+        assert!(tb.clear());
+        assert_eq!(tb.text, "");
+        assert_eq!(tb.cursor_x, 0);
+        // Nothing to clear
+        assert!(!tb.clear());
+
+        assert!(tb.undo());
+        assert_eq!(tb.text, "a long prompt!");
+        assert_eq!(tb.cursor_x, tb.text.len());
+        assert!(tb.redo());
+        assert_eq!(tb.text, "");
+    }
+
+    #[test]
+    fn test_readline_keys() {
+        let mut tb = text_box("first line\nsecond line").readline_keys(true);
+        tb.cursor_x = "first line\nsec".len();
+        assert_eq!(tb.handle_key(Key::A, true, false, false), Some(false));
+        assert_eq!(tb.cursor_x, "first line\n".len());
+        assert_eq!(tb.handle_key(Key::E, true, false, false), Some(false));
+        assert_eq!(tb.cursor_x, tb.text.len());
+
+        assert_eq!(tb.handle_key(Key::W, true, false, false), Some(true));
+        assert_eq!(tb.text, "first line\nsecond ");
+        // Whitespace right before the caret goes with the word
+        assert_eq!(tb.handle_key(Key::W, true, false, false), Some(true));
+        assert_eq!(tb.text, "first line\n");
+
+        tb.cursor_x = "first".len();
+        assert_eq!(tb.handle_key(Key::K, true, false, false), Some(true));
+        assert_eq!(tb.text, "first\n");
+        // At the end of a line, the next one is joined
+        assert_eq!(tb.handle_key(Key::K, true, false, false), Some(true));
+        assert_eq!(tb.text, "first");
+        assert_eq!(tb.handle_key(Key::U, true, false, false), Some(true));
+        assert_eq!(tb.text, "");
+        assert!(tb.undo());
+        assert_eq!(tb.text, "first");
+
+        // Ctrl+Shift+K still clears everything
+        assert_eq!(tb.handle_key(Key::K, true, false, true), Some(true));
+        assert_eq!(tb.text, "");
+
+        // Without the option, Ctrl+A selects everything and the rest are left for someone else
+        let mut tb = text_box("hello");
+        assert_eq!(tb.handle_key(Key::A, true, false, false), Some(false));
+        assert_eq!(tb.selected_text(), Some("hello".to_string()));
+        assert_eq!(tb.handle_key(Key::U, true, false, false), None);
+        assert_eq!(tb.text, "hello");
+        // Typing replaces it all
+        assert_eq!(tb.handle_key(Key::X, false, false, false), Some(true));
+        assert_eq!(tb.text, "x");
+    }
+
+    #[test]
+    fn test_focus_changes() {
+        let mut tb = MultilineTextBox::new(
+            "test".to_string(),
+            String::new(),
+            ScreenDims::new(100.0, 100.0),
+            false,
+        );
+        tb.click(true);
+        assert!(tb.has_focus());
+        assert_eq!(tb.focus_change.take(), Some(true));
+        // Already focused
+        tb.click(true);
+        assert_eq!(tb.focus_change.take(), None);
+
+        tb.click(false);
+        assert!(!tb.has_focus());
+        assert_eq!(tb.focus_change.take(), Some(false));
+        tb.click(false);
+        assert_eq!(tb.focus_change.take(), None);
+
+        // Clicking in and back out before anything is reported is no change at all
+        tb.click(true);
+        tb.click(false);
+        assert_eq!(tb.focus_change.take(), None);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut tb = text_box("ab");
+        tb.set_disabled(true);
+        assert!(!tb.has_focus());
+        assert_eq!(tb.focus_change.take(), Some(false));
+        for key in [Key::C, Key::Backspace, Key::Enter, Key::LeftArrow] {
+            assert_eq!(tb.handle_key(key, false, false, false), None);
+        }
+        assert_eq!(tb.handle_key(Key::Z, true, false, false), None);
+        assert_eq!(tb.text, "ab");
+        assert_eq!(tb.cursor_x, 2);
+
+        tb.set_disabled(false);
+        assert_eq!(tb.handle_key(Key::C, false, false, false), Some(true));
+        assert_eq!(tb.text, "abc");
+
+        // Starting out disabled drops autofocus quietly, since nothing had focus yet
+        let tb = text_box("").disabled(true);
+        assert!(!tb.has_focus());
+        assert_eq!(tb.focus_change, None);
+    }
+
+    #[test]
+    fn test_selection() {
+        let mut tb = text_box("one two");
+        tb.handle_key(Key::LeftArrow, false, false, true);
+        tb.handle_key(Key::LeftArrow, false, false, true);
+        tb.handle_key(Key::LeftArrow, false, false, true);
+        assert_eq!(tb.selected_text(), Some("two".to_string()));
+        // Typing replaces the selection, as one undo step after the deletion
+        tb.handle_key(Key::S, false, false, false);
+        assert_eq!(tb.text, "one s");
+        assert_eq!(tb.selected_text(), None);
+        tb.undo();
+        tb.undo();
+        assert_eq!(tb.text, "one two");
+        tb.cursor_x = tb.text.len();
+
+        // Moving without Shift ends the selection
+        tb.handle_key(Key::LeftArrow, false, false, true);
+        tb.handle_key(Key::RightArrow, false, false, false);
+        assert_eq!(tb.selected_text(), None);
+
+        tb.handle_key(Key::LeftArrow, false, false, true);
+        assert_eq!(
+            tb.handle_key(Key::Backspace, false, false, false),
+            Some(true)
+        );
+        assert_eq!(tb.text, "one tw");
+        // Without a selection, Ctrl+C is someone else's
+        assert_eq!(tb.handle_key(Key::C, true, false, false), None);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let mut tb = text_box("fixed").read_only(true);
+        assert_eq!(tb.handle_key(Key::X, false, false, false), None);
+        assert_eq!(tb.handle_key(Key::Backspace, false, false, false), None);
+        assert_eq!(tb.handle_key(Key::V, true, false, false), None);
+        assert_eq!(tb.text, "fixed");
+
+        // But it can be selected
+        tb.handle_key(Key::LeftArrow, false, false, true);
+        tb.handle_key(Key::LeftArrow, false, false, true);
+        assert_eq!(tb.selected_text(), Some("ed".to_string()));
+        assert_eq!(tb.handle_key(Key::A, true, false, false), Some(false));
+        assert_eq!(tb.selected_text(), Some("fixed".to_string()));
+
+        // Changing the text from outside drops the selection
+        tb.set_text("new".to_string());
+        assert_eq!(tb.selected_text(), None);
+    }
+
+    #[test]
+    fn test_rtl_lines() {
+        assert!(!is_rtl_line("hello"));
+        assert!(is_rtl_line("שלום"));
+        assert!(is_rtl_line("مرحبا"));
+        // The first strong character decides, skipping digits and punctuation
+        assert!(is_rtl_line("42: שלום world"));
+        assert!(!is_rtl_line("world שלום"));
+        assert!(!is_rtl_line("123"));
+
+        // The caret only moves visually on RTL lines; the text is still stored in logical order
+        let mut tb = text_box("abc\nשלום");
+        tb.move_visually(true);
+        assert_eq!(tb.cursor_x, tb.text.len());
+        tb.move_visually(false);
+        assert_eq!(&tb.text[tb.cursor_x..], "ם");
+
+        let mut tb = text_box("abc\nשלום").initial_cursor(2);
+        tb.move_visually(true);
+        assert_eq!(tb.cursor_x, 1);
+        tb.move_visually(false);
+        assert_eq!(tb.cursor_x, 2);
+    }
+
+    #[test]
+    fn test_modifiers_dont_type() {
+        let mut tb = text_box("ab");
+        // Whatever is on the clipboard, the letter itself isn't typed
+        tb.handle_key(Key::V, true, false, false);
+        assert!(!tb.text.contains('v'));
+
+        let mut tb = text_box("ab");
+        assert_eq!(tb.handle_key(Key::S, true, false, false), None);
+        assert_eq!(tb.handle_key(Key::S, false, true, false), None);
+        assert_eq!(tb.text, "ab");
+
+        assert_eq!(tb.handle_key(Key::S, false, false, true), Some(true));
+        assert_eq!(tb.text, "abS");
+    }
+
+    #[test]
+    fn test_single_line_paste() {
+        let mut tb = text_box("find");
+        tb.single_line = true;
+        tb.insert_str(" first\nsecond");
+        assert_eq!(tb.text, "find first second");
+        tb.set_text("a\nb".to_string());
+        assert_eq!(tb.text, "a b");
+    }
+
+    #[test]
+    fn test_get_text_is_raw() {
+        let mut tb = text_box("");
+        type_str(&mut tb, "a | b");
+        tb.move_left();
+        tb.move_left();
+        // The caret is in the middle, but it's only drawn
+        assert_eq!(tb.get_text(), "a | b");
+        assert_eq!(tb.cursor_x, 3);
+    }
+
+    #[test]
+    fn test_words() {
+        assert_eq!(
+            words("hi  there you"),
+            vec![(0, "hi", 2), (4, "there", 1), (10, "you", 0)]
+        );
+        assert_eq!(words("  x "), vec![(0, "", 2), (2, "x", 1)]);
+        assert_eq!(words(""), vec![(0, "", 0)]);
+    }
+
+    #[test]
+    fn test_wrap_long_words() {
+        // Every character is 5 wide, so 20 fit in a line
+        let measure = |text: &str| 5.0 * text.chars().count() as f64;
+        let ranges = |text: &str| -> Vec<(usize, usize)> {
+            wrap_lines(text, 100.0, 5.0, measure)
+                .into_iter()
+                .map(|line| (line.start, line.end))
+                .collect()
+        };
+
+        let mut tb = text_box("");
+        tb.insert_str(&"x".repeat(200));
+        let lines = ranges(&tb.text);
+        assert_eq!(lines.len(), 10);
+        for (idx, (start, end)) in lines.into_iter().enumerate() {
+            assert_eq!((start, end), (idx * 20, (idx + 1) * 20));
+        }
+
+        // The long word starts on its own line, and the words after it continue from where it ends
+        let text = format!("hi {} ok", "y".repeat(30));
+        assert_eq!(ranges(&text), vec![(0, 3), (3, 23), (23, 36)]);
+
+        // Ordinary words still wrap between words
+        assert_eq!(ranges("aaaaaaaaaa bbbbbbbbbb"), vec![(0, 11), (11, 21)]);
+    }
+
+    #[test]
+    fn test_layout_cache() {
+        let measure = |text: &str| 5.0 * text.chars().count() as f64;
+        let wraps = std::cell::Cell::new(0);
+        let layout = |tb: &MultilineTextBox| {
+            tb.cached_layout(|| {
+                wraps.set(wraps.get() + 1);
+                wrap_lines(&tb.text, tb.text_width(), 5.0, measure)
+            })
+            .len()
+        };
+
+        let mut tb = text_box("one\ntwo");
+        assert_eq!(layout(&tb), 2);
+        assert_eq!(layout(&tb), 2);
+        assert_eq!(wraps.get(), 1);
+
+        // Any edit, the width, and the font size all invalidate it
+        tb.insert_str("\nthree");
+        assert_eq!(layout(&tb), 3);
+        assert_eq!(wraps.get(), 2);
+        tb.dims.width /= 2.0;
+        layout(&tb);
+        assert_eq!(wraps.get(), 3);
+        tb.high_contrast = true;
+        layout(&tb);
+        assert_eq!(wraps.get(), 4);
+        layout(&tb);
+        assert_eq!(wraps.get(), 4);
+    }
+
+    #[test]
+    fn test_wrapped_line_count() {
+        // Every character is 5 wide, so 20 fit in a line
+        let measure = |text: &str| 5.0 * text.chars().count() as f64;
+        let count = |text: &str| wrap_lines(text, 100.0, 5.0, measure).len();
+
+        assert_eq!(count(""), 1);
+        assert_eq!(count("one\ntwo\n"), 3);
+        // The long line wraps once, so it's one more than the hard newlines alone
+        let text = format!("one\ntwo\n{}", "x".repeat(30));
+        assert_eq!(text.split('\n').count(), 3);
+        assert_eq!(count(&text), 4);
+        // Words wrap too, not just long unbroken runs
+        assert_eq!(count("one\nsome words that go past the edge"), 3);
+    }
+
+    #[test]
+    fn test_paste_crlf() {
+        let mut tb = text_box("");
+        tb.insert_str("a\r\nb");
+        assert_eq!(tb.text, "a\nb");
+        assert_eq!(tb.cursor_x, 3);
+
+        tb.set_text("one\rtwo\r\n\r\nthree".to_string());
+        assert_eq!(tb.text, "one\ntwo\n\nthree");
+
+        assert_eq!(text_box("x\r\ny").text, "x\ny");
+    }
+
+    #[test]
+    fn test_scroll_pass_through() {
+        // Everything fits, so the scroll belongs to whatever is under the box
+        assert_eq!(scrolled_first_line(0, 3, 5, 1.0), None);
+        assert_eq!(scrolled_first_line(0, 5, 5, -1.0), None);
+
+        // With overflow, the box keeps the scroll even at either end
+        assert_eq!(scrolled_first_line(0, 8, 5, -1.0), Some(1));
+        assert_eq!(scrolled_first_line(3, 8, 5, -1.0), Some(3));
+        assert_eq!(scrolled_first_line(0, 8, 5, 1.0), Some(0));
+        assert_eq!(scrolled_first_line(2, 8, 5, 0.2), Some(1));
+    }
+
+    #[test]
+    fn test_max_height() {
+        // Lines are 20 tall, with 14 of padding, and the box is between 50 and 200 tall
+        let height = |lines: usize| fitted_height(lines as f64 * 20.0 + 14.0, 50.0, 200.0);
+
+        // Short text still gets the minimum, and medium text sizes to fit
+        assert_eq!(height(1), 50.0);
+        assert_eq!(height(3), 74.0);
+        assert_eq!(lines_that_fit(height(3) - 14.0, 20.0), 3);
+
+        // A tall buffer stops growing and scrolls instead
+        assert_eq!(height(50), 200.0);
+        let visible = lines_that_fit(height(50) - 14.0, 20.0);
+        assert_eq!(visible, 9);
+        assert_eq!(scrolled_first_line(0, 50, visible, -1.0), Some(1));
+        assert_eq!(scrolled_first_line(40, 50, visible, -5.0), Some(41));
+    }
+
+    #[test]
+    fn test_placeholder() {
+        let mut tb = text_box("").placeholder("Type here");
+        assert!(tb.shows_placeholder());
+        assert_eq!(tb.get_text(), "");
+
+        // Hidden once there's text, or without focus
+        type_str(&mut tb, "hi");
+        assert!(!tb.shows_placeholder());
+        assert_eq!(tb.get_text(), "hi");
+        tb.set_text(String::new());
+        assert!(tb.shows_placeholder());
+        tb.click(false);
+        assert!(!tb.shows_placeholder());
+    }
+
+    #[test]
+    fn test_line_spacing() {
+        assert_eq!(text_box("").line_spacing, 1.0);
+        assert_eq!(text_box("").line_spacing(1.5).line_spacing, 1.5);
+        // Lines can't overlap
+        assert_eq!(text_box("").line_spacing(0.5).line_spacing, 1.0);
+    }
+}
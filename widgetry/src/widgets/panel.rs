@@ -9,6 +9,7 @@ use taffy::style::{Dimension, Style};
 
 use geom::Polygon;
 
+use crate::assets::Assets;
 use crate::widgets::slider;
 use crate::widgets::spinner::SpinnerValue;
 use crate::widgets::Container;
@@ -274,14 +275,13 @@ impl Panel {
     }
 
     pub fn event(&mut self, ctx: &mut EventCtx) -> Outcome {
-        if (self.scrollable_x || self.scrollable_y)
-            && ctx
-                .canvas
-                .get_cursor_in_screen_space()
-                .map(|pt| self.top_level.rect.contains(pt))
-                .unwrap_or(false)
-        {
-            if let Some((dx, dy)) = ctx.input.get_mouse_scroll() {
+        if let Some((dx, dy)) = ctx.input.get_mouse_scroll() {
+            if panel_takes_wheel(
+                &self.top_level,
+                self.scrollable_x || self.scrollable_y,
+                ctx.canvas.get_cursor_in_screen_space(),
+                &ctx.prerender.assets,
+            ) {
                 let x_offset = if self.scrollable_x {
                     self.scroll_offset().0 - dx * (ctx.canvas.settings.gui_scroll_speed as f64)
                 } else {
@@ -491,6 +491,16 @@ impl Panel {
             .unwrap_or_else(|| panic!("Can't find widget {}", name))
     }
 
+    pub fn maybe_find_mut<T: WidgetImpl>(&mut self, name: &str) -> Option<&mut T> {
+        self.top_level.find_mut(name).map(|w| {
+            if let Some(x) = w.widget.downcast_mut::<T>() {
+                x
+            } else {
+                panic!("Found widget {}, but wrong type", name);
+            }
+        })
+    }
+
     pub fn find_mut<T: WidgetImpl>(&mut self, name: &str) -> &mut T {
         if let Some(w) = self.top_level.find_mut(name) {
             if let Some(x) = w.widget.downcast_mut::<T>() {
@@ -715,3 +725,64 @@ impl PanelBuilder {
         self
     }
 }
+
+/// Whether a mouse wheel event at `cursor` scrolls a panel with these contents. Only a text box
+/// scrolling its own overflow keeps the wheel from the panel. Other widgets don't, even if they
+/// handle the wheel too.
+fn panel_takes_wheel(
+    top_level: &Widget,
+    scrollable: bool,
+    cursor: Option<ScreenPt>,
+    assets: &Assets,
+) -> bool {
+    scrollable
+        && cursor
+            .map(|pt| top_level.rect.contains(pt))
+            .unwrap_or(false)
+        && !top_level.text_box_owns_wheel(cursor, assets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MultilineTextBox, Style};
+
+    fn text_box(text: String) -> Widget {
+        MultilineTextBox::new(
+            "test".to_string(),
+            text,
+            ScreenDims::new(100.0, 100.0),
+            false,
+        )
+        .into_widget()
+    }
+
+    #[test]
+    fn test_panel_takes_wheel() {
+        let assets = Assets::new(Style::light_bg(), None, false, Box::new(|_| Vec::new()));
+        let mut contents = Widget::col(vec![text_box("short".to_string())]);
+        contents.rect =
+            ScreenRectangle::top_left(ScreenPt::new(0.0, 0.0), ScreenDims::new(500.0, 500.0));
+        let over_box = Some(ScreenPt::new(50.0, 50.0));
+        let elsewhere = Some(ScreenPt::new(400.0, 400.0));
+
+        // An ordinary scrollable panel scrolls wherever the cursor is inside it, even over a text
+        // box with nothing to scroll
+        assert!(panel_takes_wheel(&contents, true, over_box, &assets));
+        assert!(panel_takes_wheel(&contents, true, elsewhere, &assets));
+        assert!(!panel_takes_wheel(
+            &contents,
+            true,
+            Some(ScreenPt::new(600.0, 50.0)),
+            &assets
+        ));
+        assert!(!panel_takes_wheel(&contents, false, over_box, &assets));
+
+        // A text box with overflow keeps the wheel, but only while the cursor is over it
+        let mut contents = Widget::col(vec![text_box("line\n".repeat(50))]);
+        contents.rect =
+            ScreenRectangle::top_left(ScreenPt::new(0.0, 0.0), ScreenDims::new(500.0, 500.0));
+        assert!(!panel_takes_wheel(&contents, true, over_box, &assets));
+        assert!(panel_takes_wheel(&contents, true, elsewhere, &assets));
+    }
+}